@@ -0,0 +1,362 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+};
+
+use crate::{cfg::Cfg, inst::Inst, program::Program, vm::STACK_SIZE_LIMIT};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaxStack {
+    Bounded(usize),
+    Unbounded,
+}
+
+impl Display for MaxStack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MaxStack::Bounded(n) => write!(f, "{}", n),
+            MaxStack::Unbounded => write!(f, "unbounded"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct StackAnalysis {
+    // Stack height on arrival at each instruction, None if unreachable or growing
+    pub heights: Vec<Option<usize>>,
+    pub max: MaxStack,
+    // Start ip of every block whose height keeps growing around a loop
+    pub growing: Vec<usize>,
+}
+
+// What a function does to the stack of its caller, counted from the depth
+// at the call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CallEffect {
+    // Words it takes from below that depth
+    pub required: usize,
+    // Change in depth once it returns, None if it never does
+    pub net: Option<i64>,
+    // Most it pushes above that depth, None if recursion grows it without bound
+    pub peak: Option<usize>,
+}
+
+// Blocks execution goes on to from the end of block id and the depth it
+// arrives with. A call steps over its callee to where it returns.
+pub fn block_exits(
+    insts: &[Inst],
+    cfg: &Cfg,
+    effects: &BTreeMap<usize, CallEffect>,
+    id: usize,
+    depth: i64,
+) -> Vec<(usize, i64)> {
+    let block = &cfg.blocks[id];
+    let last = block.end - 1;
+    match &insts[last] {
+        Inst::InstCall(callee) => effects
+            .get(&(u64::from(*callee) as usize))
+            .and_then(|effect| effect.net)
+            .zip(cfg.block_at(last + 1))
+            .map(|(net, next)| (next, depth + net))
+            .into_iter()
+            .collect(),
+        inst => block
+            .succs
+            .iter()
+            .map(|succ| {
+                let start = cfg.blocks[*succ].start;
+                (*succ, inst.successor_depth(last, start, depth))
+            })
+            .collect(),
+    }
+}
+
+// The effect of every call target, walking each from its entry at depth 0.
+// Callees are stepped over with what the previous round found, so recursion
+// settles from its base case up. A peak still rising after as many rounds as
+// there are blocks comes from recursion that pushes on every level.
+pub fn call_effects(program: &Program, cfg: &Cfg) -> BTreeMap<usize, CallEffect> {
+    let insts = &program.insts;
+    let entries: Vec<usize> = insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::InstCall(target) => Some(u64::from(*target) as usize),
+            _ => None,
+        })
+        .filter(|entry| *entry < insts.len())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    let walk = |entry: usize, effects: &BTreeMap<usize, CallEffect>| {
+        let (mut required, mut net, mut peak) = (0i64, None, Some(0i64));
+        let mut seen = vec![false; cfg.blocks.len()];
+        let mut worklist: Vec<(usize, i64)> =
+            cfg.block_at(entry).map(|id| (id, 0)).into_iter().collect();
+        while let Some((id, mut h)) = worklist.pop() {
+            if std::mem::replace(&mut seen[id], true) {
+                continue;
+            }
+            let block = &cfg.blocks[id];
+            for inst in &insts[block.ips()] {
+                // Past the limit no stack holds enough, however far past
+                let (needs, delta) = inst.stack_effect();
+                required = required.max(needs.min(STACK_SIZE_LIMIT + 1) as i64 - h);
+                h += delta as i64;
+                peak = peak.map(|peak| peak.max(h));
+            }
+            match &insts[block.end - 1] {
+                Inst::InstRet => {
+                    net.get_or_insert(h);
+                }
+                Inst::InstCall(callee) => {
+                    if let Some(effect) = effects.get(&(u64::from(*callee) as usize)) {
+                        required = required.max(effect.required as i64 - h);
+                        peak = peak
+                            .zip(effect.peak)
+                            .map(|(peak, inner)| peak.max(h + inner as i64));
+                    }
+                }
+                _ => {}
+            }
+            worklist.extend(block_exits(insts, cfg, effects, id, h));
+        }
+        CallEffect {
+            required: required as usize,
+            net,
+            peak: peak.map(|peak| peak as usize),
+        }
+    };
+
+    let mut effects = BTreeMap::new();
+    for round in 0..=cfg.blocks.len() {
+        let next: BTreeMap<usize, CallEffect> = entries
+            .iter()
+            .map(|entry| (*entry, walk(*entry, &effects)))
+            .collect();
+        if next == effects {
+            break;
+        }
+        if round == cfg.blocks.len() {
+            return next
+                .into_iter()
+                .map(|(entry, effect)| match effects.get(&entry) {
+                    Some(before) if *before == effect => (entry, effect),
+                    _ => (
+                        entry,
+                        CallEffect {
+                            peak: None,
+                            ..effect
+                        },
+                    ),
+                })
+                .collect();
+        }
+        effects = next;
+    }
+    effects
+}
+
+pub fn analyze_stack(program: &Program, cfg: &Cfg) -> StackAnalysis {
+    let insts = &program.insts;
+    let blocks = &cfg.blocks;
+    let effects = call_effects(program, cfg);
+
+    // Net change and peak above the entry height of every block
+    let (deltas, peaks): (Vec<i64>, Vec<i64>) = blocks
+        .iter()
+        .map(|block| {
+            insts[block.ips()]
+                .iter()
+                .fold((0i64, 0i64), |(h, peak), inst| {
+                    let h = h + inst.stack_effect().1 as i64;
+                    (h, peak.max(h))
+                })
+        })
+        .unzip();
+
+    // Longest path over block entry heights. Anything still rising after
+    // blocks.len() rounds sits on, or behind, a loop that pushes more than it pops.
+    // A function body starts from the arguments it takes.
+    let mut entry: Vec<Option<i64>> = vec![None; blocks.len()];
+    let mut growing = vec![false; blocks.len()];
+    if let Some(first) = entry.first_mut() {
+        *first = Some(0);
+    }
+    effects.iter().for_each(|(ip, effect)| {
+        if let Some(id) = cfg.block_at(*ip) {
+            entry[id] = entry[id].max(Some(effect.required as i64));
+        }
+    });
+
+    for round in 0..=blocks.len() {
+        let mut changed = false;
+        for id in 0..blocks.len() {
+            let Some(h) = entry[id] else { continue };
+            let out = (h + deltas[id]).max(0);
+            for (succ, out) in block_exits(insts, cfg, &effects, id, out) {
+                if entry[succ].is_none_or(|e| out > e) {
+                    entry[succ] = Some(out);
+                    changed = true;
+                    if round == blocks.len() {
+                        growing[succ] = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    // Whatever a growing block reaches grows with it
+    let mut worklist: Vec<usize> = (0..blocks.len()).filter(|id| growing[*id]).collect();
+    while let Some(id) = worklist.pop() {
+        for (succ, _) in block_exits(insts, cfg, &effects, id, 0) {
+            if !growing[succ] {
+                growing[succ] = true;
+                worklist.push(succ);
+            }
+        }
+    }
+
+    let mut heights = vec![None; insts.len()];
+    blocks.iter().enumerate().for_each(|(id, block)| {
+        let Some(mut h) = entry[id].filter(|_| !growing[id]) else {
+            return;
+        };
+        block.ips().for_each(|ip| {
+            heights[ip] = Some(h.max(0) as usize);
+            h += insts[ip].stack_effect().1 as i64;
+        });
+    });
+
+    // A call reaches as high as its callee pushes above the call
+    let tops: Option<Vec<i64>> = (0..blocks.len())
+        .filter_map(|id| entry[id].map(|h| (id, h)))
+        .map(|(id, h)| match &insts[blocks[id].end - 1] {
+            Inst::InstCall(callee) => match effects.get(&(u64::from(*callee) as usize)) {
+                Some(effect) => effect
+                    .peak
+                    .map(|peak| (h + peaks[id]).max(h + deltas[id] + peak as i64)),
+                None => Some(h + peaks[id]),
+            },
+            _ => Some(h + peaks[id]),
+        })
+        .collect();
+    let max = match tops {
+        Some(tops) if !growing.iter().any(|g| *g) => {
+            MaxStack::Bounded(tops.into_iter().max().unwrap_or(0).max(0) as usize)
+        }
+        _ => MaxStack::Unbounded,
+    };
+
+    StackAnalysis {
+        heights,
+        max,
+        growing: (0..blocks.len())
+            .filter(|id| growing[*id])
+            .map(|id| blocks[id].start)
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{finish, vm_with};
+
+    fn analyze(hasm: &str) -> StackAnalysis {
+        let program = Program::from_hasm(hasm).unwrap();
+        analyze_stack(&program, &Cfg::build(&program))
+    }
+
+    #[test]
+    fn heights_follow_every_instruction() {
+        let analysis = analyze("push 1\npush 2\npush 3\naddi\naddi\nprinti\nhalt");
+        assert_eq!(analysis.max, MaxStack::Bounded(3));
+        assert_eq!(analysis.heights, [0, 1, 2, 3, 2, 1, 0].map(Some).to_vec());
+        assert!(analysis.growing.is_empty());
+    }
+
+    #[test]
+    fn handlers_and_fibers_start_from_their_own_depth() {
+        // The handler finds the error code on top, the fiber its argument
+        let analysis = analyze(
+            "push 1
+            try handler
+            push 2
+            push 3
+            endtry
+            halt
+        handler:
+            push 4
+            halt
+        fiber:
+            push 5
+            halt",
+        );
+        assert_eq!(analysis.heights[6], Some(2));
+        assert_eq!(analysis.max, MaxStack::Bounded(3));
+
+        let analysis = analyze("push 1\nspawn fiber\nhalt\nfiber:\npush 2\npush 3\nhalt");
+        assert_eq!(analysis.heights[3], Some(1));
+        assert_eq!(analysis.max, MaxStack::Bounded(3));
+    }
+
+    #[test]
+    fn loops_that_push_grow_without_bound() {
+        // Like the loop in fib.hasm
+        let analysis = analyze("push 0\nloop:\npush 1\njmp loop");
+        assert_eq!(analysis.max, MaxStack::Unbounded);
+        assert_eq!(analysis.growing, [1]);
+        assert_eq!(analysis.heights, [Some(0), None, None]);
+
+        let analysis = analyze("push 0\nloop:\npush 1\naddi\njmp loop");
+        assert_eq!(analysis.max, MaxStack::Bounded(2));
+    }
+
+    #[test]
+    fn calls_add_what_their_callee_pushes() {
+        let analysis = analyze("push 2\npush 3\ncall add\nprinti\nhalt\nadd:\naddi\nret");
+        assert_eq!(analysis.max, MaxStack::Bounded(2));
+        assert_eq!(analysis.heights[3], Some(1));
+        // The body counts from the arguments it takes
+        assert_eq!(analysis.heights[5], Some(2));
+
+        let hasm = "push 0
+            call three
+            addi
+            addi
+            addi
+            printi
+            halt
+        three:
+            push 1
+            push 2
+            push 3
+            ret";
+        let analysis = analyze(hasm);
+        assert_eq!(analysis.max, MaxStack::Bounded(4));
+
+        // Sized by the analysis, the stack still fits
+        let mut program = Program::from_hasm(hasm).unwrap();
+        program.max_stack = Some(analysis.max);
+        let run = finish(vm_with(program, "")).unwrap();
+        assert_eq!(run.output, b"6");
+    }
+
+    #[test]
+    fn recursion_that_pushes_is_unbounded() {
+        let program = Program::from_hasm("push 0\ncall f\nhalt\nf:\npush 1\ncall f\nret").unwrap();
+        let cfg = Cfg::build(&program);
+        let effect = call_effects(&program, &cfg)[&3];
+        assert_eq!((effect.net, effect.peak), (None, None));
+        let analysis = analyze_stack(&program, &cfg);
+        assert_eq!(analysis.max, MaxStack::Unbounded);
+        assert!(analysis.growing.is_empty());
+
+        let analysis = analyze("call f\nhalt\nf:\ncall f\nret");
+        assert_eq!(analysis.max, MaxStack::Bounded(0));
+    }
+}
@@ -0,0 +1,128 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+
+use crate::{console::BufferConsole, program::Program, VMError, VM};
+
+// Each loops forever, so a run lasts exactly as many instructions as its fuel
+const FIB: &str = "# Fibonacci numbers in two globals, one call per term
+.global a = 0
+.global b = 1
+loop:
+    call next
+    jmp loop
+next:
+    gload a
+    gload b
+    dup 0
+    gstore a
+    addi
+    gstore b
+    ret
+";
+
+const LOOPS: &str = "# Counts up in a local
+    .local i
+    enter 1
+loop:
+    lload i
+    push 1
+    addi
+    lstore i
+    jmp loop
+";
+
+const FLOAT: &str = "# Two floats that settle towards fixed points
+    .local x
+    .local y
+    enter 2
+    push 1.0
+    lstore x
+    push 2.0
+    lstore y
+loop:
+    lload x
+    push 0.999
+    mulf
+    push 1.5
+    addf
+    lstore x
+    lload y
+    push 1.0001
+    divf
+    push 0.25
+    subf
+    lstore y
+    jmp loop
+";
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum Bench {
+    fib,
+    loops,
+    float,
+}
+
+impl Bench {
+    fn hasm(self) -> &'static str {
+        match self {
+            Bench::fib => FIB,
+            Bench::loops => LOOPS,
+            Bench::float => FLOAT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BenchResult {
+    pub bench: Bench,
+    pub instructions: u64,
+    pub elapsed: Duration,
+}
+
+impl BenchResult {
+    pub fn per_second(&self) -> f64 {
+        self.instructions as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+// Times one benchmark running the given number of instructions, leaving
+// assembling and loading out of it
+pub fn run_bench(bench: Bench, instructions: u64) -> Result<BenchResult, VMError> {
+    let mut vm = VM::new();
+    vm.set_console(Box::new(BufferConsole::default()));
+    vm.load_ha_from_memory(Program::from_hasm(bench.hasm())?)?;
+
+    let start = Instant::now();
+    vm.run(Some(instructions))?;
+    let elapsed = start.elapsed();
+
+    Ok(BenchResult {
+        bench,
+        instructions: instructions - vm.fuel(),
+        elapsed,
+    })
+}
+
+// Kept results, one `name millions-per-second` line each, so later runs
+// can be compared against them. # starts a comment.
+pub fn format_results(results: &[BenchResult]) -> String {
+    results
+        .iter()
+        .map(|result| format!("{:?} {:.1}\n", result.bench, result.per_second() / 1e6))
+        .collect()
+}
+
+pub fn parse_results(text: &str) -> HashMap<String, f64> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .filter_map(|line| {
+            let (name, rate) = line.trim().split_once(' ')?;
+            Some((name.to_string(), rate.trim().parse().ok()?))
+        })
+        .collect()
+}
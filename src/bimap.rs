@@ -25,4 +25,8 @@ where
     pub fn extract_key(&self, v: &V) -> &K {
         self.backward.get(v).unwrap()
     }
+
+    pub fn get_key(&self, v: &V) -> Option<&K> {
+        self.backward.get(v).map(|k| k.as_ref())
+    }
 }
@@ -0,0 +1,233 @@
+use std::{collections::BTreeSet, fmt::Write};
+
+use crate::program::Program;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    pub start: usize,
+    pub end: usize,
+    pub succs: Vec<usize>,
+}
+
+impl BasicBlock {
+    pub fn ips(&self) -> std::ops::Range<usize> {
+        self.start..self.end
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+}
+
+impl Cfg {
+    pub fn build(program: &Program) -> Self {
+        let insts = &program.insts;
+        if insts.is_empty() {
+            return Self::default();
+        }
+
+        // Leaders: the entry, every jump target and whatever follows an
+        // instruction that does not just fall through
+        let mut leaders = BTreeSet::from([0usize]);
+        insts.iter().enumerate().for_each(|(ip, inst)| match inst {
+            inst if inst.successors(ip) != [ip + 1] => {
+                inst.successors(ip)
+                    .into_iter()
+                    .filter(|target| *target < insts.len())
+                    .for_each(|target| {
+                        leaders.insert(target);
+                    });
+                if ip + 1 < insts.len() {
+                    leaders.insert(ip + 1);
+                }
+            }
+            _ => {}
+        });
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let mut block_of = vec![0; insts.len()];
+        let mut blocks: Vec<BasicBlock> = starts
+            .iter()
+            .enumerate()
+            .map(|(id, &start)| {
+                let end = starts.get(id + 1).copied().unwrap_or(insts.len());
+                block_of[start..end].fill(id);
+                BasicBlock {
+                    start,
+                    end,
+                    succs: Vec::new(),
+                }
+            })
+            .collect();
+
+        blocks.iter_mut().for_each(|block| {
+            let last = block.end - 1;
+            block.succs = insts[last]
+                .successors(last)
+                .into_iter()
+                .filter(|target| *target < insts.len())
+                .map(|target| block_of[target])
+                .collect();
+        });
+
+        Self { blocks }
+    }
+
+    // The block holding ip
+    pub fn block_at(&self, ip: usize) -> Option<usize> {
+        let id = self
+            .blocks
+            .partition_point(|block| block.start <= ip)
+            .checked_sub(1)?;
+        Some(id).filter(|id| self.blocks[*id].ips().contains(&ip))
+    }
+
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut worklist: Vec<usize> = if self.blocks.is_empty() {
+            vec![]
+        } else {
+            vec![0]
+        };
+
+        while let Some(id) = worklist.pop() {
+            if !reachable[id] {
+                reachable[id] = true;
+                worklist.extend(self.blocks[id].succs.iter().copied());
+            }
+        }
+
+        reachable
+    }
+
+    // Block listing of `ip: hasm [height]`, heights as computed by the stack analysis
+    fn block_lines(&self, hasm: &[String], heights: &[Option<usize>], id: usize) -> Vec<String> {
+        self.blocks[id]
+            .ips()
+            .map(|ip| match heights[ip] {
+                Some(h) => format!("{}: {} [{}]", ip, hasm[ip], h),
+                None => format!("{}: {}", ip, hasm[ip]),
+            })
+            .collect()
+    }
+
+    pub fn to_dot(&self, program: &Program, heights: &[Option<usize>]) -> String {
+        let hasm = program.to_hasm();
+        let mut dot = String::new();
+
+        writeln!(dot, "digraph cfg {{").unwrap();
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        self.blocks.iter().enumerate().for_each(|(id, block)| {
+            let lines = self.block_lines(&hasm, heights, id);
+            let label: String = lines
+                .iter()
+                .map(|line| format!("{}\\l", escape(line)))
+                .collect();
+            writeln!(dot, "    b{} [label=\"b{}\\l{}\"];", id, id, label).unwrap();
+            block
+                .succs
+                .iter()
+                .for_each(|succ| writeln!(dot, "    b{} -> b{};", id, succ).unwrap());
+        });
+        writeln!(dot, "}}").unwrap();
+
+        dot
+    }
+
+    pub fn to_json(&self, program: &Program, heights: &[Option<usize>]) -> String {
+        let hasm = program.to_hasm();
+
+        let blocks: Vec<String> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(id, block)| {
+                let insts: Vec<String> = block
+                    .ips()
+                    .map(|ip| {
+                        let height = heights[ip].map_or("null".to_string(), |h| h.to_string());
+                        format!(
+                            "{{\"ip\":{},\"hasm\":\"{}\",\"height\":{}}}",
+                            ip,
+                            escape(&hasm[ip]),
+                            height
+                        )
+                    })
+                    .collect();
+                let succs: Vec<String> = block.succs.iter().map(|s| s.to_string()).collect();
+
+                format!(
+                    "{{\"id\":{},\"start\":{},\"end\":{},\"succs\":[{}],\"insts\":[{}]}}",
+                    id,
+                    block.start,
+                    block.end,
+                    succs.join(","),
+                    insts.join(",")
+                )
+            })
+            .collect();
+
+        format!("{{\"blocks\":[{}]}}", blocks.join(","))
+    }
+}
+
+// Escapes for both DOT and JSON string literals
+pub fn escape(s: &str) -> String {
+    s.chars()
+        .flat_map(|c| match c {
+            '"' => vec!['\\', '"'],
+            '\\' => vec!['\\', '\\'],
+            '\n' => vec!['\\', 'n'],
+            c => vec![c],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASM: &str = "push 1
+        jmp end
+        push 2
+    end:
+        halt";
+
+    #[test]
+    fn blocks_split_at_targets_and_after_jumps() {
+        let cfg = Cfg::build(&Program::from_hasm(HASM).unwrap());
+        let blocks: Vec<_> = cfg
+            .blocks
+            .iter()
+            .map(|block| (block.ips(), block.succs.clone()))
+            .collect();
+        assert_eq!(blocks, [(0..2, vec![2]), (2..3, vec![2]), (3..4, vec![])]);
+
+        assert_eq!(cfg.reachable(), [true, false, true]);
+        assert_eq!(cfg.block_at(1), Some(0));
+        assert_eq!(cfg.block_at(3), Some(2));
+        assert_eq!(cfg.block_at(4), None);
+        assert!(Cfg::build(&Program::default()).blocks.is_empty());
+    }
+
+    #[test]
+    fn exports_list_each_block_with_its_heights() {
+        let program = Program::from_hasm(HASM).unwrap();
+        let cfg = Cfg::build(&program);
+        let heights = [Some(0), Some(1), None, Some(1)];
+
+        let dot = cfg.to_dot(&program, &heights);
+        assert!(dot.contains("b0 [label=\"b0\\l0: push 1 [0]\\l1: jmp 3 [1]\\l\"];"));
+        assert!(dot.contains("b0 -> b2;") && dot.contains("b1 -> b2;"));
+
+        let json = cfg.to_json(&program, &heights);
+        assert!(json.starts_with("{\"blocks\":[{\"id\":0,\"start\":0,\"end\":2,\"succs\":[2],"));
+        assert!(json.contains("{\"ip\":2,\"hasm\":\"push 2\",\"height\":null}"));
+    }
+
+    #[test]
+    fn escapes_quotes_backslashes_and_newlines() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,213 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    io::{self, BufRead, Write},
+    sync::{Arc, Mutex},
+};
+
+use crate::VMError;
+
+// Where the print and read instructions go, so an embedder can capture them
+pub trait Console: Debug + Send {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), VMError>;
+
+    // One line without its line ending, None at end of input
+    fn read_line(&mut self) -> Result<Option<String>, VMError>;
+
+    // Everything written so far, for consoles that keep it
+    fn captured(&self) -> Option<&[u8]> {
+        None
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StdConsole;
+
+impl Console for StdConsole {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), VMError> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(bytes)?;
+        Ok(stdout.flush()?)
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, VMError> {
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(trim_line_ending(line)))
+    }
+}
+
+// Reads from one stream and writes to another, say a file and stdout
+#[derive(Debug)]
+pub struct StreamConsole<R: BufRead + Debug, W: Write + Debug> {
+    input: R,
+    output: W,
+}
+
+impl<R: BufRead + Debug, W: Write + Debug> StreamConsole<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Self { input, output }
+    }
+}
+
+impl<R: BufRead + Debug + Send, W: Write + Debug + Send> Console for StreamConsole<R, W> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), VMError> {
+        self.output.write_all(bytes)?;
+        Ok(self.output.flush()?)
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, VMError> {
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(trim_line_ending(line)))
+    }
+}
+
+// One input stream read in turn by the REPL or debugger and by the program
+// it runs, printing to stdout. Clones share the stream, so neither side has
+// to hold stdin locked while the other waits on it.
+#[derive(Debug)]
+pub struct SharedConsole<R> {
+    input: Arc<Mutex<R>>,
+}
+
+impl<R> SharedConsole<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input: Arc::new(Mutex::new(input)),
+        }
+    }
+}
+
+impl<R> Clone for SharedConsole<R> {
+    fn clone(&self) -> Self {
+        Self {
+            input: self.input.clone(),
+        }
+    }
+}
+
+impl<R: BufRead + Debug + Send> Console for SharedConsole<R> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), VMError> {
+        StdConsole.write(bytes)
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, VMError> {
+        let mut input = self
+            .input
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(trim_line_ending(line)))
+    }
+}
+
+// Canned input and captured output, for embedding and deterministic runs
+#[derive(Debug, Default)]
+pub struct BufferConsole {
+    input: VecDeque<String>,
+    output: Vec<u8>,
+}
+
+impl BufferConsole {
+    pub fn new(input: &str) -> Self {
+        Self {
+            input: input.lines().map(str::to_string).collect(),
+            output: Vec::new(),
+        }
+    }
+}
+
+impl Console for BufferConsole {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), VMError> {
+        self.output.extend(bytes);
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, VMError> {
+        Ok(self.input.pop_front())
+    }
+
+    fn captured(&self) -> Option<&[u8]> {
+        Some(&self.output)
+    }
+}
+
+fn trim_line_ending(mut line: String) -> String {
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        program::Program,
+        testing::{run_with, vm_with},
+        word::Word,
+    };
+
+    // What the program printed and the stack it halted with
+    fn run(hasm: &str, input: &str) -> (Vec<u8>, Vec<Word>) {
+        let run = run_with(hasm, input).unwrap();
+        assert!(run.halted);
+        (run.output, run.stack)
+    }
+
+    #[test]
+    fn readi_takes_one_line_at_a_time() {
+        let hasm = "readi
+            readi
+            addi
+            printi
+            halt";
+        assert_eq!(run(hasm, "40\r\n2\n"), (b"42".to_vec(), vec![]));
+    }
+
+    #[test]
+    fn readln_stores_the_line_and_its_length() {
+        let hasm = "push 20
+            alloc
+            dup 0
+            push 16
+            readln
+            printi
+            dup 0
+            prints
+            push 16
+            readln
+            halt";
+        // Out of input, the second readln leaves -1 behind
+        assert_eq!(
+            run(hasm, "hello"),
+            (b"5hello".to_vec(), vec![Word::i64(-1)])
+        );
+    }
+
+    #[test]
+    fn a_failed_readln_leaves_its_operands() {
+        let mut vm = vm_with(
+            Program::from_hasm("push 8\nalloc\npush 4\nreadln\nhalt").unwrap(),
+            "",
+        );
+        vm.set_console(Box::new(StreamConsole::new(&b"\xff\n"[..], Vec::new())));
+        assert!(vm.run(Some(16)).is_err());
+        assert_eq!(vm.stack().len(), 2);
+
+        vm.set_console(Box::new(BufferConsole::new("ok")));
+        vm.run(Some(16)).unwrap();
+        assert_eq!(vm.stack(), [Word::u64(2)]);
+    }
+}
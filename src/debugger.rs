@@ -0,0 +1,356 @@
+use std::{collections::BTreeSet, io::Write};
+
+use crate::{console::Console, VMError, VM};
+
+const HELP: &str = "commands act on the loaded program, nothing runs until asked
+  s, step [n]              run n instructions, 1 by default
+  c, continue              run until a breakpoint, halt or the fuel runs out
+  rs, reverse-step [n]     undo n instructions, 1 by default
+  rc, reverse-continue     go back to the last breakpoint passed
+  b, break <ip|label>      stop before the instruction
+  d, delete <ip|label>     remove a breakpoint
+  l, list                  show the program around ip
+  stack                    show the stack
+  bt, backtrace            show the call frames and their locals
+  globals                  show the globals by name
+  fibers                   show the running fiber and the ones waiting
+  info                     show ip, fuel, steps taken and breakpoints
+  h, help                  this message
+  q, quit                  leave";
+
+#[derive(Debug)]
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger {
+    // The VM should have its program loaded and history recording on
+    pub fn new(vm: VM) -> Self {
+        Self {
+            vm,
+            breakpoints: BTreeSet::new(),
+        }
+    }
+
+    fn running(&self) -> bool {
+        !self.vm.halted() && self.vm.fuel() > 0
+    }
+
+    fn resolve(&self, target: &str) -> Option<usize> {
+        target.parse().ok().or_else(|| {
+            self.vm
+                .program()
+                .labels
+                .iter()
+                .find(|(_, label)| label == target)
+                .map(|(ip, _)| *ip)
+        })
+    }
+
+    fn step(&mut self, n: usize) -> Result<(), VMError> {
+        for _ in 0..n {
+            if !self.running() {
+                break;
+            }
+            self.vm.step()?;
+        }
+        Ok(())
+    }
+
+    fn cont(&mut self) -> Result<(), VMError> {
+        while self.running() {
+            self.vm.step()?;
+            if self.breakpoints.contains(&self.vm.ip()) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn reverse_step(&mut self, n: usize) -> Result<usize, VMError> {
+        let mut undone = 0;
+        while undone < n && self.vm.step_back()? {
+            undone += 1;
+        }
+        Ok(undone)
+    }
+
+    fn reverse_cont(&mut self) -> Result<usize, VMError> {
+        let mut undone = 0;
+        while self.vm.step_back()? {
+            undone += 1;
+            if self.breakpoints.contains(&self.vm.ip()) {
+                break;
+            }
+        }
+        Ok(undone)
+    }
+
+    fn show_stack(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let stack: Vec<String> = self.vm.stack().iter().map(|w| format!("{:?}", w)).collect();
+        writeln!(out, "[{}]", stack.join(", "))
+    }
+
+    // Innermost frame first, each outer one at the call it is waiting on
+    fn backtrace(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let locals = self.vm.locals();
+        let mut at = self.vm.ip();
+        for (depth, frame) in self.vm.frames().iter().rev().enumerate() {
+            let label = self.vm.program().label_at(at).unwrap_or_default();
+            let frame_locals: Vec<String> = locals[frame.fp..frame.fp + frame.locals]
+                .iter()
+                .map(|w| format!("{:?}", w))
+                .collect();
+            writeln!(
+                out,
+                "#{:<3} ip {:>4} {:<16} locals [{}]",
+                depth,
+                at,
+                label,
+                frame_locals.join(", ")
+            )?;
+            at = frame.return_ip.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    fn show_globals(&self, out: &mut impl Write) -> std::io::Result<()> {
+        for (slot, word) in self.vm.globals().iter().enumerate() {
+            match self.vm.program().global_name(slot) {
+                Some(name) => writeln!(out, "{} = {}", name, word)?,
+                None => writeln!(out, "{} = {}", slot, word)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn show_fibers(&self, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(
+            out,
+            "fiber {} at ip {}, running",
+            self.vm.fiber_id(),
+            self.vm.ip()
+        )?;
+        for fiber in self.vm.parked_fibers() {
+            let state = if fiber.blocked { "blocked" } else { "ready" };
+            writeln!(out, "fiber {} at ip {}, {}", fiber.id, fiber.ip, state)?;
+        }
+        Ok(())
+    }
+
+    fn show_ip(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let ip = self.vm.ip();
+        let inst = self
+            .vm
+            .program()
+            .insts
+            .get(ip)
+            .map_or("<end>".to_string(), |inst| {
+                self.vm.program().inst_to_hasm(inst)
+            });
+        let label = self.vm.program().label_at(ip).unwrap_or_default();
+        let state = if self.vm.halted() {
+            " (halted)"
+        } else if self.vm.fuel() == 0 {
+            " (out of fuel)"
+        } else {
+            ""
+        };
+        writeln!(out, "ip {:>4}: {:<16} {}{}", ip, inst, label, state)
+    }
+
+    fn list(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let program = self.vm.program();
+        let ip = self.vm.ip();
+        let from = ip.saturating_sub(5);
+        for (at, inst) in program.insts.iter().enumerate().skip(from).take(11) {
+            if let Some((_, label)) = program.labels.iter().find(|(l, _)| *l == at) {
+                writeln!(out, "       {}:", label)?;
+            }
+            let marker = match (at == ip, self.breakpoints.contains(&at)) {
+                (true, true) => "*>",
+                (true, false) => " >",
+                (false, true) => "* ",
+                (false, false) => "  ",
+            };
+            writeln!(out, "{} {:>4}: {}", marker, at, program.inst_to_hasm(inst))?;
+        }
+        Ok(())
+    }
+
+    fn info(&self, out: &mut impl Write) -> std::io::Result<()> {
+        self.show_ip(out)?;
+        writeln!(out, "fuel {}", self.vm.fuel())?;
+        if let Some(history) = self.vm.history() {
+            writeln!(
+                out,
+                "steps {}, can go back to step {}",
+                history.steps(),
+                history.earliest()
+            )?;
+        }
+        let breakpoints: Vec<String> = self.breakpoints.iter().map(usize::to_string).collect();
+        writeln!(out, "breakpoints [{}]", breakpoints.join(", "))
+    }
+
+    fn command(&mut self, line: &str, out: &mut impl Write) -> Result<bool, VMError> {
+        let mut words = line.split_whitespace();
+        let (cmd, arg) = (words.next().unwrap_or_default(), words.next());
+        let count = arg.and_then(|n| n.parse().ok()).unwrap_or(1);
+
+        let result = match (cmd, arg) {
+            ("q" | "quit", _) => return Ok(false),
+            ("h" | "help", _) => {
+                writeln!(out, "{}", HELP)?;
+                return Ok(true);
+            }
+            ("stack", _) => {
+                self.show_stack(out)?;
+                return Ok(true);
+            }
+            ("bt" | "backtrace", _) => {
+                self.backtrace(out)?;
+                return Ok(true);
+            }
+            ("globals", _) => {
+                self.show_globals(out)?;
+                return Ok(true);
+            }
+            ("fibers", _) => {
+                self.show_fibers(out)?;
+                return Ok(true);
+            }
+            ("l" | "list", _) => {
+                self.list(out)?;
+                return Ok(true);
+            }
+            ("info", _) => {
+                self.info(out)?;
+                return Ok(true);
+            }
+            ("b" | "break", Some(target)) => {
+                match self.resolve(target) {
+                    Some(ip) => {
+                        self.breakpoints.insert(ip);
+                        writeln!(out, "breakpoint at ip {}", ip)?;
+                    }
+                    None => writeln!(out, "no instruction or label {}", target)?,
+                }
+                return Ok(true);
+            }
+            ("d" | "delete", Some(target)) => {
+                match self.resolve(target) {
+                    Some(ip) if self.breakpoints.remove(&ip) => {
+                        writeln!(out, "deleted breakpoint at ip {}", ip)?
+                    }
+                    _ => writeln!(out, "no breakpoint at {}", target)?,
+                }
+                return Ok(true);
+            }
+            ("s" | "step", _) => self.step(count),
+            ("c" | "continue", _) => self.cont(),
+            ("rs" | "reverse-step", _) => self.reverse_step(count).and_then(|undone| {
+                if undone < count {
+                    writeln!(out, "reached the start of the recorded history")?;
+                }
+                Ok(())
+            }),
+            ("rc" | "reverse-continue", _) => self.reverse_cont().and_then(|_| {
+                if !self.breakpoints.contains(&self.vm.ip()) {
+                    writeln!(out, "reached the start of the recorded history")?;
+                }
+                Ok(())
+            }),
+            _ => {
+                writeln!(out, "unknown command {}, try help", cmd)?;
+                return Ok(true);
+            }
+        };
+
+        // A faulting instruction leaves the VM in front of it, so it can be inspected
+        if let Err(err) = result {
+            writeln!(out, "ERROR: {}", err)?;
+        }
+        self.show_ip(out)?;
+        self.show_stack(out)?;
+
+        Ok(true)
+    }
+
+    // Commands come from input, which the program may read from as well
+    pub fn run(&mut self, mut input: impl Console, mut out: impl Write) -> Result<(), VMError> {
+        writeln!(out, "haesuk debugger, help for commands")?;
+        self.show_ip(&mut out)?;
+        write!(out, "(hdb) ")?;
+        out.flush()?;
+
+        while let Some(line) = input.read_line()? {
+            let line = line.trim();
+
+            if !line.is_empty() && !self.command(line, &mut out)? {
+                return Ok(());
+            }
+
+            write!(out, "(hdb) ")?;
+            out.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{console::BufferConsole, testing::vm};
+
+    // Everything the session printed for commands
+    fn session(hasm: &str, commands: &str) -> String {
+        let mut vm = vm(hasm);
+        vm.record_history(16, 4);
+        let mut out = Vec::new();
+        Debugger::new(vm)
+            .run(BufferConsole::new(commands), &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    const HASM: &str = ".global total
+        push 1
+        push 2
+    sum:
+        addi
+        gstore total
+        halt";
+
+    #[test]
+    fn continues_to_breakpoints_and_back() {
+        let out = session(HASM, "b sum\nc\nc\nglobals\nrc\ninfo\nrs 9\nq");
+        let expected = [
+            "breakpoint at ip 2",
+            "ip    2: addi             sum\n[i64(1), i64(2)]",
+            "ip    4: halt             sum (halted)\n[]",
+            "total = 3",
+            "ip    2: addi             sum\n[i64(1), i64(2)]",
+            "steps 2, can go back to step 0\nbreakpoints [2]",
+            "reached the start of the recorded history\nip    0: push 1",
+        ];
+        let mut rest = out.as_str();
+        for text in expected {
+            let at = rest
+                .find(text)
+                .unwrap_or_else(|| panic!("{:?} in {}", text, out));
+            rest = &rest[at + text.len()..];
+        }
+    }
+
+    #[test]
+    fn faults_leave_the_vm_in_front_of_them() {
+        let out = session("push 0\naddi\nhalt", "s 2\nd 1\nwhat\nq");
+        assert!(out.contains("ERROR: "));
+        assert!(out.contains("ip    1: addi"));
+        assert!(out.contains("no breakpoint at 1"));
+        assert!(out.contains("unknown command what, try help"));
+    }
+}
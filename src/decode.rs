@@ -0,0 +1,68 @@
+use crate::{inst::Inst, program::Program, word::Word};
+
+// Instructions as the fast interpreter loop runs them: decoded once at load,
+// with operands already turned into what they stand for, labels into ips and
+// constant indexes into their words. VM::exec_op runs them for step and the
+// fast loop alike. Everything else is Step, which only step runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Push(Word),
+    Addi,
+    Subi,
+    Muli,
+    Divi,
+    Addf,
+    Subf,
+    Mulf,
+    Divf,
+    Jmp(usize),
+    Eq(Word),
+    Dup(usize),
+    Nop,
+    Call(usize),
+    Ret,
+    Lload(usize),
+    Lstore(usize),
+    Gload(usize),
+    Gstore(usize),
+    Step,
+}
+
+impl Op {
+    pub fn decode(program: &Program, inst: &Inst) -> Op {
+        match inst {
+            Inst::InstPush(word) => Op::Push(*word),
+            Inst::InstPushk(index) => program
+                .constants
+                .get(u64::from(*index) as usize)
+                .map_or(Op::Step, |word| Op::Push(*word)),
+            Inst::InstAddi => Op::Addi,
+            Inst::InstSubi => Op::Subi,
+            Inst::InstMuli => Op::Muli,
+            Inst::InstDivi => Op::Divi,
+            Inst::InstAddf => Op::Addf,
+            Inst::InstSubf => Op::Subf,
+            Inst::InstMulf => Op::Mulf,
+            Inst::InstDivf => Op::Divf,
+            Inst::InstJmp(target) => Op::Jmp(u64::from(*target) as usize),
+            Inst::InstEq(word) => Op::Eq(*word),
+            Inst::InstDup(n) => Op::Dup(u64::from(*n) as usize),
+            Inst::InstNop => Op::Nop,
+            Inst::InstCall(target) => Op::Call(u64::from(*target) as usize),
+            Inst::InstRet => Op::Ret,
+            Inst::InstLload(index) => Op::Lload(u64::from(*index) as usize),
+            Inst::InstLstore(index) => Op::Lstore(u64::from(*index) as usize),
+            Inst::InstGload(slot) => Op::Gload(u64::from(*slot) as usize),
+            Inst::InstGstore(slot) => Op::Gstore(u64::from(*slot) as usize),
+            _ => Op::Step,
+        }
+    }
+}
+
+pub fn decode(program: &Program) -> Vec<Op> {
+    program
+        .insts
+        .iter()
+        .map(|inst| Op::decode(program, inst))
+        .collect()
+}
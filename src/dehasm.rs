@@ -1,35 +1,208 @@
-use std::{
-    fs::File,
-    io::{self, Read, Write},
-};
+use std::{fmt::Write, fs, path::Path};
 
 use crate::{program::Program, word::Word, VMError};
 
-pub fn ha_to_hasm(path: &str) -> io::Result<()> {
-    let mut file = File::open(path).map_err(|err| VMError::IoFail {
-        err: err.to_string(),
-    })?;
-
-    let mut buffer = Vec::new();
-
-    file.read_to_end(&mut buffer)
-        .map_err(|err| VMError::IoFail {
-            err: err.to_string(),
-        })?;
+pub fn ha_to_hasm(path: &Path, hasm_path: &Path) -> Result<(), VMError> {
+    let buffer = fs::read(path)?;
 
     let program = Program::from_bytes(&buffer)?;
-    let hasm = program.to_hasm();
-
-    let hasm_path = path.replace(".ha", ".hasm");
+    fs::write(hasm_path, program_to_hasm(&program))?;
+    Ok(())
+}
 
-    let mut file = File::create(hasm_path)?;
-    for inst in hasm {
-        writeln!(file, "{}", inst)?;
+// Source that assembles back to the same program
+pub fn program_to_hasm(program: &Program) -> String {
+    let mut hasm = String::new();
+    for (name, bytes) in data_chunks(program) {
+        writeln!(hasm, ".data {} \"{}\"", name, escape_data(bytes)).unwrap();
     }
-
-    Ok(())
+    for (slot, init) in program.globals.iter().enumerate() {
+        let name = match program.global_name(slot) {
+            Some(name) => name.to_string(),
+            None => format!("global_{}", slot),
+        };
+        match init {
+            Word::i64(0) => writeln!(hasm, ".global {}", name).unwrap(),
+            init => writeln!(hasm, ".global {} = {}", name, init.to_literal()).unwrap(),
+        }
+    }
+    for (ip, inst) in program.to_hasm().iter().enumerate() {
+        for (_, label) in program
+            .labels
+            .iter()
+            .filter(|(label_ip, _)| *label_ip == ip)
+        {
+            writeln!(hasm, "{}:", label).unwrap();
+        }
+        writeln!(hasm, "{}", inst).unwrap();
+    }
+    hasm
 }
 
 pub fn hasm_with_operand(hasm: String, operand: Word) -> String {
     format!("{} {}", hasm, operand)
 }
+
+// Data split at its labels, with a made up name for anything before the first one
+fn data_chunks(program: &Program) -> Vec<(String, &[u8])> {
+    let mut starts: Vec<(usize, String)> = program.data_labels.clone();
+    if starts.first().is_none_or(|(at, _)| *at > 0) && !program.data.is_empty() {
+        starts.insert(0, (0, "data_0".to_string()));
+    }
+
+    let ends = starts
+        .iter()
+        .skip(1)
+        .map(|(at, _)| *at)
+        .chain([program.data.len()]);
+    starts
+        .iter()
+        .zip(ends)
+        .map(|((start, name), end)| (name.clone(), &program.data[*start..end.max(*start)]))
+        .collect()
+}
+
+pub fn escape_data(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| match byte {
+            b'\n' => "\\n".to_string(),
+            b'\r' => "\\r".to_string(),
+            b'\t' => "\\t".to_string(),
+            0 => "\\0".to_string(),
+            b'\\' => "\\\\".to_string(),
+            b'"' => "\\\"".to_string(),
+            b' '..=b'~' => (*byte as char).to_string(),
+            _ => format!("\\x{:02x}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inst::Inst;
+
+    // xorshift, so every run checks the same programs
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n.max(1) as u64) as usize
+        }
+
+        fn bits(&mut self) -> u64 {
+            const EDGES: [u64; 8] = [
+                0,
+                u64::MAX,
+                i64::MIN as u64,
+                0x8000_0000_0000_0000, // -0.0
+                0x7FF8_0000_0000_0001, // NaN payloads
+                0xFFF0_0000_0000_0002,
+                0x7FF0_0000_0000_0000, // inf
+                1,
+            ];
+            match self.below(3) {
+                0 => EDGES[self.below(EDGES.len())],
+                1 => self.next() % 1000,
+                _ => self.next(),
+            }
+        }
+
+        fn word(&mut self) -> Word {
+            match self.below(3) {
+                0 => Word::i64(self.bits() as i64),
+                1 => Word::u64(self.bits()),
+                _ => Word::f64(f64::from_bits(self.bits())),
+            }
+        }
+    }
+
+    // pushk is written out as the push it stands for, and assembles as one
+    fn program(rng: &mut Rng) -> Program {
+        let mut program = Program::default();
+        let len = 1 + rng.below(40);
+        let globals = rng.below(4);
+        program.globals = (0..globals).map(|_| rng.word()).collect();
+        program.global_names = (0..globals)
+            .map(|slot| (slot, format!("g{}", slot)))
+            .collect();
+
+        let kinds: Vec<Inst> = (0..=u8::MAX)
+            .filter_map(Inst::deser_opcode)
+            .filter(|inst| !matches!(inst, Inst::InstPushk(_) | Inst::InstPush(_)))
+            .collect();
+        program.insts = (0..len)
+            .map(|_| {
+                let n = Word::u64(rng.bits());
+                match kinds.get(rng.below(kinds.len() + 3)) {
+                    None => Inst::InstPush(rng.word()),
+                    Some(Inst::InstPushs(_)) => {
+                        let index = rng.below(program.strings.len() + 1);
+                        if index == program.strings.len() {
+                            let len = rng.below(6);
+                            let mut literal: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+                            literal.push(index as u8);
+                            program.strings.push(literal);
+                        }
+                        Inst::InstPushs(Word::u64(index as u64))
+                    }
+                    Some(Inst::InstGload(_)) if globals > 0 => {
+                        Inst::InstGload(Word::u64(rng.below(globals) as u64))
+                    }
+                    Some(Inst::InstGstore(_)) if globals > 0 => {
+                        Inst::InstGstore(Word::u64(rng.below(globals) as u64))
+                    }
+                    Some(Inst::InstGload(_) | Inst::InstGstore(_)) => Inst::InstNop,
+                    Some(Inst::InstHalt(_)) => Inst::InstHalt(Word::i64(rng.bits() as i64)),
+                    Some(
+                        inst @ (Inst::InstJmp(_)
+                        | Inst::InstCall(_)
+                        | Inst::InstTry(_)
+                        | Inst::InstSpawn(_)),
+                    ) => inst.clone().with_target(rng.below(len)),
+                    Some(Inst::InstEq(_)) => Inst::InstEq(n),
+                    Some(Inst::InstDup(_)) => Inst::InstDup(n),
+                    Some(Inst::InstSyscall(_)) => Inst::InstSyscall(n),
+                    Some(Inst::InstEnter(_)) => Inst::InstEnter(n),
+                    Some(Inst::InstLload(_)) => Inst::InstLload(n),
+                    Some(Inst::InstLstore(_)) => Inst::InstLstore(n),
+                    Some(inst) => inst.clone(),
+                }
+            })
+            .collect();
+
+        let mut ip = 0;
+        while ip < len {
+            program.labels.push((ip, format!("l{}", ip)));
+            ip += 1 + rng.below(8);
+        }
+        let mut at = 0;
+        for chunk in 0..rng.below(4) {
+            program.data_labels.push((at, format!("d{}", chunk)));
+            let len = 1 + rng.below(12);
+            program.data.extend((0..len).map(|_| rng.next() as u8));
+            at += len;
+        }
+        program
+    }
+
+    #[test]
+    fn disassembly_assembles_back_to_the_same_bytes() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..500 {
+            let bytes = program(&mut rng).to_bytes().unwrap();
+            let hasm = program_to_hasm(&Program::from_bytes(&bytes).unwrap());
+            let parsed =
+                Program::from_hasm(&hasm).unwrap_or_else(|err| panic!("{}\n{}", err, hasm));
+            assert_eq!(parsed.to_bytes().unwrap(), bytes, "{}", hasm);
+        }
+    }
+}
@@ -1,8 +1,56 @@
-use std::io;
+use std::{fmt::Display, io};
 
 use thiserror::Error;
 
-use crate::inst::Inst;
+use crate::{heap::HeapFault, inst::Inst, verifier::VerifyError, word::Word};
+
+// Stack words kept with a runtime error
+pub const ERROR_STACK_DEPTH: usize = 8;
+
+// Where a runtime error happened, attached as it leaves VM::step
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorContext {
+    pub ip: usize,
+    // Disassembled, with string and constant operands written out
+    pub inst: String,
+    pub label: Option<String>,
+    // At most ERROR_STACK_DEPTH words from the top, top last
+    pub stack: Vec<Word>,
+    pub stack_size: usize,
+    // (ip, label) of each call waiting on a return, innermost first
+    pub calls: Vec<(usize, Option<String>)>,
+}
+
+impl Display for ErrorContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "  at ip {}: {}", self.ip, self.inst)?;
+        if let Some(label) = &self.label {
+            write!(f, " in {}", label)?;
+        }
+
+        let stack: Vec<String> = self
+            .stack
+            .iter()
+            .rev()
+            .map(|w| format!("{:?}", w))
+            .collect();
+        write!(
+            f,
+            "\n  stack, top first ({} of {}): [{}]",
+            self.stack.len(),
+            self.stack_size,
+            stack.join(", ")
+        )?;
+
+        for (ip, label) in &self.calls {
+            write!(f, "\n  called from ip {}", ip)?;
+            if let Some(label) = label {
+                write!(f, " in {}", label)?;
+            }
+        }
+        Ok(())
+    }
+}
 
 #[derive(Error, Debug)]
 pub enum VMError {
@@ -24,6 +72,51 @@ pub enum VMError {
     #[error("Invalid operand")]
     InvalidOperand,
 
+    #[error("Invalid input {input:?}")]
+    InvalidInput { input: String },
+
+    #[error("Permission denied for syscall {syscall}")]
+    PermissionDenied { syscall: &'static str },
+
+    #[error("Out of memory allocating {requested} bytes")]
+    OutOfMemory { requested: u64 },
+
+    #[error("Heap fault at {addr}: {fault}")]
+    HeapFault { addr: u64, fault: HeapFault },
+
+    #[error("Too many {kind}s, the limit is {limit}")]
+    HandleLimit { kind: &'static str, limit: usize },
+
+    #[error("Deadlock, every fiber is blocked")]
+    Deadlock,
+
+    #[error("No {kind} {id}")]
+    InvalidHandle { kind: &'static str, id: u64 },
+
+    #[error("Call stack overflow at depth {depth}")]
+    CallStackOverflow { depth: usize },
+
+    #[error("Return without a matching call")]
+    ReturnWithoutCall,
+
+    #[error("Local {index} out of range, the frame has {locals}")]
+    LocalOutOfRange { index: u64, locals: usize },
+
+    #[error("Global {index} out of range")]
+    GlobalOutOfRange { index: u64 },
+
+    #[error("endtry without a matching try")]
+    EndtryWithoutTry,
+
+    #[error("Uncaught throw {code}")]
+    Uncaught { code: i64 },
+
+    #[error("{error}\n{context}")]
+    Runtime {
+        error: Box<VMError>,
+        context: Box<ErrorContext>,
+    },
+
     #[error("Deserialize opcode failed")]
     DeserializeOpcodeFail,
 
@@ -38,10 +131,131 @@ pub enum VMError {
 
     #[error("Resolve label fail")]
     ResolveLabelFail,
+
+    #[error("Unsupported .ha version {version}")]
+    UnsupportedVersion { version: u16 },
+
+    #[error("Max stack {max_stack} exceeds the stack limit")]
+    StackLimitExceeded { max_stack: usize },
+
+    #[error("Verify fail, {} problem(s) found", errors.len())]
+    VerifyFail { errors: Vec<VerifyError> },
+
+    #[error("{count} file(s) still open, a snapshot file cannot hold them")]
+    FilesOpen { count: usize },
+}
+
+impl VMError {
+    // Process exit status for the CLI, listed in `haesuk --help`
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            VMError::HandleLimit { .. } => 61,
+            VMError::Deadlock => 62,
+            VMError::InvalidHandle { .. } => 63,
+            VMError::CallStackOverflow { .. } => 64,
+            VMError::ReturnWithoutCall => 65,
+            VMError::LocalOutOfRange { .. } => 66,
+            VMError::GlobalOutOfRange { .. } => 67,
+            VMError::EndtryWithoutTry => 68,
+            VMError::Uncaught { .. } => 69,
+            VMError::Runtime { error, .. } => error.exit_code(),
+            VMError::StackOverflow { .. } => 70,
+            VMError::StackUnderflow { .. } => 71,
+            VMError::OperandNonExists { .. } => 72,
+            VMError::DivisionByZero => 73,
+            VMError::SegmentFault => 74,
+            VMError::InvalidOperand => 75,
+            VMError::InvalidInput { .. } => 76,
+            VMError::PermissionDenied { .. } => 77,
+            VMError::OutOfMemory { .. } => 78,
+            VMError::HeapFault { .. } => 79,
+            VMError::DeserializeOpcodeFail => 80,
+            VMError::ParseLeBytesFail => 81,
+            VMError::InvalidAsmInst { .. } => 82,
+            VMError::ResolveLabelFail => 83,
+            VMError::UnsupportedVersion { .. } => 84,
+            VMError::IoFail { .. } => 90,
+            VMError::VerifyFail { .. } => 91,
+            VMError::StackLimitExceeded { .. } => 92,
+            VMError::FilesOpen { .. } => 93,
+        }
+    }
+}
+
+impl From<io::Error> for VMError {
+    fn from(err: io::Error) -> Self {
+        VMError::IoFail {
+            err: err.to_string(),
+        }
+    }
 }
 
 impl From<VMError> for io::Error {
     fn from(error: VMError) -> Self {
-        io::Error::new(io::ErrorKind::Other, format!("{:#?}", error))
+        io::Error::other(format!("{:#?}", error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::vm;
+
+    fn context(err: VMError) -> (VMError, ErrorContext) {
+        match err {
+            VMError::Runtime { error, context } => (*error, *context),
+            err => panic!("no context on {:?}", err),
+        }
+    }
+
+    #[test]
+    fn runtime_errors_say_where_they_happened() {
+        let pushes: String = (1..=10).map(|n| format!("push {}\n", n)).collect();
+        let hasm = format!(
+            "{}call outer
+            halt
+        outer:
+            call inner
+            ret
+        inner:
+            push 0
+            divi
+            ret",
+            pushes
+        );
+
+        // The fast loop and step report the same thing
+        let ran = context(vm(&hasm).run(Some(64)).unwrap_err());
+        let mut stepped = vm(&hasm);
+        let stepped = context(loop {
+            if let Err(err) = stepped.step() {
+                break err;
+            }
+        });
+        assert_eq!(ran.1, stepped.1);
+
+        let (error, context) = ran;
+        assert!(matches!(error, VMError::DivisionByZero));
+        assert_eq!((context.ip, context.inst.as_str()), (15, "divi"));
+        assert_eq!(context.label.as_deref(), Some("inner"));
+        assert_eq!(context.stack_size, 11);
+        assert_eq!(context.stack.len(), ERROR_STACK_DEPTH);
+        assert_eq!(context.stack.last(), Some(&Word::i64(0)));
+        assert_eq!(context.calls, [(12, Some("outer".to_string())), (10, None)]);
+
+        assert_eq!(
+            context.to_string(),
+            "  at ip 15: divi in inner
+  stack, top first (8 of 11): [i64(0), i64(10), i64(9), i64(8), i64(7), i64(6), i64(5), i64(4)]
+  called from ip 12 in outer
+  called from ip 10"
+        );
+    }
+
+    #[test]
+    fn context_keeps_the_exit_code() {
+        let err = vm("push 1\naddi\nhalt").run(Some(8)).unwrap_err();
+        assert_eq!(err.exit_code(), 71);
+        assert!(err.to_string().contains("at ip 1: addi"), "{}", err);
     }
 }
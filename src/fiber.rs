@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::word::Word;
+
+pub const FIBER_LIMIT: usize = 1024;
+pub const CHANNEL_LIMIT: usize = 1024;
+pub const MAIN_FIBER: u64 = 0;
+// Words a stack starts out with, it doubles from there up to its limit
+const INITIAL_STACK: usize = 16;
+
+// One call. Its locals are locals[fp..fp + locals], reserved by enter.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Frame {
+    pub return_ip: usize,
+    pub fp: usize,
+    pub locals: usize,
+}
+
+// An active try: where to go on a throw, and the depths to unwind to
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Handler {
+    pub ip: usize,
+    pub stack_size: usize,
+    pub frame_count: usize,
+}
+
+// One thread of execution, with its own stack, ip, calls and tries. Memory,
+// globals and the heap are shared by every fiber.
+#[derive(Debug, Clone)]
+pub struct Fiber {
+    pub id: u64,
+    pub stack: Vec<Word>,
+    pub stack_size: usize,
+    pub stack_limit: usize,
+    pub ip: usize,
+
+    // The root frame, then one per call not yet returned from
+    pub frames: Vec<Frame>,
+    pub frame_count: usize,
+    pub locals: Vec<Word>,
+    pub locals_size: usize,
+    // Innermost try last
+    pub handlers: Vec<Handler>,
+    pub handler_count: usize,
+
+    // Waiting in join or recv, with nothing since that could wake it up
+    pub blocked: bool,
+}
+
+impl Fiber {
+    pub fn new(id: u64, ip: usize, stack_limit: usize) -> Self {
+        Self {
+            id,
+            stack: vec![Word::i64(0); INITIAL_STACK.min(stack_limit)],
+            stack_size: 0,
+            stack_limit,
+            ip,
+
+            frames: vec![Frame::default()],
+            frame_count: 1,
+            locals: Vec::new(),
+            locals_size: 0,
+            handlers: Vec::new(),
+            handler_count: 0,
+
+            blocked: false,
+        }
+    }
+
+    // Makes room for one more word, false once the stack is at its limit
+    pub fn reserve(&mut self) -> bool {
+        if self.stack_size < self.stack.len() {
+            return true;
+        }
+        if self.stack_size >= self.stack_limit {
+            return false;
+        }
+        let len = (self.stack.len() * 2).clamp(INITIAL_STACK, self.stack_limit);
+        self.stack.resize(len, Word::i64(0));
+        true
+    }
+}
+
+// Every fiber but the running one, plus what they share between them
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    // Round robin order, the next to run first
+    pub fibers: VecDeque<Fiber>,
+    pub channels: Vec<VecDeque<Word>>,
+    // What each finished fiber left on top of its stack, by id
+    pub results: BTreeMap<u64, Word>,
+    pub next_id: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            fibers: VecDeque::new(),
+            channels: Vec::new(),
+            results: BTreeMap::new(),
+            next_id: MAIN_FIBER + 1,
+        }
+    }
+
+    // Something happened that a blocked fiber may have been waiting on
+    pub fn wake_all(&mut self) {
+        self.fibers
+            .iter_mut()
+            .for_each(|fiber| fiber.blocked = false);
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stacks_double_until_their_limit() {
+        let mut fiber = Fiber::new(1, 0, 40);
+        assert_eq!(fiber.stack.len(), INITIAL_STACK);
+
+        let mut lens = vec![];
+        while fiber.reserve() {
+            fiber.stack_size += 1;
+            if lens.last() != Some(&fiber.stack.len()) {
+                lens.push(fiber.stack.len());
+            }
+        }
+        assert_eq!(lens, [16, 32, 40]);
+        assert_eq!(fiber.stack_size, 40);
+    }
+
+    #[test]
+    fn empty_limits_never_grow() {
+        let mut fiber = Fiber::new(1, 0, 0);
+        assert!(fiber.stack.is_empty());
+        assert!(!fiber.reserve());
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+
+use crate::{
+    heap::{Heap, HeapFault, HeapMemory},
+    word::Word,
+    VMError,
+};
+
+pub const GC_THRESHOLD: u64 = 64 * 1024;
+
+// Collected objects live in managed heap blocks. The payload starts with an
+// object header, len << 8 | kind, and references are ptr words pointing just
+// past it. A record holds len slots of a tag then a value, so the collector
+// can tell references from numbers; byte objects and strings hold len raw
+// bytes.
+const OBJECT_HEADER_SIZE: u64 = 8;
+const SLOT_SIZE: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectKind {
+    Record = 1,
+    Bytes = 2,
+    String = 3,
+}
+
+impl ObjectKind {
+    fn from_header(header: u64) -> Option<Self> {
+        match header & 0xFF {
+            1 => Some(ObjectKind::Record),
+            2 => Some(ObjectKind::Bytes),
+            3 => Some(ObjectKind::String),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct GcStats {
+    pub collections: u64,
+    pub freed_objects: u64,
+    pub freed_bytes: u64,
+    // As of the last collection
+    pub live_objects: u64,
+    pub live_bytes: u64,
+}
+
+fn not_an_object(addr: u64) -> VMError {
+    VMError::HeapFault {
+        addr,
+        fault: HeapFault::NotAnObject,
+    }
+}
+
+fn wrong_kind(reference: Word) -> VMError {
+    VMError::HeapFault {
+        addr: u64::from(reference),
+        fault: HeapFault::WrongKind,
+    }
+}
+
+fn make_reference(addr: u64) -> Word {
+    Word::ptr(addr + OBJECT_HEADER_SIZE)
+}
+
+pub fn alloc(
+    heap: &Heap,
+    mem: &mut impl HeapMemory,
+    kind: ObjectKind,
+    len: u64,
+) -> Result<Word, VMError> {
+    let size = match kind {
+        ObjectKind::Record => len.checked_mul(SLOT_SIZE),
+        ObjectKind::Bytes | ObjectKind::String => Some(len),
+    }
+    .and_then(|size| size.checked_add(OBJECT_HEADER_SIZE))
+    .filter(|_| len < 1 << 56)
+    .ok_or(VMError::OutOfMemory { requested: len })?;
+
+    let addr = heap.alloc_managed(mem, size)?;
+    mem.write_u64(addr, (len << 8) | kind as u64)?;
+    if kind == ObjectKind::Record {
+        let tag = Word::i64(0).tag() as u64;
+        for slot in 0..len {
+            mem.write_u64(addr + OBJECT_HEADER_SIZE + slot * SLOT_SIZE, tag)?;
+        }
+    }
+    Ok(make_reference(addr))
+}
+
+// (kind, len) of the object a reference points to
+pub fn object(
+    heap: &Heap,
+    mem: &impl HeapMemory,
+    reference: Word,
+) -> Result<(ObjectKind, u64), VMError> {
+    let Word::ptr(ptr) = reference else {
+        return Err(not_an_object(u64::from(reference)));
+    };
+    let addr = ptr.wrapping_sub(OBJECT_HEADER_SIZE);
+    if !heap.is_managed(mem, addr)? {
+        return Err(not_an_object(ptr));
+    }
+
+    let header = mem.read_u64(addr)?;
+    let kind = ObjectKind::from_header(header).ok_or_else(|| not_an_object(ptr))?;
+    Ok((kind, header >> 8))
+}
+
+fn slot(heap: &Heap, mem: &impl HeapMemory, reference: Word, index: u64) -> Result<u64, VMError> {
+    match object(heap, mem, reference)? {
+        (ObjectKind::Record, len) if index < len => Ok(u64::from(reference) + index * SLOT_SIZE),
+        (ObjectKind::Record, _) => Err(VMError::SegmentFault),
+        _ => Err(wrong_kind(reference)),
+    }
+}
+
+fn read_slot(mem: &impl HeapMemory, at: u64) -> Result<Word, VMError> {
+    let tag = mem.read_u64(at)? as u8;
+    let value = mem.read_u64(at + 8)?.to_le_bytes();
+    Word::from_tagged(tag, value).ok_or(VMError::ParseLeBytesFail)
+}
+
+pub fn get_field(
+    heap: &Heap,
+    mem: &impl HeapMemory,
+    reference: Word,
+    index: u64,
+) -> Result<Word, VMError> {
+    read_slot(mem, slot(heap, mem, reference, index)?)
+}
+
+pub fn set_field(
+    heap: &Heap,
+    mem: &mut impl HeapMemory,
+    reference: Word,
+    index: u64,
+    value: Word,
+) -> Result<(), VMError> {
+    let at = slot(heap, mem, reference, index)?;
+    mem.write_u64(at, value.tag() as u64)?;
+    mem.write_u64(at + 8, u64::from_le_bytes(value.to_le_bytes()))
+}
+
+pub fn string(heap: &Heap, mem: &impl HeapMemory, reference: Word) -> Result<Vec<u8>, VMError> {
+    match object(heap, mem, reference)? {
+        (ObjectKind::String, len) => mem.read(u64::from(reference), len),
+        _ => Err(wrong_kind(reference)),
+    }
+}
+
+// Mark and sweep: everything a root reaches, directly or through records,
+// survives, every other object is freed. Roots that do not point at an
+// object are ignored, they may be plain numbers that look like one.
+pub fn collect(heap: &Heap, mem: &mut impl HeapMemory, roots: &[Word]) -> Result<GcStats, VMError> {
+    // Object address -> (block size, marked)
+    let mut objects: HashMap<u64, (u64, bool)> = heap
+        .objects(mem)?
+        .into_iter()
+        .map(|(addr, size)| (addr + OBJECT_HEADER_SIZE, (size, false)))
+        .collect();
+
+    let mut pending: Vec<u64> = roots
+        .iter()
+        .filter_map(|word| match word {
+            Word::ptr(ptr) => Some(*ptr),
+            _ => None,
+        })
+        .collect();
+    while let Some(at) = pending.pop() {
+        let Some((_, marked)) = objects.get_mut(&at).filter(|(_, marked)| !marked) else {
+            continue;
+        };
+        *marked = true;
+
+        let header = mem.read_u64(at - OBJECT_HEADER_SIZE)?;
+        if ObjectKind::from_header(header) == Some(ObjectKind::Record) {
+            for index in 0..header >> 8 {
+                if let Word::ptr(ptr) = read_slot(mem, at + index * SLOT_SIZE)? {
+                    pending.push(ptr);
+                }
+            }
+        }
+    }
+
+    let mut stats = GcStats {
+        collections: 1,
+        ..Default::default()
+    };
+    let mut garbage = Vec::new();
+    for (at, (size, marked)) in objects {
+        if marked {
+            stats.live_objects += 1;
+            stats.live_bytes += size;
+        } else {
+            stats.freed_objects += 1;
+            stats.freed_bytes += size;
+            garbage.push((at - OBJECT_HEADER_SIZE, size));
+        }
+    }
+    heap.release_managed(mem, garbage)?;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{heap::HeapOptions, program::Program, VM};
+
+    fn heap() -> (Heap, Vec<u8>) {
+        let heap = Heap::new(0, HeapOptions::default());
+        let mut mem = Vec::new();
+        heap.init(&mut mem).unwrap();
+        (heap, mem)
+    }
+
+    fn fault(result: Result<impl std::fmt::Debug, VMError>) -> HeapFault {
+        match result {
+            Err(VMError::HeapFault { fault, .. }) => fault,
+            other => panic!("expected a heap fault, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frees_what_no_root_reaches() {
+        let (heap, mut mem) = heap();
+        let record = alloc(&heap, &mut mem, ObjectKind::Record, 2).unwrap();
+        let inner = alloc(&heap, &mut mem, ObjectKind::Bytes, 8).unwrap();
+        let lost = alloc(&heap, &mut mem, ObjectKind::String, 3).unwrap();
+        set_field(&heap, &mut mem, record, 1, inner).unwrap();
+
+        // A number equal to an object's address is not a reference to it
+        let roots = [record, Word::i64(u64::from(lost) as i64)];
+        let stats = collect(&heap, &mut mem, &roots).unwrap();
+        assert_eq!((stats.live_objects, stats.freed_objects), (2, 1));
+        // Header and three bytes, rounded up to whole words
+        assert_eq!(stats.freed_bytes, 16);
+        assert_eq!(get_field(&heap, &mem, record, 1).unwrap(), inner);
+        assert_eq!(fault(object(&heap, &mem, lost)), HeapFault::NotAnObject);
+    }
+
+    #[test]
+    fn cycles_live_and_die_together() {
+        let (heap, mut mem) = heap();
+        let a = alloc(&heap, &mut mem, ObjectKind::Record, 1).unwrap();
+        let b = alloc(&heap, &mut mem, ObjectKind::Record, 1).unwrap();
+        set_field(&heap, &mut mem, a, 0, b).unwrap();
+        set_field(&heap, &mut mem, b, 0, a).unwrap();
+
+        assert_eq!(collect(&heap, &mut mem, &[b]).unwrap().live_objects, 2);
+        assert_eq!(collect(&heap, &mut mem, &[]).unwrap().freed_objects, 2);
+        assert!(heap.objects(&mem).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fields_check_the_kind_and_index() {
+        let (heap, mut mem) = heap();
+        let record = alloc(&heap, &mut mem, ObjectKind::Record, 1).unwrap();
+        let bytes = alloc(&heap, &mut mem, ObjectKind::Bytes, 16).unwrap();
+
+        assert_eq!(get_field(&heap, &mem, record, 0).unwrap(), Word::i64(0));
+        assert!(matches!(
+            get_field(&heap, &mem, record, 1),
+            Err(VMError::SegmentFault)
+        ));
+        assert_eq!(
+            fault(get_field(&heap, &mem, bytes, 0)),
+            HeapFault::WrongKind
+        );
+        assert_eq!(fault(string(&heap, &mem, bytes)), HeapFault::WrongKind);
+        let inside = Word::ptr(u64::from(record) + 8);
+        assert_eq!(fault(object(&heap, &mem, inside)), HeapFault::NotAnObject);
+        assert!(alloc(&heap, &mut mem, ObjectKind::Record, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn allocating_collects_as_the_heap_grows() {
+        let mut vm = VM::new();
+        vm.configure_heap(HeapOptions {
+            limit: 4096,
+            gc_threshold: 512,
+            ..Default::default()
+        });
+        // Each turn drops the object the one before kept
+        let program = Program::from_hasm(
+            ".global kept
+        loop:
+            push 100
+            newbytes
+            gstore kept
+            jmp loop",
+        )
+        .unwrap();
+        vm.load_ha_from_memory(program).unwrap();
+        vm.run(Some(4_000)).unwrap();
+
+        let stats = vm.gc_stats();
+        assert!(stats.collections > 1);
+        assert_eq!(stats.live_objects, 1);
+        // Far more than the heap holds went through it
+        assert!(stats.freed_bytes > 16 * 4096);
+    }
+}
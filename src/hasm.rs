@@ -1,27 +1,24 @@
-use std::{
-    fs::File,
-    io::{self, Read, Write},
-};
-
-use crate::{program::Program, VMError};
+use std::{fs, path::Path};
 
-pub fn hasm_to_ha(path: &str) -> io::Result<()> {
-    let mut file = File::open(path).map_err(|err| VMError::IoFail {
-        err: err.to_string(),
-    })?;
+use crate::{
+    analysis::{analyze_stack, StackAnalysis},
+    cfg::Cfg,
+    optimizer::{optimize, Pass},
+    program::Program,
+    VMError,
+};
 
-    let mut buffer = String::new();
+pub fn hasm_to_ha(path: &Path, ha_path: &Path, passes: &[Pass]) -> Result<StackAnalysis, VMError> {
+    let buffer = fs::read_to_string(path)?;
 
-    file.read_to_string(&mut buffer)
-        .map_err(|err| VMError::IoFail {
-            err: err.to_string(),
-        })?;
+    let mut program = Program::from_hasm(&buffer)?;
+    optimize(&mut program, passes);
+    program.pool_constants();
 
-    let program = Program::from_hasm(&buffer)?;
-    let ha_path = path.replace(".hasm", ".ha");
+    let analysis = analyze_stack(&program, &Cfg::build(&program));
+    program.max_stack = Some(analysis.max);
 
-    let mut file = File::create(ha_path)?;
-    file.write_all(&program.to_bytes())?;
+    fs::write(ha_path, program.to_bytes()?)?;
 
-    Ok(())
+    Ok(analysis)
 }
@@ -0,0 +1,590 @@
+use std::fmt::Display;
+
+use clap::Args;
+
+use crate::{gc::GC_THRESHOLD, VMError};
+
+pub const HEAP_LIMIT: usize = 1 << 20;
+
+// The heap lives in VM memory right after the data, 8 byte aligned:
+// [0..8] free list head, [8..16] top, then blocks up to top. A block is a
+// u64 header, payload size | flags, then the payload. A free block keeps
+// the address of the next free payload in its first 8 bytes. Everything is in
+// memory, so snapshots and the undo log cover the heap without knowing of it.
+const HEAP_HEADER_SIZE: u64 = 16;
+const BLOCK_HEADER_SIZE: u64 = 8;
+const ALLOCATED: u64 = 1;
+// Owned by the garbage collector, never freed by the program
+const MANAGED: u64 = 2;
+const MIN_SPLIT: u64 = 16;
+const POISON: u8 = 0xDD;
+
+#[derive(Args, Debug, Clone, Copy)]
+pub struct HeapOptions {
+    /// Most bytes the heap may grow to
+    #[arg(id = "heap_limit", long = "heap-limit", default_value_t = HEAP_LIMIT)]
+    pub limit: usize,
+
+    /// Catch use after free by never reusing freed blocks
+    #[arg(id = "heap_debug", long = "heap-debug")]
+    pub debug: bool,
+
+    /// Collect garbage each time the heap grows by this many bytes
+    #[arg(id = "gc_threshold", long = "gc-threshold", default_value_t = GC_THRESHOLD)]
+    pub gc_threshold: u64,
+}
+
+impl Default for HeapOptions {
+    fn default() -> Self {
+        Self {
+            limit: HEAP_LIMIT,
+            debug: false,
+            gc_threshold: GC_THRESHOLD,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HeapFault {
+    DoubleFree,
+    UseAfterFree,
+    InvalidFree,
+    NotAnObject,
+    WrongKind,
+    Corrupted,
+}
+
+impl Display for HeapFault {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeapFault::DoubleFree => write!(f, "double free"),
+            HeapFault::UseAfterFree => write!(f, "use after free"),
+            HeapFault::InvalidFree => write!(f, "free of a non heap address"),
+            HeapFault::NotAnObject => write!(f, "not a collected object"),
+            HeapFault::WrongKind => write!(f, "wrong kind of object"),
+            HeapFault::Corrupted => write!(f, "corrupted heap"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HeapStats {
+    // Bytes from the first block to top, headers included
+    pub used: u64,
+    pub allocated: u64,
+    pub free: u64,
+    pub blocks: usize,
+    pub free_blocks: usize,
+    pub limit: usize,
+}
+
+// Memory as the allocator sees it, so the VM can log what it overwrites
+pub trait HeapMemory {
+    fn read_u64(&self, at: u64) -> Result<u64, VMError>;
+    fn write_u64(&mut self, at: u64, value: u64) -> Result<(), VMError>;
+    fn read(&self, at: u64, len: u64) -> Result<Vec<u8>, VMError>;
+    fn write(&mut self, at: u64, bytes: &[u8]) -> Result<(), VMError>;
+    fn fill(&mut self, at: u64, len: u64, byte: u8) -> Result<(), VMError>;
+    fn copy(&mut self, from: u64, to: u64, len: u64) -> Result<(), VMError>;
+    fn grow(&mut self, len: u64);
+}
+
+fn range(memory: &[u8], at: u64, len: u64) -> Result<std::ops::Range<usize>, VMError> {
+    let at = usize::try_from(at).map_err(|_| VMError::SegmentFault)?;
+    let end = usize::try_from(len)
+        .ok()
+        .and_then(|len| at.checked_add(len))
+        .filter(|end| *end <= memory.len())
+        .ok_or(VMError::SegmentFault)?;
+    Ok(at..end)
+}
+
+// Plain memory, nothing gets logged
+impl HeapMemory for Vec<u8> {
+    fn read_u64(&self, at: u64) -> Result<u64, VMError> {
+        let bytes = &self[range(self, at, 8)?];
+        Ok(u64::from_le_bytes(bytes.try_into().expect("8 bytes")))
+    }
+
+    fn write_u64(&mut self, at: u64, value: u64) -> Result<(), VMError> {
+        let range = range(self, at, 8)?;
+        self[range].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn read(&self, at: u64, len: u64) -> Result<Vec<u8>, VMError> {
+        Ok(self[range(self, at, len)?].to_vec())
+    }
+
+    fn write(&mut self, at: u64, bytes: &[u8]) -> Result<(), VMError> {
+        let range = range(self, at, bytes.len() as u64)?;
+        self[range].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn fill(&mut self, at: u64, len: u64, byte: u8) -> Result<(), VMError> {
+        let range = range(self, at, len)?;
+        self[range].fill(byte);
+        Ok(())
+    }
+
+    fn copy(&mut self, from: u64, to: u64, len: u64) -> Result<(), VMError> {
+        let from = range(self, from, len)?;
+        range(self, to, len)?;
+        self.copy_within(from, to as usize);
+        Ok(())
+    }
+
+    fn grow(&mut self, len: u64) {
+        if len as usize > self.len() {
+            self.resize(len as usize, 0);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Heap {
+    base: u64,
+    options: HeapOptions,
+}
+
+fn fault(addr: u64, fault: HeapFault) -> VMError {
+    VMError::HeapFault { addr, fault }
+}
+
+fn align(size: u64) -> u64 {
+    size.div_ceil(8).saturating_mul(8).max(8)
+}
+
+impl Heap {
+    pub fn new(data_size: usize, options: HeapOptions) -> Self {
+        Self {
+            base: (data_size as u64).div_ceil(8) * 8,
+            options,
+        }
+    }
+
+    pub fn first_block(&self) -> u64 {
+        self.base + HEAP_HEADER_SIZE
+    }
+
+    pub fn init(&self, mem: &mut impl HeapMemory) -> Result<(), VMError> {
+        mem.grow(self.first_block());
+        mem.write_u64(self.base, 0)?;
+        mem.write_u64(self.base + 8, self.first_block())
+    }
+
+    // The header sits in plain memory, so a program can store anything over it
+    fn top(&self, mem: &impl HeapMemory) -> Result<u64, VMError> {
+        let top = mem.read_u64(self.base + 8)?;
+        match top.checked_sub(self.first_block()) {
+            Some(used) if used <= self.options.limit as u64 && top.is_multiple_of(8) => Ok(top),
+            _ => Err(fault(top, HeapFault::Corrupted)),
+        }
+    }
+
+    // Bytes from the first block to top
+    pub fn used(&self, mem: &impl HeapMemory) -> Result<u64, VMError> {
+        Ok(self.top(mem)? - self.first_block())
+    }
+
+    // Payload address of the block after the one at addr
+    fn next_block(addr: u64, size: u64) -> Result<u64, VMError> {
+        addr.checked_add(size)
+            .and_then(|end| end.checked_add(BLOCK_HEADER_SIZE))
+            .ok_or_else(|| fault(addr, HeapFault::Corrupted))
+    }
+
+    fn free_head(&self, mem: &impl HeapMemory) -> Result<u64, VMError> {
+        mem.read_u64(self.base)
+    }
+
+    // (payload size, allocated) of the block whose payload starts at addr
+    fn block(&self, mem: &impl HeapMemory, addr: u64) -> Result<(u64, bool), VMError> {
+        let header = mem.read_u64(addr - BLOCK_HEADER_SIZE)?;
+        Ok((header & !7, header & ALLOCATED != 0))
+    }
+
+    fn managed(&self, mem: &impl HeapMemory, addr: u64) -> Result<bool, VMError> {
+        Ok(mem.read_u64(addr - BLOCK_HEADER_SIZE)? & MANAGED != 0)
+    }
+
+    // Payload addresses of every block, walking the headers from the first one
+    fn blocks(&self, mem: &impl HeapMemory) -> Result<Vec<(u64, u64, bool)>, VMError> {
+        let top = self.top(mem)?;
+        let mut blocks = Vec::new();
+        let mut at = self.first_block();
+        while at < top {
+            let addr = at + BLOCK_HEADER_SIZE;
+            let (size, allocated) = self.block(mem, addr)?;
+            blocks.push((addr, size, allocated));
+            at = addr
+                .checked_add(size)
+                .ok_or_else(|| fault(addr, HeapFault::Corrupted))?;
+        }
+        Ok(blocks)
+    }
+
+    // Takes the block at addr off the free list, if it is on it
+    fn unlink(&self, mem: &mut impl HeapMemory, addr: u64) -> Result<(), VMError> {
+        let mut prev = None;
+        let mut cur = self.free_head(mem)?;
+        let mut steps = 0;
+        while cur != 0 {
+            let next = mem.read_u64(cur)?;
+            if cur == addr {
+                return match prev {
+                    Some(prev) => mem.write_u64(prev, next),
+                    None => mem.write_u64(self.base, next),
+                };
+            }
+            prev = Some(cur);
+            cur = next;
+
+            steps += 1;
+            if steps > self.options.limit {
+                return Err(fault(cur, HeapFault::Corrupted));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn alloc(&self, mem: &mut impl HeapMemory, size: u64) -> Result<u64, VMError> {
+        let size = align(size);
+        if size > self.options.limit as u64 {
+            return Err(VMError::OutOfMemory { requested: size });
+        }
+
+        // First fit from the free list, splitting off whatever is big enough to keep
+        let top = self.top(mem)?;
+        let mut cur = self.free_head(mem)?;
+        let mut steps = 0;
+        while cur != 0 {
+            if cur < self.first_block() + BLOCK_HEADER_SIZE || cur >= top {
+                return Err(fault(cur, HeapFault::Corrupted));
+            }
+            let (found, _) = self.block(mem, cur)?;
+            let next = mem.read_u64(cur)?;
+            if found >= size {
+                self.unlink(mem, cur)?;
+                if found - size >= MIN_SPLIT + BLOCK_HEADER_SIZE {
+                    let rest = Self::next_block(cur, size)?;
+                    mem.write_u64(rest - BLOCK_HEADER_SIZE, found - size - BLOCK_HEADER_SIZE)?;
+                    mem.write_u64(rest, self.free_head(mem)?)?;
+                    mem.write_u64(self.base, rest)?;
+                    mem.write_u64(cur - BLOCK_HEADER_SIZE, size | ALLOCATED)?;
+                } else {
+                    mem.write_u64(cur - BLOCK_HEADER_SIZE, found | ALLOCATED)?;
+                }
+                mem.fill(cur, size, 0)?;
+                return Ok(cur);
+            }
+            cur = next;
+
+            steps += 1;
+            if steps > self.options.limit {
+                return Err(fault(cur, HeapFault::Corrupted));
+            }
+        }
+
+        let addr = top + BLOCK_HEADER_SIZE;
+        let new_top = addr + size;
+        if new_top - self.first_block() > self.options.limit as u64 {
+            return Err(VMError::OutOfMemory { requested: size });
+        }
+        mem.grow(new_top);
+        mem.write_u64(top, size | ALLOCATED)?;
+        mem.fill(addr, size, 0)?;
+        mem.write_u64(self.base + 8, new_top)?;
+        Ok(addr)
+    }
+
+    fn check_allocated(&self, mem: &impl HeapMemory, addr: u64) -> Result<u64, VMError> {
+        let top = self.top(mem)?;
+        if addr < self.first_block() + BLOCK_HEADER_SIZE || addr >= top || !addr.is_multiple_of(8) {
+            return Err(fault(addr, HeapFault::InvalidFree));
+        }
+        if self.options.debug && !self.blocks(mem)?.iter().any(|(at, _, _)| *at == addr) {
+            return Err(fault(addr, HeapFault::InvalidFree));
+        }
+
+        match self.block(mem, addr)? {
+            (_, true) if self.managed(mem, addr)? => Err(fault(addr, HeapFault::InvalidFree)),
+            (size, true) => Ok(size),
+            (_, false) => Err(fault(addr, HeapFault::DoubleFree)),
+        }
+    }
+
+    pub fn free(&self, mem: &mut impl HeapMemory, addr: u64) -> Result<(), VMError> {
+        if addr == 0 {
+            return Ok(());
+        }
+        let size = self.check_allocated(mem, addr)?;
+        self.release(mem, addr, size)
+    }
+
+    fn release(&self, mem: &mut impl HeapMemory, addr: u64, mut size: u64) -> Result<(), VMError> {
+        // Freed blocks stay out of circulation, so later accesses can be caught
+        if self.options.debug {
+            mem.write_u64(addr - BLOCK_HEADER_SIZE, size)?;
+            return mem.fill(addr, size, POISON);
+        }
+
+        // Blocks stay below top once made, so a second free always finds the header
+        let top = self.top(mem)?;
+        let next = Self::next_block(addr, size)?;
+        if next < top {
+            if let (next_size, false) = self.block(mem, next)? {
+                self.unlink(mem, next)?;
+                size += BLOCK_HEADER_SIZE + next_size;
+            }
+        }
+
+        mem.write_u64(addr - BLOCK_HEADER_SIZE, size)?;
+        mem.write_u64(addr, self.free_head(mem)?)?;
+        mem.write_u64(self.base, addr)
+    }
+
+    pub fn realloc(&self, mem: &mut impl HeapMemory, addr: u64, size: u64) -> Result<u64, VMError> {
+        if addr == 0 {
+            return self.alloc(mem, size);
+        }
+        let old = self.check_allocated(mem, addr)?;
+        let size = align(size);
+        if size <= old {
+            return Ok(addr);
+        }
+
+        // The last block can grow in place
+        let top = self.top(mem)?;
+        if addr.checked_add(old) == Some(top) {
+            let new_top = addr + size;
+            if new_top - self.first_block() > self.options.limit as u64 {
+                return Err(VMError::OutOfMemory { requested: size });
+            }
+            mem.grow(new_top);
+            mem.write_u64(addr - BLOCK_HEADER_SIZE, size | ALLOCATED)?;
+            mem.fill(top, size - old, 0)?;
+            mem.write_u64(self.base + 8, new_top)?;
+            return Ok(addr);
+        }
+
+        let moved = self.alloc(mem, size)?;
+        mem.copy(addr, moved, old)?;
+        self.free(mem, addr)?;
+        Ok(moved)
+    }
+
+    // A block for the collector, which finds it again through objects
+    pub fn alloc_managed(&self, mem: &mut impl HeapMemory, size: u64) -> Result<u64, VMError> {
+        let addr = self.alloc(mem, size)?;
+        let (size, _) = self.block(mem, addr)?;
+        mem.write_u64(addr - BLOCK_HEADER_SIZE, size | ALLOCATED | MANAGED)?;
+        Ok(addr)
+    }
+
+    // Whether addr is the payload of a live managed block
+    pub fn is_managed(&self, mem: &impl HeapMemory, addr: u64) -> Result<bool, VMError> {
+        if addr < self.first_block() + BLOCK_HEADER_SIZE
+            || addr >= self.top(mem)?
+            || !addr.is_multiple_of(8)
+        {
+            return Ok(false);
+        }
+        if self.options.debug && !self.blocks(mem)?.iter().any(|(at, _, _)| *at == addr) {
+            return Ok(false);
+        }
+        Ok(self.block(mem, addr)?.1 && self.managed(mem, addr)?)
+    }
+
+    // (payload address, payload size) of every live managed block
+    pub fn objects(&self, mem: &impl HeapMemory) -> Result<Vec<(u64, u64)>, VMError> {
+        let mut objects = Vec::new();
+        for (addr, size, allocated) in self.blocks(mem)? {
+            if allocated && self.managed(mem, addr)? {
+                objects.push((addr, size));
+            }
+        }
+        Ok(objects)
+    }
+
+    // Frees managed blocks, highest first so each one merges with the next
+    pub fn release_managed(
+        &self,
+        mem: &mut impl HeapMemory,
+        mut blocks: Vec<(u64, u64)>,
+    ) -> Result<(), VMError> {
+        blocks.sort_unstable();
+        for (addr, size) in blocks.into_iter().rev() {
+            self.release(mem, addr, size)?;
+        }
+        Ok(())
+    }
+
+    // Only checked in debug mode, where freed blocks are never handed out again
+    pub fn check_access(&self, mem: &impl HeapMemory, addr: u64) -> Result<(), VMError> {
+        if !self.options.debug || addr < self.first_block() || addr >= self.top(mem)? {
+            return Ok(());
+        }
+
+        match self
+            .blocks(mem)?
+            .into_iter()
+            .find(|(at, size, _)| (*at - BLOCK_HEADER_SIZE..*at + size).contains(&addr))
+        {
+            Some((_, _, false)) => Err(fault(addr, HeapFault::UseAfterFree)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn stats(&self, mem: &impl HeapMemory) -> Result<HeapStats, VMError> {
+        let mut stats = HeapStats {
+            used: self.top(mem)? - self.first_block(),
+            limit: self.options.limit,
+            ..Default::default()
+        };
+        self.blocks(mem)?
+            .into_iter()
+            .for_each(|(_, size, allocated)| {
+                stats.blocks += 1;
+                if allocated {
+                    stats.allocated += size;
+                } else {
+                    stats.free += size;
+                    stats.free_blocks += 1;
+                }
+            });
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn heap(debug: bool) -> (Heap, Vec<u8>) {
+        let heap = Heap::new(
+            5,
+            HeapOptions {
+                debug,
+                ..Default::default()
+            },
+        );
+        let mut mem = vec![0; 5];
+        heap.init(&mut mem).unwrap();
+        (heap, mem)
+    }
+
+    fn faults(result: Result<impl std::fmt::Debug, VMError>) -> HeapFault {
+        match result {
+            Err(VMError::HeapFault { fault, .. }) => fault,
+            other => panic!("expected a heap fault, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn allocates_past_the_data_aligned() {
+        let (heap, mut mem) = heap(false);
+        let a = heap.alloc(&mut mem, 3).unwrap();
+        let b = heap.alloc(&mut mem, 8).unwrap();
+        assert_eq!(a, 8 + HEAP_HEADER_SIZE + BLOCK_HEADER_SIZE);
+        assert_eq!(b, a + 8 + BLOCK_HEADER_SIZE);
+        assert_eq!(heap.used(&mem).unwrap(), 2 * (8 + BLOCK_HEADER_SIZE));
+
+        mem.write_u64(a, u64::MAX).unwrap();
+        heap.free(&mut mem, a).unwrap();
+        // Reused first fit, and zeroed
+        assert_eq!(heap.alloc(&mut mem, 8).unwrap(), a);
+        assert_eq!(mem.read_u64(a).unwrap(), 0);
+    }
+
+    #[test]
+    fn splits_big_blocks_and_merges_with_the_next() {
+        let (heap, mut mem) = heap(false);
+        let big = heap.alloc(&mut mem, 64).unwrap();
+        let after = heap.alloc(&mut mem, 8).unwrap();
+        heap.free(&mut mem, big).unwrap();
+
+        let small = heap.alloc(&mut mem, 8).unwrap();
+        assert_eq!(small, big);
+        let stats = heap.stats(&mem).unwrap();
+        assert_eq!((stats.blocks, stats.free_blocks), (3, 1));
+        assert_eq!(stats.free, 64 - 8 - BLOCK_HEADER_SIZE);
+
+        // Freeing the front block merges the split off rest back into it
+        heap.free(&mut mem, small).unwrap();
+        let stats = heap.stats(&mem).unwrap();
+        assert_eq!((stats.blocks, stats.free_blocks, stats.free), (2, 1, 64));
+        assert_eq!(heap.alloc(&mut mem, 64).unwrap(), big);
+        assert_eq!(
+            heap.alloc(&mut mem, 8).unwrap(),
+            after + 8 + BLOCK_HEADER_SIZE
+        );
+    }
+
+    #[test]
+    fn realloc_grows_the_last_block_in_place_and_moves_the_rest() {
+        let (heap, mut mem) = heap(false);
+        let first = heap.alloc(&mut mem, 8).unwrap();
+        mem.write_u64(first, 42).unwrap();
+        let last = heap.alloc(&mut mem, 8).unwrap();
+
+        assert_eq!(heap.realloc(&mut mem, last, 32).unwrap(), last);
+        assert_eq!(heap.realloc(&mut mem, last, 16).unwrap(), last);
+
+        let moved = heap.realloc(&mut mem, first, 16).unwrap();
+        assert!(moved > last);
+        assert_eq!(mem.read_u64(moved).unwrap(), 42);
+        assert_eq!(faults(heap.free(&mut mem, first)), HeapFault::DoubleFree);
+        assert_eq!(heap.realloc(&mut mem, 0, 8).unwrap(), first);
+    }
+
+    #[test]
+    fn bad_frees_fault() {
+        let (heap, mut mem) = heap(false);
+        let addr = heap.alloc(&mut mem, 8).unwrap();
+        heap.free(&mut mem, 0).unwrap();
+        assert_eq!(
+            faults(heap.free(&mut mem, addr + 1)),
+            HeapFault::InvalidFree
+        );
+        assert_eq!(faults(heap.free(&mut mem, 8)), HeapFault::InvalidFree);
+        heap.free(&mut mem, addr).unwrap();
+        assert_eq!(faults(heap.free(&mut mem, addr)), HeapFault::DoubleFree);
+
+        let managed = heap.alloc_managed(&mut mem, 8).unwrap();
+        assert_eq!(faults(heap.free(&mut mem, managed)), HeapFault::InvalidFree);
+    }
+
+    #[test]
+    fn debug_heaps_catch_use_after_free() {
+        let (heap, mut mem) = heap(true);
+        let addr = heap.alloc(&mut mem, 16).unwrap();
+        heap.check_access(&mem, addr + 8).unwrap();
+        heap.free(&mut mem, addr).unwrap();
+
+        assert_eq!(
+            faults(heap.check_access(&mem, addr + 8)),
+            HeapFault::UseAfterFree
+        );
+        assert_eq!(mem.read(addr, 16).unwrap(), [POISON; 16]);
+        assert_ne!(heap.alloc(&mut mem, 16).unwrap(), addr);
+        assert_eq!(faults(heap.free(&mut mem, addr)), HeapFault::DoubleFree);
+    }
+
+    #[test]
+    fn overwritten_headers_fault_instead_of_overflowing() {
+        let (heap, mut mem) = heap(false);
+        let addr = heap.alloc(&mut mem, 8).unwrap();
+        mem.write_u64(addr - BLOCK_HEADER_SIZE, !MANAGED).unwrap();
+        assert_eq!(faults(heap.free(&mut mem, addr)), HeapFault::Corrupted);
+
+        for top in [0, 9, u64::MAX] {
+            let (heap, mut mem) = self::heap(false);
+            mem.write_u64(16, top).unwrap();
+            assert_eq!(faults(heap.used(&mem)), HeapFault::Corrupted);
+            assert_eq!(faults(heap.stats(&mem)), HeapFault::Corrupted);
+            assert_eq!(faults(heap.alloc(&mut mem, 8)), HeapFault::Corrupted);
+        }
+    }
+}
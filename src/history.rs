@@ -0,0 +1,288 @@
+use std::collections::VecDeque;
+
+use crate::{
+    fiber::{Fiber, Frame, Handler, Scheduler},
+    snapshot::Snapshot,
+    word::Word,
+};
+
+pub const HISTORY_CAPACITY: usize = 10_000;
+pub const CHECKPOINT_INTERVAL: u64 = 1_000;
+const CHECKPOINT_LIMIT: usize = 64;
+
+// What one step overwrote, enough to put the VM back where it was before it
+#[derive(Debug, Clone, Default)]
+pub struct Change {
+    pub ip: usize,
+    pub fuel: u64,
+    pub halt: bool,
+    pub exit_status: Option<i64>,
+    // Memory only grows during a step, undoing it truncates back to this
+    pub memory_size: usize,
+    pub stack_size: usize,
+    pub frame_count: usize,
+    pub locals_size: usize,
+    pub handler_count: usize,
+    // (slot, old value), in the order they were written
+    pub stack: Vec<(usize, Word)>,
+    pub frames: Vec<(usize, Frame)>,
+    pub locals: Vec<(usize, Word)>,
+    pub globals: Vec<(usize, Word)>,
+    pub handlers: Vec<(usize, Handler)>,
+    // (offset, old bytes), in the order they were written
+    pub memory: Vec<(usize, Vec<u8>)>,
+    // The running fiber and the scheduler as they were, for steps that may
+    // switch fibers
+    pub fibers: Option<Box<(Fiber, Scheduler)>>,
+}
+
+// Something a step got from outside the VM, handed back when the step runs again
+#[derive(Debug, Clone)]
+pub enum Input {
+    Line(Option<String>),
+    // The syscall result, and what it read into memory
+    Syscall { result: Word, data: Vec<u8> },
+}
+
+// Undo log for the most recent steps, plus sparse checkpoints further back.
+// Stepping back past the log restores a checkpoint and replays forward.
+#[derive(Debug)]
+pub struct History {
+    changes: VecDeque<Change>,
+    pending: Option<Change>,
+    // (steps taken when it was made, state at that point), oldest first
+    checkpoints: VecDeque<(u64, Snapshot)>,
+    // (step, input) for every console read and syscall, so a replay sees the
+    // same input and the outside world sees each effect once
+    reads: VecDeque<(u64, Input)>,
+    // Reads of undone steps, handed out again when they are redone
+    unread: VecDeque<Input>,
+    steps: u64,
+
+    capacity: usize,
+    interval: u64,
+}
+
+impl History {
+    pub fn new(capacity: usize, interval: u64) -> Self {
+        Self {
+            changes: VecDeque::new(),
+            pending: None,
+            checkpoints: VecDeque::new(),
+            reads: VecDeque::new(),
+            unread: VecDeque::new(),
+            steps: 0,
+
+            capacity: capacity.max(1),
+            interval: interval.max(1),
+        }
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    // Oldest step that can still be reached going backwards
+    pub fn earliest(&self) -> u64 {
+        let logged = self.steps - self.changes.len() as u64;
+        self.checkpoints
+            .front()
+            .map_or(logged, |(steps, _)| logged.min(*steps))
+    }
+
+    pub fn wants_checkpoint(&self) -> bool {
+        self.steps.is_multiple_of(self.interval)
+            && self
+                .checkpoints
+                .back()
+                .is_none_or(|(steps, _)| *steps < self.steps)
+    }
+
+    pub fn checkpoint(&mut self, snapshot: Snapshot) {
+        self.checkpoints.push_back((self.steps, snapshot));
+        if self.checkpoints.len() > CHECKPOINT_LIMIT {
+            self.checkpoints.pop_front();
+        }
+        if let Some((oldest, _)) = self.checkpoints.front() {
+            while self.reads.front().is_some_and(|(at, _)| at < oldest) {
+                self.reads.pop_front();
+            }
+        }
+    }
+
+    // Starts logging a step from the VM state it begins in, with nothing written yet
+    pub fn begin(&mut self, change: Change) {
+        self.pending = Some(change);
+    }
+
+    pub fn record_stack(&mut self, slot: usize, old: Word) {
+        if let Some(change) = self.pending.as_mut() {
+            change.stack.push((slot, old));
+        }
+    }
+
+    pub fn record_frame(&mut self, slot: usize, old: Frame) {
+        if let Some(change) = self.pending.as_mut() {
+            change.frames.push((slot, old));
+        }
+    }
+
+    pub fn record_local(&mut self, slot: usize, old: Word) {
+        if let Some(change) = self.pending.as_mut() {
+            change.locals.push((slot, old));
+        }
+    }
+
+    pub fn record_global(&mut self, slot: usize, old: Word) {
+        if let Some(change) = self.pending.as_mut() {
+            change.globals.push((slot, old));
+        }
+    }
+
+    pub fn record_handler(&mut self, slot: usize, old: Handler) {
+        if let Some(change) = self.pending.as_mut() {
+            change.handlers.push((slot, old));
+        }
+    }
+
+    pub fn record_memory(&mut self, at: usize, old: &[u8]) {
+        if let Some(change) = self.pending.as_mut() {
+            change.memory.push((at, old.to_vec()));
+        }
+    }
+
+    pub fn record_read(&mut self, input: Input) {
+        self.reads.push_back((self.steps, input));
+    }
+
+    // What a redone step read the first time, None when it is a new read
+    pub fn take_unread(&mut self) -> Option<Input> {
+        self.unread.pop_front()
+    }
+
+    pub fn commit(&mut self) {
+        if let Some(change) = self.pending.take() {
+            self.changes.push_back(change);
+            self.steps += 1;
+            if self.changes.len() > self.capacity {
+                self.changes.pop_front();
+            }
+        }
+    }
+
+    pub fn abort(&mut self) {
+        self.pending = None;
+        self.forget_after(self.steps);
+    }
+
+    pub fn pop(&mut self) -> Option<Change> {
+        let change = self.changes.pop_back()?;
+        self.steps -= 1;
+        self.forget_after(self.steps);
+        Some(change)
+    }
+
+    // Latest checkpoint at or before `steps`, dropping the undo log it replaces
+    pub fn rewind_to(&mut self, steps: u64) -> Option<(u64, Snapshot)> {
+        let (at, snapshot) = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|(at, _)| *at <= steps)?
+            .clone();
+
+        self.changes.clear();
+        self.pending = None;
+        self.steps = at;
+        self.forget_after(at);
+        Some((at, snapshot))
+    }
+
+    // Drops checkpoints made after `steps` steps, keeping what later steps read
+    // for when they run again
+    fn forget_after(&mut self, steps: u64) {
+        while self.checkpoints.back().is_some_and(|(at, _)| *at > steps) {
+            self.checkpoints.pop_back();
+        }
+        while self.reads.back().is_some_and(|(at, _)| *at >= steps) {
+            if let Some((_, input)) = self.reads.pop_back() {
+                self.unread.push_front(input);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        program::Program,
+        testing::{vm, vm_with},
+    };
+
+    // Touches the stack, memory, frames, locals, globals and tries
+    const HASM: &str = ".global total = 1
+        .space cell 8
+        push 3
+        call double
+        push cell
+        dup 1
+        store
+        gload total
+        addi
+        gstore total
+        try done
+        push 1
+        push 0
+        divi
+    done:
+        halt
+    double:
+        .local n
+        enter 1
+        lstore n
+        lload n
+        lload n
+        addi
+        leave
+        ret";
+
+    #[test]
+    fn stepping_back_undoes_every_step() {
+        // A short log, so going back far enough replays from a checkpoint
+        let mut vm = vm(HASM);
+        vm.record_history(4, 3);
+        let mut states = vec![vm.snapshot().to_bytes().unwrap()];
+        while !vm.halted() {
+            vm.step().unwrap();
+            states.push(vm.snapshot().to_bytes().unwrap());
+        }
+
+        states.pop();
+        while let Some(state) = states.pop() {
+            assert!(vm.step_back().unwrap());
+            assert_eq!(
+                vm.snapshot().to_bytes().unwrap(),
+                state,
+                "{} steps",
+                states.len()
+            );
+        }
+        assert!(!vm.step_back().unwrap());
+        assert_eq!(vm.history().unwrap().earliest(), 0);
+    }
+
+    #[test]
+    fn redone_steps_read_what_they_read_before() {
+        let program = Program::from_hasm("readi\nreadi\naddi\nhalt").unwrap();
+        let mut vm = vm_with(program, "40\n2\n");
+        vm.record_history(1, 2);
+        vm.run(Some(16)).unwrap();
+        assert_eq!(vm.history().unwrap().steps(), 4);
+
+        (0..4).for_each(|_| assert!(vm.step_back().unwrap()));
+        // The console has nothing left, so these can only come from the history
+        vm.run(Some(16)).unwrap();
+        assert_eq!(vm.stack().len(), 1);
+        assert_eq!(i64::from(vm.stack()[0]), 42);
+    }
+}
@@ -0,0 +1,424 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use clap::Args;
+
+use crate::VMError;
+
+// syscall n ABI: arguments are pushed in order, so the last one is on top,
+// and get popped; every call except exit pushes one i64 result.
+// Strings and buffers are (address, length) pairs in VM memory.
+//
+//   0  exit(status)                          halts with the status
+//   1  open(path, path_len, mode) -> fd      mode 0 read, 1 write, 2 append
+//   2  read(fd, buf, len) -> bytes read
+//   3  write(fd, buf, len) -> bytes written
+//   4  close(fd) -> 0
+//   5  clock() -> milliseconds since the Unix epoch
+//   6  getenv(name, name_len, buf, len) -> length of the value
+//
+// Failed calls push -1. Calls needing a capability the VM was not granted
+// fail with PermissionDenied instead, files need the fs root. fd 0 reads a
+// line from the console, 1 and 2 write to it.
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_OPEN: u64 = 1;
+pub const SYS_READ: u64 = 2;
+pub const SYS_WRITE: u64 = 3;
+pub const SYS_CLOSE: u64 = 4;
+pub const SYS_CLOCK: u64 = 5;
+pub const SYS_GETENV: u64 = 6;
+
+pub const FIRST_FILE_FD: u64 = 3;
+
+// (arguments popped, results pushed)
+pub fn syscall_arity(n: u64) -> Option<(usize, usize)> {
+    match n {
+        SYS_EXIT => Some((1, 0)),
+        SYS_OPEN => Some((3, 1)),
+        SYS_READ => Some((3, 1)),
+        SYS_WRITE => Some((3, 1)),
+        SYS_CLOSE => Some((1, 1)),
+        SYS_CLOCK => Some((0, 1)),
+        SYS_GETENV => Some((4, 1)),
+        _ => None,
+    }
+}
+
+pub fn syscall_name(n: u64) -> &'static str {
+    match n {
+        SYS_EXIT => "exit",
+        SYS_OPEN => "open",
+        SYS_READ => "read",
+        SYS_WRITE => "write",
+        SYS_CLOSE => "close",
+        SYS_CLOCK => "clock",
+        SYS_GETENV => "getenv",
+        _ => "unknown",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenMode {
+    Read,
+    Write,
+    Append,
+}
+
+impl OpenMode {
+    pub fn from_word(mode: u64) -> Option<Self> {
+        match mode {
+            0 => Some(OpenMode::Read),
+            1 => Some(OpenMode::Write),
+            2 => Some(OpenMode::Append),
+            _ => None,
+        }
+    }
+}
+
+// What the program may reach outside the VM, nothing unless granted
+#[derive(Args, Debug, Clone, Default)]
+pub struct Capabilities {
+    /// Let the program open files under this directory
+    #[arg(long = "allow-fs", value_name = "DIR")]
+    pub fs_root: Option<PathBuf>,
+
+    /// Let the program read the clock
+    #[arg(long = "allow-clock")]
+    pub clock: bool,
+
+    /// Let the program read environment variables
+    #[arg(long = "allow-env")]
+    pub env: bool,
+}
+
+impl Capabilities {
+    // Path under the fs root, refusing anything absolute, climbing out of it
+    // or leading out of it through a symlink
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, VMError> {
+        let denied = || VMError::PermissionDenied {
+            syscall: syscall_name(SYS_OPEN),
+        };
+        let root = self.fs_root.as_ref().ok_or_else(denied)?;
+        let root = root.canonicalize().map_err(|_| denied())?;
+
+        let path = Path::new(path);
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(denied());
+        }
+
+        // Only what already exists can be a symlink, so that part is followed
+        // to where it really is and the rest, plain names, goes on top of it
+        let joined = root.join(path);
+        let existing = joined
+            .ancestors()
+            .find(|at| at.symlink_metadata().is_ok())
+            .ok_or_else(denied)?;
+        let rest = joined.strip_prefix(existing).map_err(|_| denied())?;
+        let resolved = rest.components().fold(
+            existing.canonicalize().map_err(|_| denied())?,
+            |resolved, component| resolved.join(component),
+        );
+
+        if !resolved.starts_with(&root) {
+            return Err(denied());
+        }
+        Ok(resolved)
+    }
+}
+
+// The outside world as the syscalls see it, fds start at FIRST_FILE_FD
+pub trait Host: Debug + Send {
+    fn open(&mut self, path: &Path, mode: OpenMode) -> io::Result<u64>;
+    fn read(&mut self, fd: u64, buf: &mut [u8]) -> io::Result<usize>;
+    fn write(&mut self, fd: u64, buf: &[u8]) -> io::Result<usize>;
+    fn close(&mut self, fd: u64) -> io::Result<()>;
+    fn now(&self) -> Duration;
+    fn env(&self, name: &str) -> Option<String>;
+    fn open_files(&self) -> usize;
+}
+
+fn bad_fd() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "bad file descriptor")
+}
+
+#[derive(Debug, Default)]
+pub struct OsHost {
+    files: HashMap<u64, File>,
+    next_fd: u64,
+}
+
+impl Host for OsHost {
+    fn open(&mut self, path: &Path, mode: OpenMode) -> io::Result<u64> {
+        let file = match mode {
+            OpenMode::Read => File::open(path)?,
+            OpenMode::Write => File::create(path)?,
+            OpenMode::Append => OpenOptions::new().append(true).create(true).open(path)?,
+        };
+
+        let fd = self.next_fd.max(FIRST_FILE_FD);
+        self.next_fd = fd + 1;
+        self.files.insert(fd, file);
+        Ok(fd)
+    }
+
+    fn read(&mut self, fd: u64, buf: &mut [u8]) -> io::Result<usize> {
+        self.files.get_mut(&fd).ok_or_else(bad_fd)?.read(buf)
+    }
+
+    fn write(&mut self, fd: u64, buf: &[u8]) -> io::Result<usize> {
+        self.files.get_mut(&fd).ok_or_else(bad_fd)?.write(buf)
+    }
+
+    fn close(&mut self, fd: u64) -> io::Result<()> {
+        self.files.remove(&fd).map(|_| ()).ok_or_else(bad_fd)
+    }
+
+    fn now(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+
+    fn env(&self, name: &str) -> Option<String> {
+        std::env::var(name).ok()
+    }
+
+    fn open_files(&self) -> usize {
+        self.files.len()
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+struct OpenFile {
+    path: PathBuf,
+    at: usize,
+    mode: OpenMode,
+}
+
+// Files, clock and environment held in memory, for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct MemoryHost {
+    pub files: HashMap<PathBuf, Vec<u8>>,
+    pub env: HashMap<String, String>,
+    pub clock: Duration,
+    open: HashMap<u64, OpenFile>,
+    next_fd: u64,
+}
+
+#[cfg(test)]
+impl Host for MemoryHost {
+    fn open(&mut self, path: &Path, mode: OpenMode) -> io::Result<u64> {
+        let at = match mode {
+            OpenMode::Read if !self.files.contains_key(path) => {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+            OpenMode::Read => 0,
+            OpenMode::Write => {
+                self.files.insert(path.to_path_buf(), Vec::new());
+                0
+            }
+            OpenMode::Append => self.files.entry(path.to_path_buf()).or_default().len(),
+        };
+
+        let fd = self.next_fd.max(FIRST_FILE_FD);
+        self.next_fd = fd + 1;
+        self.open.insert(
+            fd,
+            OpenFile {
+                path: path.to_path_buf(),
+                at,
+                mode,
+            },
+        );
+        Ok(fd)
+    }
+
+    fn read(&mut self, fd: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let file = self.open.get_mut(&fd).ok_or_else(bad_fd)?;
+        let contents = self.files.get(&file.path).ok_or_else(bad_fd)?;
+        let rest = contents.get(file.at..).unwrap_or_default();
+        let n = rest.len().min(buf.len());
+        buf[..n].copy_from_slice(&rest[..n]);
+        file.at += n;
+        Ok(n)
+    }
+
+    fn write(&mut self, fd: u64, buf: &[u8]) -> io::Result<usize> {
+        let file = self.open.get_mut(&fd).ok_or_else(bad_fd)?;
+        if file.mode == OpenMode::Read {
+            return Err(bad_fd());
+        }
+        let contents = self.files.entry(file.path.clone()).or_default();
+        contents.truncate(file.at);
+        contents.extend(buf);
+        file.at += buf.len();
+        Ok(buf.len())
+    }
+
+    fn close(&mut self, fd: u64) -> io::Result<()> {
+        self.open.remove(&fd).map(|_| ()).ok_or_else(bad_fd)
+    }
+
+    fn now(&self) -> Duration {
+        self.clock
+    }
+
+    fn env(&self, name: &str) -> Option<String> {
+        self.env.get(name).cloned()
+    }
+
+    fn open_files(&self) -> usize {
+        self.open.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{finish, vm};
+    use std::{env, fs, process};
+
+    // A fresh directory to use as the fs root
+    fn sandbox(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("haesuk-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn granted(root: &Path) -> Capabilities {
+        Capabilities {
+            fs_root: Some(root.to_path_buf()),
+            clock: true,
+            env: true,
+        }
+    }
+
+    // What the program printed, or the error it stopped with
+    fn run(hasm: &str, capabilities: Capabilities) -> Result<Vec<u8>, VMError> {
+        let mut vm = vm(hasm);
+        vm.grant(capabilities);
+        vm.set_host(Box::new(MemoryHost {
+            env: HashMap::from([("NAME".to_string(), "haesuk".to_string())]),
+            clock: Duration::from_millis(1234),
+            ..Default::default()
+        }));
+        Ok(finish(vm)?.output)
+    }
+
+    const DATA: &str = ".data path \"f.txt\"
+        .data name \"NAME\"
+        .space buf 16
+        ";
+
+    #[test]
+    fn calls_without_a_grant_are_denied() {
+        let calls = [
+            "push path\npush 5\npush 0\nsyscall 1",
+            "push 3\npush buf\npush 16\nsyscall 2",
+            "push 3\npush buf\npush 16\nsyscall 3",
+            "push 3\nsyscall 4",
+            "syscall 5",
+            "push name\npush 4\npush buf\npush 16\nsyscall 6",
+        ];
+        for call in calls {
+            let err = run(&format!("{}{}\nhalt", DATA, call), Capabilities::default());
+            assert!(
+                matches!(err, Err(VMError::PermissionDenied { .. })),
+                "{}: {:?}",
+                call,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn paths_outside_the_root_are_denied() {
+        let root = sandbox("outside");
+        let capabilities = granted(&root);
+        ["../f.txt", "a/../../f.txt", "/etc/passwd"]
+            .into_iter()
+            .for_each(|path| assert!(capabilities.resolve(path).is_err(), "{}", path));
+
+        let root = root.canonicalize().unwrap();
+        assert_eq!(
+            capabilities.resolve("./a/f.txt").unwrap(),
+            root.join("a/f.txt")
+        );
+        fs::write(root.join("f.txt"), "").unwrap();
+        assert_eq!(capabilities.resolve("f.txt").unwrap(), root.join("f.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_out_of_the_root_are_denied() {
+        let root = sandbox("symlinks");
+        let capabilities = granted(&root);
+        std::os::unix::fs::symlink(env::temp_dir(), root.join("out")).unwrap();
+        std::os::unix::fs::symlink(env::temp_dir().join("gone"), root.join("dangling")).unwrap();
+        std::os::unix::fs::symlink(".", root.join("here")).unwrap();
+
+        assert!(capabilities.resolve("out/f.txt").is_err());
+        assert!(capabilities.resolve("dangling").is_err());
+        assert!(capabilities.resolve("here/f.txt").is_ok());
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let root = sandbox("round-trip");
+        let hasm = format!(
+            "{}.data text \"hello\"
+            push path
+            push 5
+            push 1
+            syscall 1
+            dup 0
+            push text
+            push 5
+            syscall 3
+            printi
+            syscall 4
+            printi
+            push path
+            push 5
+            push 0
+            syscall 1
+            dup 0
+            push buf
+            push 16
+            syscall 2
+            printi
+            push 1
+            push buf
+            push 5
+            syscall 3
+            printi
+            syscall 4
+            printi
+            syscall 5
+            printi
+            push name
+            push 4
+            push buf
+            push 16
+            syscall 6
+            printi
+            halt",
+            DATA
+        );
+        assert_eq!(run(&hasm, granted(&root)).unwrap(), b"505hello5012346");
+        // The files only ever existed in the host
+        assert!(!root.join("f.txt").exists());
+    }
+}
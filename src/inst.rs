@@ -5,12 +5,15 @@ use strum_macros::{AsRefStr, Display, EnumString};
 
 use crate::{
     bimap::Bimap,
+    dehasm::hasm_with_operand,
+    host::{syscall_arity, SYS_EXIT},
     program::{TranslationContext, DEFERRED_OPERANDS_CAPACITY},
     word::Word,
     VMError,
 };
 
 #[derive(Debug, Clone, Display, PartialEq, AsRefStr, EnumString)]
+#[allow(clippy::enum_variant_names)]
 pub enum Inst {
     InstPush(Word),
     InstAddi,
@@ -23,11 +26,70 @@ pub enum Inst {
     InstMulf,
     InstDivf,
 
-    InstHalt,
+    InstHalt(Word),
     InstJmp(Word),
     InstEq(Word),
     InstDup(Word),
     InstNop,
+
+    InstPrinti,
+    InstPrintu,
+    InstPrintf,
+    InstPrintc,
+    InstPrints,
+    InstReadi,
+    InstReadln,
+
+    InstSyscall(Word),
+
+    InstAlloc,
+    InstFree,
+    InstRealloc,
+    InstLoad,
+    InstLoadf,
+    InstStore,
+    InstLoadb,
+    InstStoreb,
+
+    InstNew,
+    InstNewbytes,
+    InstGetfield,
+    InstSetfield,
+    InstGc,
+
+    InstPushs(Word),
+    InstConcat,
+    InstStrlen,
+    InstSubstr,
+    InstStrcmp,
+    InstCharat,
+    InstItos,
+    InstFtos,
+    InstStoi,
+    InstStof,
+
+    InstPushk(Word),
+
+    InstCall(Word),
+    InstRet,
+    InstEnter(Word),
+    InstLeave,
+    InstLload(Word),
+    InstLstore(Word),
+
+    InstGload(Word),
+    InstGstore(Word),
+
+    InstTry(Word),
+    InstEndtry,
+    InstThrow,
+
+    InstSpawn(Word),
+    InstYield,
+    InstJoin,
+    InstChan,
+    InstSend,
+    InstRecv,
 }
 
 lazy_static! {
@@ -42,11 +104,60 @@ lazy_static! {
         bimap.insert(Inst::InstSubf.as_ref(), "subf");
         bimap.insert(Inst::InstMulf.as_ref(), "mulf");
         bimap.insert(Inst::InstDivf.as_ref(), "divf");
-        bimap.insert(Inst::InstHalt.as_ref(), "halt");
+        bimap.insert(Inst::InstHalt(Word::i64(0)).as_ref(), "halt");
         bimap.insert(Inst::InstJmp(Word::u64(0)).as_ref(), "jmp");
         bimap.insert(Inst::InstEq(Word::u64(0)).as_ref(), "eq");
         bimap.insert(Inst::InstDup(Word::u64(0)).as_ref(), "dup");
         bimap.insert(Inst::InstNop.as_ref(), "nop");
+        bimap.insert(Inst::InstPrinti.as_ref(), "printi");
+        bimap.insert(Inst::InstPrintu.as_ref(), "printu");
+        bimap.insert(Inst::InstPrintf.as_ref(), "printf");
+        bimap.insert(Inst::InstPrintc.as_ref(), "printc");
+        bimap.insert(Inst::InstPrints.as_ref(), "prints");
+        bimap.insert(Inst::InstReadi.as_ref(), "readi");
+        bimap.insert(Inst::InstReadln.as_ref(), "readln");
+        bimap.insert(Inst::InstSyscall(Word::u64(0)).as_ref(), "syscall");
+        bimap.insert(Inst::InstAlloc.as_ref(), "alloc");
+        bimap.insert(Inst::InstFree.as_ref(), "free");
+        bimap.insert(Inst::InstRealloc.as_ref(), "realloc");
+        bimap.insert(Inst::InstLoad.as_ref(), "load");
+        bimap.insert(Inst::InstLoadf.as_ref(), "loadf");
+        bimap.insert(Inst::InstStore.as_ref(), "store");
+        bimap.insert(Inst::InstLoadb.as_ref(), "loadb");
+        bimap.insert(Inst::InstStoreb.as_ref(), "storeb");
+        bimap.insert(Inst::InstNew.as_ref(), "new");
+        bimap.insert(Inst::InstNewbytes.as_ref(), "newbytes");
+        bimap.insert(Inst::InstGetfield.as_ref(), "getfield");
+        bimap.insert(Inst::InstSetfield.as_ref(), "setfield");
+        bimap.insert(Inst::InstGc.as_ref(), "gc");
+        bimap.insert(Inst::InstPushs(Word::u64(0)).as_ref(), "pushs");
+        bimap.insert(Inst::InstConcat.as_ref(), "concat");
+        bimap.insert(Inst::InstStrlen.as_ref(), "strlen");
+        bimap.insert(Inst::InstSubstr.as_ref(), "substr");
+        bimap.insert(Inst::InstStrcmp.as_ref(), "strcmp");
+        bimap.insert(Inst::InstCharat.as_ref(), "charat");
+        bimap.insert(Inst::InstItos.as_ref(), "itos");
+        bimap.insert(Inst::InstFtos.as_ref(), "ftos");
+        bimap.insert(Inst::InstStoi.as_ref(), "stoi");
+        bimap.insert(Inst::InstStof.as_ref(), "stof");
+        bimap.insert(Inst::InstPushk(Word::u64(0)).as_ref(), "pushk");
+        bimap.insert(Inst::InstCall(Word::u64(0)).as_ref(), "call");
+        bimap.insert(Inst::InstRet.as_ref(), "ret");
+        bimap.insert(Inst::InstEnter(Word::u64(0)).as_ref(), "enter");
+        bimap.insert(Inst::InstLeave.as_ref(), "leave");
+        bimap.insert(Inst::InstLload(Word::u64(0)).as_ref(), "lload");
+        bimap.insert(Inst::InstLstore(Word::u64(0)).as_ref(), "lstore");
+        bimap.insert(Inst::InstGload(Word::u64(0)).as_ref(), "gload");
+        bimap.insert(Inst::InstGstore(Word::u64(0)).as_ref(), "gstore");
+        bimap.insert(Inst::InstTry(Word::u64(0)).as_ref(), "try");
+        bimap.insert(Inst::InstEndtry.as_ref(), "endtry");
+        bimap.insert(Inst::InstThrow.as_ref(), "throw");
+        bimap.insert(Inst::InstSpawn(Word::u64(0)).as_ref(), "spawn");
+        bimap.insert(Inst::InstYield.as_ref(), "yield");
+        bimap.insert(Inst::InstJoin.as_ref(), "join");
+        bimap.insert(Inst::InstChan.as_ref(), "chan");
+        bimap.insert(Inst::InstSend.as_ref(), "send");
+        bimap.insert(Inst::InstRecv.as_ref(), "recv");
         bimap
     };
 }
@@ -58,6 +169,17 @@ lazy_static! {
         map.insert(Inst::InstJmp(Word::u64(0)).as_ref(), true);
         map.insert(Inst::InstEq(Word::u64(0)).as_ref(), true);
         map.insert(Inst::InstDup(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstSyscall(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstPushs(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstPushk(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstCall(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstEnter(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstLload(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstLstore(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstGload(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstGstore(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstTry(Word::u64(0)).as_ref(), true);
+        map.insert(Inst::InstSpawn(Word::u64(0)).as_ref(), true);
 
         map
     };
@@ -67,16 +189,159 @@ impl Inst {
     pub fn translate(&self) -> &str {
         ""
     }
-    pub fn ser_opcode(&self) -> u8 {
+
+    // (slots required on the stack, net change of the stack size)
+    pub fn stack_effect(&self) -> (usize, isize) {
+        match self {
+            Inst::InstPush(_) => (0, 1),
+
+            Inst::InstAddi
+            | Inst::InstSubi
+            | Inst::InstMuli
+            | Inst::InstDivi
+            | Inst::InstAddf
+            | Inst::InstSubf
+            | Inst::InstMulf
+            | Inst::InstDivf => (2, -1),
+
+            Inst::InstHalt(_) => (0, 0),
+            Inst::InstJmp(_) => (0, 0),
+            Inst::InstEq(_) => (1, 1),
+            Inst::InstDup(operand) => ((u64::from(*operand) as usize).saturating_add(1), 1),
+            Inst::InstNop => (0, 0),
+
+            Inst::InstPrinti
+            | Inst::InstPrintu
+            | Inst::InstPrintf
+            | Inst::InstPrintc
+            | Inst::InstPrints => (1, -1),
+            Inst::InstReadi => (0, 1),
+            Inst::InstReadln => (2, -1),
+            Inst::InstSyscall(operand) => match syscall_arity(u64::from(*operand)) {
+                Some((args, results)) => (args, results as isize - args as isize),
+                None => (0, 0),
+            },
+
+            Inst::InstAlloc => (1, 0),
+            Inst::InstFree => (1, -1),
+            Inst::InstRealloc => (2, -1),
+            Inst::InstLoad | Inst::InstLoadf | Inst::InstLoadb => (1, 0),
+            Inst::InstStore | Inst::InstStoreb => (2, -2),
+
+            Inst::InstNew | Inst::InstNewbytes => (1, 0),
+            Inst::InstGetfield => (2, -1),
+            Inst::InstSetfield => (3, -3),
+            Inst::InstGc => (0, 0),
+
+            Inst::InstPushs(_) => (0, 1),
+            Inst::InstConcat | Inst::InstStrcmp | Inst::InstCharat => (2, -1),
+            Inst::InstSubstr => (3, -2),
+            Inst::InstStrlen
+            | Inst::InstItos
+            | Inst::InstFtos
+            | Inst::InstStoi
+            | Inst::InstStof => (1, 0),
+
+            Inst::InstPushk(_) => (0, 1),
+
+            // Arguments and results stay on the stack, what the callee does
+            // with them is worked out by call_effects
+            Inst::InstCall(_) | Inst::InstRet => (0, 0),
+            Inst::InstEnter(_) | Inst::InstLeave => (0, 0),
+            Inst::InstLload(_) => (0, 1),
+            Inst::InstLstore(_) => (1, -1),
+
+            Inst::InstGload(_) => (0, 1),
+            Inst::InstGstore(_) => (1, -1),
+
+            // The handler is entered with the error code pushed on top
+            Inst::InstTry(_) | Inst::InstEndtry => (0, 0),
+            Inst::InstThrow => (1, -1),
+
+            // spawn moves its argument to the new fiber and pushes its id,
+            // join swaps a fiber id for its result, recv a channel id for a word
+            Inst::InstSpawn(_) => (1, 0),
+            Inst::InstYield => (0, 0),
+            Inst::InstJoin | Inst::InstRecv => (1, 0),
+            Inst::InstChan => (0, 1),
+            Inst::InstSend => (2, -2),
+        }
+    }
+
+    pub fn successors(&self, ip: usize) -> Vec<usize> {
+        match self {
+            Inst::InstHalt(_) => vec![],
+            Inst::InstJmp(operand) => vec![u64::from(*operand) as usize],
+            // The callee, then wherever its ret comes back to
+            Inst::InstCall(operand) => vec![u64::from(*operand) as usize, ip + 1],
+            Inst::InstRet => vec![],
+            Inst::InstTry(operand) => vec![ip + 1, u64::from(*operand) as usize],
+            Inst::InstThrow => vec![],
+            Inst::InstSpawn(operand) => vec![ip + 1, u64::from(*operand) as usize],
+            Inst::InstSyscall(operand) if u64::from(*operand) == SYS_EXIT => vec![],
+            _ => vec![ip + 1],
+        }
+    }
+
+    // Whether it may switch fibers or touch what they share
+    pub fn schedules(&self) -> bool {
+        matches!(
+            self,
+            Inst::InstHalt(_)
+                | Inst::InstSpawn(_)
+                | Inst::InstYield
+                | Inst::InstJoin
+                | Inst::InstChan
+                | Inst::InstSend
+                | Inst::InstRecv
+        )
+    }
+
+    // Where a jmp, call, try or spawn sends execution
+    pub fn target(&self) -> Option<usize> {
+        match self {
+            Inst::InstJmp(target)
+            | Inst::InstCall(target)
+            | Inst::InstTry(target)
+            | Inst::InstSpawn(target) => Some(u64::from(*target) as usize),
+            _ => None,
+        }
+    }
+
+    // The same instruction sent elsewhere, anything without a target as it is
+    pub fn with_target(self, target: usize) -> Self {
+        let target = Word::u64(target as u64);
+        match self {
+            Inst::InstJmp(_) => Inst::InstJmp(target),
+            Inst::InstCall(_) => Inst::InstCall(target),
+            Inst::InstTry(_) => Inst::InstTry(target),
+            Inst::InstSpawn(_) => Inst::InstSpawn(target),
+            inst => inst,
+        }
+    }
+
+    // Stack depth on arrival at target from ip. A try handler finds an error
+    // code pushed, a spawned fiber starts out with just its argument.
+    pub fn successor_depth(&self, ip: usize, target: usize, depth: i64) -> i64 {
         match self {
+            Inst::InstTry(handler)
+                if u64::from(*handler) as usize == target && target != ip + 1 =>
+            {
+                depth + 1
+            }
+            Inst::InstSpawn(entry) if u64::from(*entry) as usize == target && target != ip + 1 => 1,
+            _ => depth,
+        }
+    }
+
+    // A push of a ptr has no encoding, references only exist at run time
+    pub fn ser_opcode(&self) -> Result<u8, VMError> {
+        Ok(match self {
             Inst::InstPush(word) => match word {
                 Word::i64(_) => 0xF1,
                 Word::u64(_) => 0xF2,
                 Word::f64(_) => 0xF3,
-                _ => {
-                    println!("Unsupported word type for inst push, word: {}", word);
-                    exit(15)
-                }
+                Word::ptr(_) => return Err(VMError::InvalidOperand),
             },
 
             Inst::InstAddi => 0x02,
@@ -89,12 +354,70 @@ impl Inst {
             Inst::InstMulf => 0x08,
             Inst::InstDivf => 0x09,
 
-            Inst::InstHalt => 0x0A,
+            Inst::InstHalt(_) => 0x0A,
             Inst::InstJmp(_) => 0x0B,
             Inst::InstEq(_) => 0x0C,
             Inst::InstDup(_) => 0x0D,
             Inst::InstNop => 0x0E,
-        }
+
+            Inst::InstPrinti => 0x10,
+            Inst::InstPrintu => 0x11,
+            Inst::InstPrintf => 0x12,
+            Inst::InstPrintc => 0x13,
+            Inst::InstPrints => 0x14,
+            Inst::InstReadi => 0x15,
+            Inst::InstReadln => 0x16,
+            Inst::InstSyscall(_) => 0x17,
+
+            Inst::InstAlloc => 0x18,
+            Inst::InstFree => 0x19,
+            Inst::InstRealloc => 0x1A,
+            Inst::InstLoad => 0x1B,
+            Inst::InstLoadf => 0x1C,
+            Inst::InstStore => 0x1D,
+            Inst::InstLoadb => 0x1E,
+            Inst::InstStoreb => 0x1F,
+
+            Inst::InstNew => 0x20,
+            Inst::InstNewbytes => 0x21,
+            Inst::InstGetfield => 0x22,
+            Inst::InstSetfield => 0x23,
+            Inst::InstGc => 0x24,
+
+            Inst::InstPushs(_) => 0x25,
+            Inst::InstConcat => 0x26,
+            Inst::InstStrlen => 0x27,
+            Inst::InstSubstr => 0x28,
+            Inst::InstStrcmp => 0x29,
+            Inst::InstCharat => 0x2A,
+            Inst::InstItos => 0x2B,
+            Inst::InstFtos => 0x2C,
+            Inst::InstStoi => 0x2D,
+            Inst::InstStof => 0x2E,
+
+            Inst::InstPushk(_) => 0x2F,
+
+            Inst::InstCall(_) => 0x30,
+            Inst::InstRet => 0x31,
+            Inst::InstEnter(_) => 0x32,
+            Inst::InstLeave => 0x33,
+            Inst::InstLload(_) => 0x34,
+            Inst::InstLstore(_) => 0x35,
+
+            Inst::InstGload(_) => 0x36,
+            Inst::InstGstore(_) => 0x37,
+
+            Inst::InstTry(_) => 0x38,
+            Inst::InstEndtry => 0x39,
+            Inst::InstThrow => 0x3A,
+
+            Inst::InstSpawn(_) => 0x3B,
+            Inst::InstYield => 0x3C,
+            Inst::InstJoin => 0x3D,
+            Inst::InstChan => 0x3E,
+            Inst::InstSend => 0x3F,
+            Inst::InstRecv => 0x40,
+        })
     }
 
     pub fn deser_opcode(opcode: u8) -> Option<Self> {
@@ -113,32 +436,94 @@ impl Inst {
             0x08 => Some(Inst::InstMulf),
             0x09 => Some(Inst::InstDivf),
 
-            0x0A => Some(Inst::InstHalt),
+            0x0A => Some(Inst::InstHalt(Word::i64(0))),
             0x0B => Some(Inst::InstJmp(Word::u64(0))),
             0x0C => Some(Inst::InstEq(Word::u64(0))),
             0x0D => Some(Inst::InstDup(Word::u64(0))),
             0x0E => Some(Inst::InstNop),
+
+            0x10 => Some(Inst::InstPrinti),
+            0x11 => Some(Inst::InstPrintu),
+            0x12 => Some(Inst::InstPrintf),
+            0x13 => Some(Inst::InstPrintc),
+            0x14 => Some(Inst::InstPrints),
+            0x15 => Some(Inst::InstReadi),
+            0x16 => Some(Inst::InstReadln),
+            0x17 => Some(Inst::InstSyscall(Word::u64(0))),
+
+            0x18 => Some(Inst::InstAlloc),
+            0x19 => Some(Inst::InstFree),
+            0x1A => Some(Inst::InstRealloc),
+            0x1B => Some(Inst::InstLoad),
+            0x1C => Some(Inst::InstLoadf),
+            0x1D => Some(Inst::InstStore),
+            0x1E => Some(Inst::InstLoadb),
+            0x1F => Some(Inst::InstStoreb),
+
+            0x20 => Some(Inst::InstNew),
+            0x21 => Some(Inst::InstNewbytes),
+            0x22 => Some(Inst::InstGetfield),
+            0x23 => Some(Inst::InstSetfield),
+            0x24 => Some(Inst::InstGc),
+
+            0x25 => Some(Inst::InstPushs(Word::u64(0))),
+            0x26 => Some(Inst::InstConcat),
+            0x27 => Some(Inst::InstStrlen),
+            0x28 => Some(Inst::InstSubstr),
+            0x29 => Some(Inst::InstStrcmp),
+            0x2A => Some(Inst::InstCharat),
+            0x2B => Some(Inst::InstItos),
+            0x2C => Some(Inst::InstFtos),
+            0x2D => Some(Inst::InstStoi),
+            0x2E => Some(Inst::InstStof),
+
+            0x2F => Some(Inst::InstPushk(Word::u64(0))),
+
+            0x30 => Some(Inst::InstCall(Word::u64(0))),
+            0x31 => Some(Inst::InstRet),
+            0x32 => Some(Inst::InstEnter(Word::u64(0))),
+            0x33 => Some(Inst::InstLeave),
+            0x34 => Some(Inst::InstLload(Word::u64(0))),
+            0x35 => Some(Inst::InstLstore(Word::u64(0))),
+
+            0x36 => Some(Inst::InstGload(Word::u64(0))),
+            0x37 => Some(Inst::InstGstore(Word::u64(0))),
+
+            0x38 => Some(Inst::InstTry(Word::u64(0))),
+            0x39 => Some(Inst::InstEndtry),
+            0x3A => Some(Inst::InstThrow),
+
+            0x3B => Some(Inst::InstSpawn(Word::u64(0))),
+            0x3C => Some(Inst::InstYield),
+            0x3D => Some(Inst::InstJoin),
+            0x3E => Some(Inst::InstChan),
+            0x3F => Some(Inst::InstSend),
+            0x40 => Some(Inst::InstRecv),
             _ => None,
         }
     }
 
-    pub fn serialize<'a>(&self, bytes: &'a mut [u8; 16]) -> &'a [u8; 16] {
-        bytes[0..16].copy_from_slice(&(self.ser_opcode() as u128).to_le_bytes());
+    pub fn serialize<'a>(&self, bytes: &'a mut [u8; 16]) -> Result<&'a [u8; 16], VMError> {
+        bytes[0..16].copy_from_slice(&(self.ser_opcode()? as u128).to_le_bytes());
 
-        bytes
+        Ok(bytes)
     }
 
-    pub fn serialize_operand<'a>(&self, bytes: &'a mut [u8; 16], operand: &Word) -> &'a [u8; 16] {
-        bytes[0..8].copy_from_slice(&(self.ser_opcode() as usize).to_le_bytes());
+    pub fn serialize_operand<'a>(
+        &self,
+        bytes: &'a mut [u8; 16],
+        operand: &Word,
+    ) -> Result<&'a [u8; 16], VMError> {
+        bytes[0..8].copy_from_slice(&(self.ser_opcode()? as usize).to_le_bytes());
         bytes[8..16].copy_from_slice(&operand.to_le_bytes());
 
-        bytes
+        Ok(bytes)
     }
 
-    pub fn to_bytes(&self) -> [u8; 16] {
+    pub fn to_bytes(&self) -> Result<[u8; 16], VMError> {
         let mut bytes = [0u8; 16];
-        match self {
-            Inst::InstPush(operand) => *self.serialize_operand(&mut bytes, operand),
+        Ok(match self {
+            Inst::InstPush(operand) => *self.serialize_operand(&mut bytes, operand)?,
 
             Inst::InstAddi
             | Inst::InstSubi
@@ -147,14 +532,70 @@ impl Inst {
             | Inst::InstAddf
             | Inst::InstSubf
             | Inst::InstMulf
-            | Inst::InstDivf => *self.serialize(&mut bytes),
+            | Inst::InstDivf => *self.serialize(&mut bytes)?,
+
+            Inst::InstHalt(operand) => *self.serialize_operand(&mut bytes, operand)?,
+            Inst::InstJmp(operand) => *self.serialize_operand(&mut bytes, operand)?,
+            Inst::InstEq(operand) => *self.serialize_operand(&mut bytes, operand)?,
+            Inst::InstDup(operand) => *self.serialize_operand(&mut bytes, operand)?,
+            Inst::InstNop => *self.serialize(&mut bytes)?,
+
+            Inst::InstPrinti
+            | Inst::InstPrintu
+            | Inst::InstPrintf
+            | Inst::InstPrintc
+            | Inst::InstPrints
+            | Inst::InstReadi
+            | Inst::InstReadln => *self.serialize(&mut bytes)?,
+
+            Inst::InstSyscall(operand) => *self.serialize_operand(&mut bytes, operand)?,
+
+            Inst::InstAlloc
+            | Inst::InstFree
+            | Inst::InstRealloc
+            | Inst::InstLoad
+            | Inst::InstLoadf
+            | Inst::InstStore
+            | Inst::InstLoadb
+            | Inst::InstStoreb => *self.serialize(&mut bytes)?,
+
+            Inst::InstNew
+            | Inst::InstNewbytes
+            | Inst::InstGetfield
+            | Inst::InstSetfield
+            | Inst::InstGc => *self.serialize(&mut bytes)?,
+
+            Inst::InstPushs(operand) => *self.serialize_operand(&mut bytes, operand)?,
+            Inst::InstConcat
+            | Inst::InstStrlen
+            | Inst::InstSubstr
+            | Inst::InstStrcmp
+            | Inst::InstCharat
+            | Inst::InstItos
+            | Inst::InstFtos
+            | Inst::InstStoi
+            | Inst::InstStof => *self.serialize(&mut bytes)?,
+
+            Inst::InstPushk(operand) => *self.serialize_operand(&mut bytes, operand)?,
+
+            Inst::InstCall(operand)
+            | Inst::InstEnter(operand)
+            | Inst::InstLload(operand)
+            | Inst::InstLstore(operand) => *self.serialize_operand(&mut bytes, operand)?,
+            Inst::InstRet | Inst::InstLeave => *self.serialize(&mut bytes)?,
+
+            Inst::InstGload(operand) | Inst::InstGstore(operand) => {
+                *self.serialize_operand(&mut bytes, operand)?
+            }
 
-            Inst::InstHalt => *self.serialize(&mut bytes),
-            Inst::InstJmp(operand) => *self.serialize_operand(&mut bytes, operand),
-            Inst::InstEq(operand) => *self.serialize_operand(&mut bytes, operand),
-            Inst::InstDup(operand) => *self.serialize_operand(&mut bytes, operand),
-            Inst::InstNop => *self.serialize(&mut bytes),
-        }
+            Inst::InstTry(operand) => *self.serialize_operand(&mut bytes, operand)?,
+            Inst::InstEndtry | Inst::InstThrow => *self.serialize(&mut bytes)?,
+
+            Inst::InstSpawn(operand) => *self.serialize_operand(&mut bytes, operand)?,
+            Inst::InstYield | Inst::InstJoin | Inst::InstChan | Inst::InstSend | Inst::InstRecv => {
+                *self.serialize(&mut bytes)?
+            }
+        })
     }
 
     pub fn with_operand_word(
@@ -162,52 +603,76 @@ impl Inst {
         maybe_operand_str: Option<&str>,
         tc: &mut TranslationContext,
         program_size_t: &mut u16,
-    ) -> Self {
-        let operand_str = maybe_operand_str.unwrap();
+    ) -> Option<Self> {
+        let operand_str = maybe_operand_str?;
 
-        match self {
+        let inst = match self {
             Inst::InstPush(_) => {
-                let operand_word = if operand_str.contains(".") {
-                    println!("Trying to parse operand str, {}", operand_str);
-                    Word::f64(operand_str.parse::<f64>().unwrap())
-                } else {
-                    operand_str
-                        .parse::<i64>()
-                        .map(|n| Word::i64(n))
-                        .or_else(|_| operand_str.parse::<u64>().map(|n| Word::u64(n)))
-                        .unwrap()
+                let operand_word = match tc.data_symbols.get(operand_str) {
+                    Some(offset) => Word::u64(*offset),
+                    None => Word::parse_literal(operand_str)?,
                 };
 
-                println!("parsed operand {:#?}", operand_word);
-
                 Inst::InstPush(operand_word)
             }
-            Inst::InstJmp(_) => {
-                if (operand_str).chars().next().unwrap().is_numeric() {
-                    return Inst::InstJmp((operand_str).parse::<u64>().unwrap().into());
+            Inst::InstJmp(_) | Inst::InstCall(_) | Inst::InstTry(_) | Inst::InstSpawn(_) => {
+                let target = if (operand_str).chars().next()?.is_numeric() {
+                    (operand_str).parse::<u64>().ok()?.into()
                 } else {
                     assert!(tc.deferred_operands.cache_size + 1 < DEFERRED_OPERANDS_CAPACITY);
                     tc.deferred_operands
                         .hash_map
                         .insert(*program_size_t, (operand_str).to_string());
                     tc.deferred_operands.cache_size += 1;
-                    return Inst::InstJmp(Word::u64(0));
+                    Word::u64(0)
+                };
+                match self {
+                    Inst::InstCall(_) => Inst::InstCall(target),
+                    Inst::InstTry(_) => Inst::InstTry(target),
+                    Inst::InstSpawn(_) => Inst::InstSpawn(target),
+                    _ => Inst::InstJmp(target),
+                }
+            }
+            Inst::InstEq(_) => Inst::InstEq(Word::u64(operand_str.parse::<u64>().ok()?)),
+            Inst::InstDup(_) => Inst::InstDup(Word::u64(operand_str.parse::<u64>().ok()?)),
+            Inst::InstSyscall(_) => Inst::InstSyscall(Word::u64(operand_str.parse::<u64>().ok()?)),
+            Inst::InstPushs(_) => Inst::InstPushs(Word::u64(operand_str.parse::<u64>().ok()?)),
+            Inst::InstPushk(_) => Inst::InstPushk(Word::u64(operand_str.parse::<u64>().ok()?)),
+            Inst::InstEnter(_) => Inst::InstEnter(Word::u64(operand_str.parse::<u64>().ok()?)),
+            Inst::InstLload(_) | Inst::InstLstore(_) => {
+                let index = match tc.locals.get(operand_str) {
+                    Some(index) => *index,
+                    None => operand_str.parse::<u64>().ok()?,
+                };
+                match self {
+                    Inst::InstLload(_) => Inst::InstLload(Word::u64(index)),
+                    _ => Inst::InstLstore(Word::u64(index)),
+                }
+            }
+            Inst::InstGload(_) | Inst::InstGstore(_) => {
+                let slot = match tc.globals.get(operand_str) {
+                    Some(slot) => *slot,
+                    None => operand_str.parse::<u64>().ok()?,
                 };
+                match self {
+                    Inst::InstGload(_) => Inst::InstGload(Word::u64(slot)),
+                    _ => Inst::InstGstore(Word::u64(slot)),
+                }
             }
-            Inst::InstEq(_) => Inst::InstEq(Word::u64(operand_str.parse::<u64>().unwrap())),
-            Inst::InstDup(_) => Inst::InstDup(Word::u64(operand_str.parse::<u64>().unwrap())),
             _ => self,
-        }
+        };
+
+        Some(inst)
     }
 
-    pub fn with_operand_bytes(self, op_bytes: &mut [u8; 8]) -> Self {
-        match self {
+    pub fn with_operand_bytes(self, op_bytes: &mut [u8; 8]) -> Result<Self, VMError> {
+        Ok(match self {
             Inst::InstPush(word) => {
                 let word = match word {
                     Word::i64(_) => Word::from_le_bytes::<i64>(*op_bytes),
                     Word::u64(_) => Word::from_le_bytes::<u64>(*op_bytes),
                     Word::f64(_) => Word::from_le_bytes::<f64>(*op_bytes),
-                    _ => exit(15),
+                    Word::ptr(_) => return Err(VMError::DeserializeOpcodeFail),
                 };
 
                 Inst::InstPush(word)
@@ -215,8 +680,55 @@ impl Inst {
             Inst::InstJmp(_) => Inst::InstJmp(Word::from_le_bytes::<u64>(*op_bytes)),
             Inst::InstEq(_) => Inst::InstEq(Word::from_le_bytes::<u64>(*op_bytes)),
             Inst::InstDup(_) => Inst::InstDup(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstSyscall(_) => Inst::InstSyscall(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstPushs(_) => Inst::InstPushs(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstPushk(_) => Inst::InstPushk(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstCall(_) => Inst::InstCall(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstEnter(_) => Inst::InstEnter(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstLload(_) => Inst::InstLload(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstLstore(_) => Inst::InstLstore(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstGload(_) => Inst::InstGload(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstGstore(_) => Inst::InstGstore(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstTry(_) => Inst::InstTry(Word::from_le_bytes::<u64>(*op_bytes)),
+            Inst::InstSpawn(_) => Inst::InstSpawn(Word::from_le_bytes::<u64>(*op_bytes)),
+            // Zero in files from before halt took an exit status
+            Inst::InstHalt(_) => Inst::InstHalt(Word::from_le_bytes::<i64>(*op_bytes)),
             _ => self,
+        })
+    }
+
+    pub fn to_hasm(&self) -> String {
+        let asm_inst = (*INST_TRANSLATE.extract_val(&self.as_ref())).to_string();
+
+        match self {
+            Inst::InstHalt(Word::i64(0)) => return asm_inst,
+            Inst::InstHalt(operand) => return hasm_with_operand(asm_inst, *operand),
+            Inst::InstPush(operand) => return format!("{} {}", asm_inst, operand.to_literal()),
+            _ => {}
+        }
+
+        if *OPERAND_REQUIRED.get(self.as_ref()).unwrap_or(&false) {
+            return match self {
+                Inst::InstPush(operand)
+                | Inst::InstDup(operand)
+                | Inst::InstEq(operand)
+                | Inst::InstJmp(operand)
+                | Inst::InstSyscall(operand)
+                | Inst::InstPushs(operand)
+                | Inst::InstPushk(operand)
+                | Inst::InstCall(operand)
+                | Inst::InstEnter(operand)
+                | Inst::InstLload(operand)
+                | Inst::InstLstore(operand)
+                | Inst::InstGload(operand)
+                | Inst::InstGstore(operand)
+                | Inst::InstTry(operand)
+                | Inst::InstSpawn(operand) => hasm_with_operand(asm_inst, *operand),
+                _ => exit(2),
+            };
         }
+
+        asm_inst
     }
 
     pub fn resolve_operand(
@@ -224,19 +736,142 @@ impl Inst {
         maybe_operand_str: Option<&str>,
         tc: &mut TranslationContext,
         program_size_t: &mut u16,
-    ) -> Self {
+    ) -> Option<Self> {
         if *OPERAND_REQUIRED.get(self.as_ref()).unwrap_or(&false) {
             return self.with_operand_word(maybe_operand_str, tc, program_size_t);
         }
-        self
+
+        // halt takes an optional exit status
+        if let Inst::InstHalt(_) = self {
+            return match maybe_operand_str {
+                Some(status) => Some(Inst::InstHalt(Word::i64(status.parse().ok()?))),
+                None => Some(Inst::InstHalt(Word::i64(0))),
+            };
+        }
+        Some(self)
     }
 
     pub fn from_bytes(bytes: &mut [u8; 16]) -> Result<Self, VMError> {
         let inst = Inst::deser_opcode(bytes[0]).ok_or(VMError::DeserializeOpcodeFail)?;
 
         let mut op_bytes: [u8; 8] = bytes[8..16].try_into().unwrap();
-        let inst = inst.with_operand_bytes(&mut op_bytes);
+        let inst = inst.with_operand_bytes(&mut op_bytes)?;
 
         Ok(inst)
     }
+
+    // Compact form for .ha code: the opcode, then push's and halt's operand
+    // as its 8 bytes and any other operand, an index, target or count, as a
+    // LEB128 varint. A pushk of one of the first 128 constants takes 2 bytes.
+    pub fn encode(&self, code: &mut Vec<u8>) -> Result<(), VMError> {
+        let bytes = self.to_bytes()?;
+        code.push(bytes[0]);
+        match self {
+            Inst::InstPush(_) | Inst::InstHalt(_) => code.extend(&bytes[8..16]),
+            _ if *OPERAND_REQUIRED.get(self.as_ref()).unwrap_or(&false) => {
+                let mut n = u64::from_le_bytes(bytes[8..16].try_into().expect("8 bytes"));
+                while n >= 0x80 {
+                    code.push(n as u8 | 0x80);
+                    n >>= 7;
+                }
+                code.push(n as u8);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // Reads one instruction off the front of code
+    pub fn decode(code: &mut &[u8]) -> Result<Self, VMError> {
+        let (&opcode, rest) = code.split_first().ok_or(VMError::ParseLeBytesFail)?;
+        let inst = Inst::deser_opcode(opcode).ok_or(VMError::DeserializeOpcodeFail)?;
+        let mut bytes = [0u8; 16];
+        bytes[0] = opcode;
+        *code = rest;
+
+        match inst {
+            Inst::InstPush(_) | Inst::InstHalt(_) => {
+                let operand = code.get(..8).ok_or(VMError::ParseLeBytesFail)?;
+                bytes[8..16].copy_from_slice(operand);
+                *code = &code[8..];
+            }
+            _ if *OPERAND_REQUIRED.get(inst.as_ref()).unwrap_or(&false) => {
+                let mut n = 0u64;
+                for shift in (0..64).step_by(7) {
+                    let (&byte, rest) = code.split_first().ok_or(VMError::ParseLeBytesFail)?;
+                    *code = rest;
+                    n |= ((byte & 0x7F) as u64)
+                        .checked_shl(shift)
+                        .filter(|bits| bits >> shift == (byte & 0x7F) as u64)
+                        .ok_or(VMError::ParseLeBytesFail)?;
+                    if byte & 0x80 == 0 {
+                        bytes[8..16].copy_from_slice(&n.to_le_bytes());
+                        return Inst::from_bytes(&mut bytes);
+                    }
+                }
+                return Err(VMError::ParseLeBytesFail);
+            }
+            _ => {}
+        }
+        Inst::from_bytes(&mut bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn push_round_trips_through_bytes() {
+        for word in [Word::i64(-7), Word::u64(u64::MAX), Word::f64(0.5)] {
+            let mut bytes = Inst::InstPush(word).to_bytes().unwrap();
+            assert_eq!(Inst::from_bytes(&mut bytes).unwrap(), Inst::InstPush(word));
+        }
+    }
+
+    #[test]
+    fn push_of_a_reference_is_an_error_not_an_exit() {
+        let inst = Inst::InstPush(Word::ptr(0x1000));
+        assert!(matches!(inst.to_bytes(), Err(VMError::InvalidOperand)));
+    }
+
+    #[test]
+    fn compact_encoding_round_trips() {
+        let insts = [
+            Inst::InstPushk(Word::u64(3)),
+            Inst::InstPushk(Word::u64(300)),
+            Inst::InstJmp(Word::u64(u64::MAX)),
+            Inst::InstPush(Word::f64(-0.0)),
+            Inst::InstHalt(Word::i64(-1)),
+            Inst::InstAddi,
+        ];
+        let mut code = Vec::new();
+        insts
+            .iter()
+            .for_each(|inst| inst.encode(&mut code).unwrap());
+        assert_eq!(code.len(), 2 + 3 + 11 + 9 + 9 + 1);
+
+        let mut rest = &code[..];
+        for inst in &insts {
+            assert_eq!(&Inst::decode(&mut rest).unwrap(), inst);
+        }
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn truncated_or_overlong_operands_are_rejected() {
+        let mut code = Vec::new();
+        Inst::InstJmp(Word::u64(u64::MAX))
+            .encode(&mut code)
+            .unwrap();
+        (1..code.len()).for_each(|len| assert!(Inst::decode(&mut &code[..len]).is_err()));
+
+        // Bits past the 64th
+        let mut overlong = code.clone();
+        overlong[10] = 0x02;
+        assert!(Inst::decode(&mut &overlong[..]).is_err());
+        let mut endless = vec![code[0]];
+        endless.extend([0x80; 11]);
+        assert!(Inst::decode(&mut &endless[..]).is_err());
+    }
 }
@@ -1,122 +1,704 @@
+mod analysis;
+mod bench;
 #[allow(dead_code)]
 mod bimap;
+mod cfg;
+mod console;
+mod debugger;
+mod decode;
 mod dehasm;
 mod errors;
+mod fiber;
+mod gc;
 mod hasm;
+mod heap;
+mod history;
+mod host;
 mod inst;
 mod macros;
 #[allow(dead_code)]
 mod nanbox;
+mod optimizer;
+mod pool;
+mod profiler;
 mod program;
+mod repl;
+mod snapshot;
+#[cfg(test)]
+mod testing;
+mod trace;
+mod verifier;
 mod vm;
 mod word;
 
+use analysis::{analyze_stack, MaxStack};
+use bench::{format_results, parse_results, run_bench, Bench};
+use clap::{Parser, Subcommand, ValueEnum};
+use console::{SharedConsole, StreamConsole};
+use debugger::Debugger;
 use dehasm::ha_to_hasm;
 pub use errors::*;
 use hasm::hasm_to_ha;
+use heap::HeapOptions;
+use history::{CHECKPOINT_INTERVAL, HISTORY_CAPACITY};
+use host::Capabilities;
+use optimizer::Pass;
+use pool::Pool;
+use profiler::Profiler;
+use program::Program;
+use repl::Repl;
+use snapshot::Snapshot;
 use std::{
-    env,
-    io::{self},
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
     process::exit,
-    str::FromStr,
+    sync::Arc,
 };
-use strum::IntoEnumIterator;
 pub use strum_macros::EnumString;
-use strum_macros::{AsRefStr, EnumIter};
+use trace::{diff_traces, read_trace, BinaryTracer, JsonlTracer, TraceFormat};
 pub use vm::*;
 
-#[derive(EnumString, EnumIter, AsRefStr, Debug)]
+const EXIT_CODES: &str = "Exit codes:
+  0    success
+  1    verification found problems, traces diverge, or a batch run failed
+  2    invalid command line
+  60   emulate: the program halted with a status it cannot exit with
+  61   too many fibers or channels
+  62   deadlock, every fiber is blocked
+  63   no such fiber or channel
+  64   call stack overflow
+  65   ret without a call
+  66   local out of range
+  67   global out of range
+  68   endtry without a try
+  69   uncaught throw
+  70   stack overflow
+  71   stack underflow
+  72   operand non exists
+  73   division by zero
+  74   segment fault
+  75   invalid operand
+  76   invalid input
+  77   permission denied
+  78   out of heap memory
+  79   heap fault, like a double free
+  80   deserialize opcode failed
+  81   malformed .ha bytes
+  82   invalid asm instruction
+  83   unresolved label
+  84   unsupported .ha version
+  90   I/O failure
+  91   verification failed while loading
+  92   max stack exceeds the stack limit
+  93   files still open when writing a snapshot
+
+A fault inside a try block pushes its code from this list for the handler.
+emulate exits with the status a program halts with when that is 1 or 3 to 59.
+Any other nonzero status would pass for one of the codes above, so emulate
+prints it to stderr and exits 60 instead";
+
+// Halt statuses emulate can exit with as they are, clear of the codes above
+const HALT_STATUSES: [RangeInclusive<i64>; 2] = [1..=1, 3..=59];
+const HALT_STATUS_ELSEWHERE: i32 = 60;
+
+#[derive(Parser, Debug)]
+#[command(name = "haesuk", about = "haesuk stack VM toolchain", after_help = EXIT_CODES)]
+struct Cli {
+    #[command(subcommand)]
+    cmd: Cmd,
+
+    /// Only print errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+}
+
+#[derive(Subcommand, Debug)]
 #[allow(non_camel_case_types)]
 pub enum Cmd {
-    hasm,
-    emulate,
-    dehasm,
+    /// Assemble a .hasm file into .ha bytecode
+    hasm {
+        input: PathBuf,
+
+        /// Output path, defaults to the input with a .ha extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Run every optimization pass
+        #[arg(short = 'O')]
+        optimize: bool,
+
+        /// Run only the given optimization passes
+        #[arg(long = "pass", value_delimiter = ',')]
+        passes: Vec<Pass>,
+    },
+
+    /// Disassemble .ha bytecode into a .hasm file
+    dehasm {
+        input: PathBuf,
+
+        /// Output path, defaults to the input with a .hasm extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Run .ha bytecode
+    emulate {
+        #[arg(required_unless_present = "resume")]
+        input: Option<PathBuf>,
+
+        /// Maximum number of instructions to execute, defaults to 64 or the fuel left in a snapshot
+        #[arg(short, long)]
+        limit: Option<u64>,
+
+        /// Print a JSON line trace of every step to stderr
+        #[arg(short, long)]
+        trace: bool,
+
+        /// Write the trace to a file instead of stderr, implies --trace
+        #[arg(long, value_name = "PATH")]
+        trace_output: Option<PathBuf>,
+
+        /// Refuse to run bytecode that fails verification
+        #[arg(long)]
+        verify: bool,
+
+        /// Write the VM state to a snapshot file when the run stops, even on error
+        #[arg(long, value_name = "PATH")]
+        snapshot_on_exit: Option<PathBuf>,
+
+        /// Continue from a snapshot instead of starting the input from scratch
+        #[arg(long, value_name = "PATH", conflicts_with = "input")]
+        resume: Option<PathBuf>,
+
+        #[command(flatten)]
+        capabilities: Capabilities,
+
+        #[command(flatten)]
+        heap: HeapOptions,
+    },
+
+    /// Run .ha bytecode once per input file, spread over a pool of threads
+    batch {
+        input: PathBuf,
+
+        /// Files each run reads its console input from, one run per file
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Worker threads, defaults to one per CPU
+        #[arg(short = 'j', long)]
+        threads: Option<usize>,
+
+        /// Maximum number of instructions each run executes
+        #[arg(short, long, default_value_t = 64)]
+        limit: u64,
+
+        #[command(flatten)]
+        capabilities: Capabilities,
+
+        #[command(flatten)]
+        heap: HeapOptions,
+    },
+
+    /// Run .ha bytecode, recording every step to a trace file
+    trace {
+        input: PathBuf,
+
+        /// Output path, defaults to the input with a .jsonl or .htrace extension
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        #[arg(short, long, value_enum, default_value_t = TraceFormat::jsonl)]
+        format: TraceFormat,
+
+        /// Maximum number of instructions to execute
+        #[arg(short, long, default_value_t = 64)]
+        limit: u64,
+
+        #[command(flatten)]
+        capabilities: Capabilities,
+
+        #[command(flatten)]
+        heap: HeapOptions,
+    },
+
+    /// Run a .ha or .hasm program, reporting where execution time goes
+    profile {
+        input: PathBuf,
+
+        /// Maximum number of instructions to execute
+        #[arg(short, long, default_value_t = 64)]
+        limit: u64,
+
+        /// Rows per report section
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+
+        /// Also write folded stacks for flamegraph tools
+        #[arg(long)]
+        folded: Option<PathBuf>,
+
+        #[command(flatten)]
+        capabilities: Capabilities,
+
+        #[command(flatten)]
+        heap: HeapOptions,
+    },
+
+    /// Measure how many instructions per second the interpreter runs
+    bench {
+        /// Benchmarks to run, defaults to all of them
+        #[arg(value_enum)]
+        benches: Vec<Bench>,
+
+        /// Instructions each benchmark runs
+        #[arg(short, long, default_value_t = 10_000_000)]
+        limit: u64,
+
+        /// Compare against results kept with --save
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+
+        /// Keep the results in this file
+        #[arg(long)]
+        save: Option<PathBuf>,
+    },
+
+    /// Assemble and run hasm interactively, one line at a time
+    repl,
+
+    /// Step through a .ha or .hasm program, forwards and backwards
+    debug {
+        input: PathBuf,
+
+        /// Maximum number of instructions to execute
+        #[arg(short, long, default_value_t = 64)]
+        limit: u64,
+
+        /// Steps kept in the undo log
+        #[arg(long, default_value_t = HISTORY_CAPACITY)]
+        history: usize,
+
+        /// Steps between checkpoints for going back past the undo log
+        #[arg(long, default_value_t = CHECKPOINT_INTERVAL)]
+        checkpoint_every: u64,
+
+        /// File the program reads its input from instead of sharing stdin with debugger commands
+        #[arg(long)]
+        input_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        capabilities: Capabilities,
+
+        #[command(flatten)]
+        heap: HeapOptions,
+    },
+
+    /// Find the first step where two traces diverge
+    trace_diff { a: PathBuf, b: PathBuf },
+
+    /// Check a .ha or .hasm program for malformed control flow and stack usage
+    verify { input: PathBuf },
+
+    /// Print the control flow graph of a .ha or .hasm program
+    cfg {
+        input: PathBuf,
+
+        #[arg(short, long, value_enum, default_value_t = CfgFormat::dot)]
+        format: CfgFormat,
+
+        /// Output path, defaults to stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
 }
 
-fn main() -> io::Result<()> {
-    run_haesuk()?;
-    Ok(())
+#[derive(ValueEnum, Clone, Copy, Debug)]
+#[allow(non_camel_case_types)]
+pub enum CfgFormat {
+    dot,
+    json,
 }
 
-fn run_haesuk() -> io::Result<()> {
-    let avaiable_cmds: Vec<Cmd> = Cmd::iter().map(|cmd| cmd).collect();
+fn main() {
+    let cli = Cli::parse();
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!(
-            "Usage: input cmd, --* with the following {:#?}",
-            avaiable_cmds
-        );
-        exit(-1)
+    if let Err(err) = run_haesuk(cli) {
+        eprintln!("ERROR: {}", err);
+        exit(err.exit_code());
     }
+}
 
-    let cmd = Cmd::from_str(&args[1].as_ref()).unwrap_or_else(|_| {
-        println!(
-            "ERROR: invalid cmd, --* with the following {:#?}",
-            avaiable_cmds
-        );
-        exit(1);
-    });
+fn halt_exit_code(status: i64) -> i32 {
+    if HALT_STATUSES.iter().any(|range| range.contains(&status)) {
+        status as i32
+    } else {
+        HALT_STATUS_ELSEWHERE
+    }
+}
 
-    match cmd {
-        Cmd::hasm => {
-            if args.len() < 3 {
-                println!("Usage: input path args");
-                println!("\t*.hasm");
-                exit(-1)
+fn load_program(path: &Path) -> Result<Program, VMError> {
+    if path.extension().is_some_and(|ext| ext == "hasm") {
+        Program::from_hasm(&fs::read_to_string(path)?)
+    } else {
+        Program::from_bytes(&fs::read(path)?)
+    }
+}
+
+fn run_haesuk(cli: Cli) -> Result<(), VMError> {
+    let quiet = cli.quiet;
+
+    match cli.cmd {
+        Cmd::hasm {
+            input,
+            output,
+            optimize,
+            passes,
+        } => {
+            let passes: Vec<Pass> = if optimize {
+                Pass::value_variants().to_vec()
+            } else {
+                passes
+            };
+            let output = output.unwrap_or_else(|| input.with_extension("ha"));
+
+            let analysis = hasm_to_ha(&input, &output, &passes)?;
+            match analysis.max {
+                // Recursion alone leaves the stack height unknown, nothing grows
+                MaxStack::Unbounded if !analysis.growing.is_empty() => analysis
+                    .growing
+                    .iter()
+                    .for_each(|ip| eprintln!("WARNING: stack grows without bound at ip {}", ip)),
+                max if !quiet => println!(
+                    "{} -> {}, max stack {}",
+                    input.display(),
+                    output.display(),
+                    max
+                ),
+                _ => {}
             }
+        }
 
-            let hasm_path = &args[2];
-            assert!(hasm_path.ends_with(".hasm"));
-            println!("Hasm path: {}", hasm_path);
+        Cmd::dehasm { input, output } => {
+            let output = output.unwrap_or_else(|| input.with_extension("hasm"));
 
-            hasm_to_ha(hasm_path)?;
+            ha_to_hasm(&input, &output)?;
+            if !quiet {
+                println!("{} -> {}", input.display(), output.display());
+            }
         }
 
-        Cmd::dehasm => {
-            if args.len() < 3 {
-                println!("Usage: input path args");
-                println!("\t*.ha");
-                exit(-1)
+        Cmd::emulate {
+            input,
+            limit,
+            trace,
+            trace_output,
+            verify,
+            snapshot_on_exit,
+            resume,
+            capabilities,
+            heap,
+        } => {
+            let mut vm = VM::new();
+            vm.grant(capabilities);
+            vm.configure_heap(heap);
+            match (resume, input) {
+                (Some(resume), _) => {
+                    let snapshot = Snapshot::from_bytes(&fs::read(resume)?)?;
+                    if verify {
+                        verifier::verify(&snapshot.program)
+                            .map_err(|errors| VMError::VerifyFail { errors })?;
+                    }
+                    vm.restore(snapshot)?;
+                }
+                (None, Some(input)) if verify => vm.load_verified_ha_from_file(&input)?,
+                (None, Some(input)) => vm.load_ha_from_file(&input)?,
+                (None, None) => unreachable!("clap requires input without --resume"),
+            }
+
+            if let Some(path) = trace_output {
+                let out = BufWriter::new(File::create(path)?);
+                vm.set_tracer(Box::new(JsonlTracer::new(out)));
+            } else if trace {
+                vm.set_tracer(Box::new(JsonlTracer::new(io::stderr())));
+            }
+            let result = vm.run(limit);
+            vm.finish_tracer()?;
+            if let Some(path) = snapshot_on_exit {
+                fs::write(path, vm.snapshot().to_bytes()?)?;
+            }
+            let status = result?;
+            if !quiet {
+                vm.dump();
             }
+            if let Some(status) = status.filter(|status| *status != 0) {
+                let code = halt_exit_code(status);
+                if code == HALT_STATUS_ELSEWHERE {
+                    eprintln!("halted with status {}", status);
+                }
+                exit(code);
+            }
+        }
+
+        Cmd::batch {
+            input,
+            inputs,
+            threads,
+            limit,
+            capabilities,
+            heap,
+        } => {
+            let program = Arc::new(load_program(&input)?);
+            let texts = inputs
+                .iter()
+                .map(fs::read_to_string)
+                .collect::<Result<Vec<String>, io::Error>>()?;
 
-            let hasm_path = &args[2];
-            assert!(hasm_path.ends_with(".ha"));
-            println!("Ha path: {}", hasm_path);
+            let mut pool = Pool::new(program, threads, limit);
+            pool.grant(capabilities);
+            pool.configure_heap(heap);
+            let results = pool.run(&texts);
 
-            ha_to_hasm(hasm_path)?;
+            let mut failed = false;
+            for (path, result) in inputs.iter().zip(results) {
+                match result {
+                    Ok(outcome) if !quiet => {
+                        match outcome.exit_status {
+                            Some(status) => println!("{}: exit {}", path.display(), status),
+                            None => println!("{}: out of fuel", path.display()),
+                        }
+                        print!("{}", String::from_utf8_lossy(&outcome.output));
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        failed = true;
+                        println!("{}: ERROR: {}", path.display(), err);
+                    }
+                }
+            }
+            if failed {
+                exit(1)
+            }
         }
 
-        Cmd::emulate => {
-            if args.len() < 3 {
-                println!("Usage: input path args");
-                println!("\t*.ha");
-                println!("Extra optional args: limit");
-                println!("\tlimit __");
-                exit(-1)
+        Cmd::trace {
+            input,
+            output,
+            format,
+            limit,
+            capabilities,
+            heap,
+        } => {
+            let output = output.unwrap_or_else(|| match format {
+                TraceFormat::jsonl => input.with_extension("jsonl"),
+                TraceFormat::binary => input.with_extension("htrace"),
+            });
+            let out = BufWriter::new(File::create(&output)?);
+
+            let mut vm = VM::new();
+            vm.grant(capabilities);
+            vm.configure_heap(heap);
+            vm.load_ha_from_file(&input)?;
+            vm.set_tracer(match format {
+                TraceFormat::jsonl => Box::new(JsonlTracer::new(out)),
+                TraceFormat::binary => Box::new(BinaryTracer::new(out)),
+            });
+            let result = vm.run(Some(limit));
+            vm.finish_tracer()?;
+
+            if !quiet {
+                println!("{} -> {}", input.display(), output.display());
             }
+            result?;
+        }
 
-            let maybe_limit = if args.len() > 3 {
-                if args.len() < 5 {
-                    println!("Usage: input limit");
-                    println!("\t0~2^16");
-                    exit(-1)
-                }
+        Cmd::profile {
+            input,
+            limit,
+            top,
+            folded,
+            capabilities,
+            heap,
+        } => {
+            let program = load_program(&input)?;
+            let folded = folded.map(File::create).transpose()?.map(BufWriter::new);
+            let profiler = Profiler::new(&program, top, io::stdout(), folded);
+
+            let mut vm = VM::new();
+            vm.grant(capabilities);
+            vm.configure_heap(heap);
+            vm.load_ha_from_memory(program)?;
+            vm.set_tracer(Box::new(profiler));
+            let result = vm.run(Some(limit));
+            vm.finish_tracer()?;
+            result?;
+        }
 
-                Some(args[4].parse::<u16>().unwrap())
+        Cmd::bench {
+            benches,
+            limit,
+            baseline,
+            save,
+        } => {
+            let benches = if benches.is_empty() {
+                Bench::value_variants().to_vec()
             } else {
-                None
+                benches
             };
+            let baseline = match baseline {
+                Some(path) => parse_results(&fs::read_to_string(path)?),
+                None => Default::default(),
+            };
+            let mut results = Vec::new();
+            for bench in benches {
+                let result = run_bench(bench, limit)?;
+                let rate = result.per_second() / 1e6;
+                let change = match baseline.get(&format!("{:?}", result.bench)) {
+                    Some(before) => {
+                        format!(", {:+.1}% on the baseline", (rate / before - 1.0) * 100.0)
+                    }
+                    None => String::new(),
+                };
+                println!(
+                    "{:<8} {} instructions in {:.3}s, {:.1}M/s{}",
+                    format!("{:?}", result.bench),
+                    result.instructions,
+                    result.elapsed.as_secs_f64(),
+                    rate,
+                    change
+                );
+                results.push(result);
+            }
+            if let Some(path) = save {
+                fs::write(path, format_results(&results))?;
+            }
+        }
 
-            let eml_path = &args[2];
-            assert!(eml_path.ends_with(".ha"));
+        Cmd::repl => Repl::default().run(
+            SharedConsole::new(BufReader::new(io::stdin())),
+            io::stdout(),
+        )?,
 
+        Cmd::debug {
+            input,
+            limit,
+            history,
+            checkpoint_every,
+            input_file,
+            capabilities,
+            heap,
+        } => {
+            let stdin = SharedConsole::new(BufReader::new(io::stdin()));
             let mut vm = VM::new();
-            vm.load_ha_from_file(eml_path)?;
-            vm.run(maybe_limit).map_err(io::Error::from)?;
-            vm.dump();
+            vm.grant(capabilities);
+            vm.configure_heap(heap);
+            match input_file {
+                Some(input_file) => {
+                    let input = BufReader::new(File::open(input_file)?);
+                    vm.set_console(Box::new(StreamConsole::new(input, io::stdout())))
+                }
+                None => vm.set_console(Box::new(stdin.clone())),
+            };
+            vm.load_ha_from_memory(load_program(&input)?)?;
+            vm.set_fuel(limit);
+            vm.record_history(history, checkpoint_every);
+            Debugger::new(vm).run(stdin, io::stdout())?;
+        }
+
+        Cmd::trace_diff { a, b } => {
+            let (trace_a, trace_b) = (read_trace(&a)?, read_trace(&b)?);
+            match diff_traces(&trace_a, &trace_b) {
+                None => {
+                    if !quiet {
+                        println!("traces agree for {} steps", trace_a.len());
+                    }
+                }
+                Some(step) => {
+                    println!("traces diverge at step {}", step);
+                    println!(
+                        "  {}: {}",
+                        a.display(),
+                        trace_a.get(step).map_or("<end>", String::as_str)
+                    );
+                    println!(
+                        "  {}: {}",
+                        b.display(),
+                        trace_b.get(step).map_or("<end>", String::as_str)
+                    );
+                    exit(1)
+                }
+            }
+        }
+
+        Cmd::verify { input } => {
+            let program = load_program(&input)?;
+            match verifier::verify(&program) {
+                Ok(()) if !quiet => println!("{}: ok", input.display()),
+                Ok(()) => {}
+                Err(errors) => {
+                    errors
+                        .iter()
+                        .for_each(|err| println!("{}: {}", input.display(), err));
+                    exit(1)
+                }
+            }
+        }
+
+        Cmd::cfg {
+            input,
+            format,
+            output,
+        } => {
+            let program = load_program(&input)?;
+            let cfg = cfg::Cfg::build(&program);
+            let analysis = analyze_stack(&program, &cfg);
+
+            let rendered = match format {
+                CfgFormat::dot => cfg.to_dot(&program, &analysis.heights),
+                CfgFormat::json => cfg.to_json(&program, &analysis.heights) + "\n",
+            };
+            match output {
+                Some(output) => fs::write(output, rendered)?,
+                None => print!("{}", rendered),
+            }
         }
     };
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn halt_statuses_never_pass_for_a_tool_error() {
+        assert_eq!(halt_exit_code(1), 1);
+        assert_eq!(halt_exit_code(42), 42);
+        for status in [-1, 2, 60, 70, 256, i64::MAX] {
+            assert_eq!(halt_exit_code(status), HALT_STATUS_ELSEWHERE, "{}", status);
+        }
+        let codes = [61, 73, 84, 90, 92];
+        assert!(codes
+            .iter()
+            .all(|code| halt_exit_code(*code) != *code as i32));
+    }
+
+    #[test]
+    fn emulate_traces_to_a_file() {
+        let cli = Cli::try_parse_from(["haesuk", "emulate", "a.ha", "--trace-output", "a.jsonl"]);
+        match cli.unwrap().cmd {
+            Cmd::emulate {
+                trace,
+                trace_output,
+                ..
+            } => {
+                assert!(!trace);
+                assert_eq!(trace_output, Some(PathBuf::from("a.jsonl")));
+            }
+            cmd => panic!("parsed as {:?}", cmd),
+        }
+    }
+}
@@ -7,6 +7,7 @@ const VALUE_MASK_BITS: u64 = (1u64 << 48) - 1;
 
 #[derive(Debug, PartialEq)]
 #[repr(u8)]
+#[allow(clippy::enum_variant_names)]
 pub enum NanType {
     IntType = 0,
     PointerType = 1,
@@ -28,8 +29,7 @@ fn extract_type(f: f64) -> NanType {
 }
 
 fn extract_value(f: f64) -> u64 {
-    let n = f.to_bits() & VALUE_MASK_BITS;
-    n as u64
+    f.to_bits() & VALUE_MASK_BITS
 }
 
 fn set_type(f: f64, nan_type: NanType) -> f64 {
@@ -40,7 +40,7 @@ fn set_type(f: f64, nan_type: NanType) -> f64 {
 
 fn set_value(f: f64, value: u64) -> f64 {
     let f_bits = f.to_bits();
-    let n: u64 = value as u64;
+    let n: u64 = value;
     f64::from_bits((f_bits & !VALUE_MASK_BITS) | n)
 }
 
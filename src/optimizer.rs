@@ -0,0 +1,269 @@
+use clap::ValueEnum;
+
+use crate::{cfg::Cfg, inst::Inst, program::Program, word::Word};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum Pass {
+    nops,
+    fold,
+    jumps,
+    unreachable,
+}
+
+pub fn optimize(program: &mut Program, passes: &[Pass]) {
+    // Fixed order, whichever subset is enabled
+    [Pass::nops, Pass::fold, Pass::jumps, Pass::unreachable]
+        .into_iter()
+        .filter(|pass| passes.contains(pass))
+        .for_each(|pass| match pass {
+            Pass::nops => remove_nops(program),
+            Pass::fold => fold_constants(program),
+            Pass::jumps => collapse_jumps(program),
+            Pass::unreachable => remove_unreachable(program),
+        });
+}
+
+// Drops every instruction not kept, pointing jumps and labels at the next surviving instruction
+fn retain(program: &mut Program, keep: &[bool]) {
+    let mut remap = Vec::with_capacity(keep.len() + 1);
+    let mut kept = 0u64;
+    keep.iter().for_each(|k| {
+        remap.push(kept);
+        kept += *k as u64;
+    });
+    remap.push(kept);
+
+    program
+        .labels
+        .iter_mut()
+        .for_each(|(ip, _)| *ip = remap[*ip] as usize);
+
+    let insts = std::mem::take(&mut program.insts);
+    program.insts = insts
+        .into_iter()
+        .zip(keep)
+        .filter(|(_, k)| **k)
+        .map(
+            |(inst, _)| match inst.target().and_then(|target| remap.get(target)) {
+                Some(target) => inst.with_target(*target as usize),
+                None => inst,
+            },
+        )
+        .collect();
+}
+
+fn jump_targets(program: &Program) -> Vec<bool> {
+    let mut targets = vec![false; program.insts.len()];
+    program.insts.iter().for_each(|inst| {
+        if let Some(target) = inst.target().and_then(|target| targets.get_mut(target)) {
+            *target = true;
+        }
+    });
+
+    targets
+}
+
+fn remove_nops(program: &mut Program) {
+    let keep: Vec<bool> = program
+        .insts
+        .iter()
+        .map(|inst| *inst != Inst::InstNop)
+        .collect();
+    retain(program, &keep);
+}
+
+// Mirrors the arithmetic in VM::run, giving up wherever the VM would fault
+fn eval(op: &Inst, a: Word, b: Word) -> Option<Word> {
+    match op {
+        Inst::InstAddi => i64::from(a).checked_add(i64::from(b)).map(Word::i64),
+        Inst::InstSubi => i64::from(a).checked_sub(i64::from(b)).map(Word::i64),
+        Inst::InstMuli => i64::from(a).checked_mul(i64::from(b)).map(Word::i64),
+        Inst::InstDivi => i64::from(a).checked_div(i64::from(b)).map(Word::i64),
+        Inst::InstAddf => Some(Word::f64(f64::from(a) + f64::from(b))),
+        Inst::InstSubf => Some(Word::f64(f64::from(a) - f64::from(b))),
+        Inst::InstMulf => Some(Word::f64(f64::from(a) * f64::from(b))),
+        Inst::InstDivf if f64::from(b) != 0.0 => Some(Word::f64(f64::from(a) / f64::from(b))),
+        _ => None,
+    }
+}
+
+fn fold_constants(program: &mut Program) {
+    loop {
+        let targets = jump_targets(program);
+        let insts = &mut program.insts;
+        let mut keep = vec![true; insts.len()];
+        let mut folded = false;
+
+        let mut ip = 0;
+        while ip + 2 < insts.len() {
+            // Only the first push may be entered by a jump
+            if let (Inst::InstPush(a), Inst::InstPush(b), false, false) =
+                (&insts[ip], &insts[ip + 1], targets[ip + 1], targets[ip + 2])
+            {
+                if let Some(result) = eval(&insts[ip + 2], *a, *b) {
+                    insts[ip] = Inst::InstPush(result);
+                    keep[ip + 1] = false;
+                    keep[ip + 2] = false;
+                    folded = true;
+                    ip += 3;
+                    continue;
+                }
+            }
+            ip += 1;
+        }
+
+        if !folded {
+            break;
+        }
+        retain(program, &keep);
+    }
+}
+
+// Points every jmp, call, try and spawn that lands on a jmp at where the
+// chain of jmps ends up, none of them touch the stack on the way
+fn collapse_jumps(program: &mut Program) {
+    let insts = &program.insts;
+    let resolved: Vec<Inst> = insts
+        .iter()
+        .map(|inst| {
+            let Some(mut target) = inst.target() else {
+                return inst.clone();
+            };
+
+            // Bounded by the program size, so a jmp cycle ends up where it started
+            for _ in 0..insts.len() {
+                match insts.get(target) {
+                    Some(Inst::InstJmp(next)) => target = u64::from(*next) as usize,
+                    _ => break,
+                }
+            }
+
+            inst.clone().with_target(target)
+        })
+        .collect();
+
+    program.insts = resolved;
+}
+
+fn remove_unreachable(program: &mut Program) {
+    let cfg = Cfg::build(program);
+    let reachable = cfg.reachable();
+
+    let mut keep = vec![false; program.insts.len()];
+    cfg.blocks
+        .iter()
+        .zip(reachable)
+        .filter(|(_, reachable)| *reachable)
+        .for_each(|(block, _)| keep[block.ips()].fill(true));
+    retain(program, &keep);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{finish, vm_with, Run};
+
+    fn run(program: Program) -> Run {
+        finish(vm_with(program, "")).unwrap()
+    }
+
+    // Optimizes with one pass, checking it changed something and nothing observable
+    fn assert_preserved(hasm: &str, pass: Pass) -> Program {
+        let program = Program::from_hasm(hasm).unwrap();
+        let mut optimized = program.clone();
+        optimize(&mut optimized, &[pass]);
+
+        assert_ne!(optimized.insts, program.insts, "{:?} changed nothing", pass);
+        assert_eq!(run(optimized.clone()), run(program));
+        optimized
+    }
+
+    #[test]
+    fn nops_preserves_behavior() {
+        let optimized = assert_preserved(
+            "push 1
+            nop
+            jmp over
+            nop
+            over:
+            nop
+            printi
+            push 2
+            halt 3",
+            Pass::nops,
+        );
+        assert!(!optimized.insts.contains(&Inst::InstNop));
+    }
+
+    #[test]
+    fn fold_preserves_behavior() {
+        let optimized = assert_preserved(
+            "push 2
+            push 3
+            muli
+            push 1.5
+            push 2.0
+            addf
+            printf
+            printi
+            halt",
+            Pass::fold,
+        );
+        assert_eq!(optimized.insts[0], Inst::InstPush(Word::i64(6)));
+    }
+
+    #[test]
+    fn jumps_preserves_behavior() {
+        let optimized = assert_preserved(
+            "jmp a
+            a:
+            jmp b
+            b:
+            push 7
+            call f
+            printi
+            try handler
+            push 1
+            throw
+            handler:
+            jmp caught
+            caught:
+            printi
+            halt
+            f:
+            jmp g
+            g:
+            push 1
+            addi
+            ret",
+            Pass::jumps,
+        );
+        // Nothing lands on a jmp any more, whatever instruction gets it there
+        optimized
+            .insts
+            .iter()
+            .filter_map(Inst::target)
+            .for_each(|target| assert!(!matches!(optimized.insts[target], Inst::InstJmp(_))));
+    }
+
+    #[test]
+    fn unreachable_preserves_behavior() {
+        let optimized = assert_preserved(
+            "push 1
+            call f
+            jmp end
+            push 2
+            printi
+            end:
+            printi
+            halt
+            f:
+            push 40
+            addi
+            ret",
+            Pass::unreachable,
+        );
+        assert!(!optimized.insts.contains(&Inst::InstPush(Word::i64(2))));
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    panic,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use crate::{
+    console::BufferConsole, heap::HeapOptions, host::Capabilities, program::Program, VMError, VM,
+};
+
+// Pools hand VMs and the program they share to other threads
+const _: () = {
+    const fn send<T: Send>() {}
+    send::<Arc<Program>>();
+    send::<VM>();
+};
+
+// How one run ended, None when the fuel ran out first, and what it printed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub exit_status: Option<i64>,
+    pub output: Vec<u8>,
+}
+
+// Runs one program over many inputs, each in a fresh VM, spread over a
+// fixed number of threads. Every VM shares the one loaded program.
+#[derive(Debug, Clone)]
+pub struct Pool {
+    program: Arc<Program>,
+    threads: usize,
+    fuel: u64,
+    heap_options: HeapOptions,
+    capabilities: Capabilities,
+}
+
+impl Pool {
+    // With no thread count, one thread per CPU
+    pub fn new(program: Arc<Program>, threads: Option<usize>, fuel: u64) -> Self {
+        let threads = threads
+            .or_else(|| thread::available_parallelism().ok().map(usize::from))
+            .unwrap_or(1);
+
+        Self {
+            program,
+            threads: threads.max(1),
+            fuel,
+            heap_options: HeapOptions::default(),
+            capabilities: Capabilities::default(),
+        }
+    }
+
+    pub fn configure_heap(&mut self, options: HeapOptions) {
+        self.heap_options = options;
+    }
+
+    pub fn grant(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    // One run with input as its console input
+    pub fn run_one(&self, input: &str) -> Result<Outcome, VMError> {
+        let mut vm = VM::new();
+        vm.grant(self.capabilities.clone());
+        vm.configure_heap(self.heap_options);
+        vm.set_console(Box::new(BufferConsole::new(input)));
+        vm.load_shared(self.program.clone())?;
+
+        let exit_status = vm.run(Some(self.fuel))?;
+        Ok(Outcome {
+            exit_status,
+            output: vm.console().captured().unwrap_or_default().to_vec(),
+        })
+    }
+
+    // Results come back in the order of the inputs. Threads take the next
+    // input as they free up, so slow runs do not hold up the rest.
+    pub fn run(&self, inputs: &[String]) -> Vec<Result<Outcome, VMError>> {
+        let next = AtomicUsize::new(0);
+        let threads = self.threads.min(inputs.len());
+
+        let mut results: Vec<(usize, Result<Outcome, VMError>)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+                        loop {
+                            let at = next.fetch_add(1, Ordering::Relaxed);
+                            let Some(input) = inputs.get(at) else {
+                                return done;
+                            };
+                            done.push((at, self.run_one(input)));
+                        }
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .flat_map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|panic| panic::resume_unwind(panic))
+                })
+                .collect()
+        });
+
+        results.sort_by_key(|(at, _)| *at);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(hasm: &str, threads: Option<usize>, fuel: u64) -> Pool {
+        Pool::new(Arc::new(Program::from_hasm(hasm).unwrap()), threads, fuel)
+    }
+
+    #[test]
+    fn results_come_back_in_input_order() {
+        let pool = pool("readi\ndup 0\nmuli\nprinti\nhalt 4", Some(4), 64);
+        let mut inputs: Vec<String> = (0..100).map(|n| n.to_string()).collect();
+        inputs[37] = "x".to_string();
+
+        let results = pool.run(&inputs);
+        assert_eq!(results.len(), inputs.len());
+        for (n, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(outcome) => {
+                    assert_eq!(outcome.exit_status, Some(4));
+                    assert_eq!(outcome.output, (n * n).to_string().into_bytes());
+                }
+                Err(err) => {
+                    assert_eq!(n, 37);
+                    assert_eq!(err.exit_code(), 76);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn each_run_gets_its_own_fuel() {
+        let pool = pool("push 65\nprintc\njmp 0", None, 9);
+        let outcomes: Vec<_> = pool
+            .run(&["".to_string(), "".to_string()])
+            .into_iter()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(outcomes[0], outcomes[1]);
+        assert_eq!(outcomes[0].exit_status, None);
+        assert_eq!(outcomes[0].output, b"AAA");
+        assert!(pool.run(&[]).is_empty());
+    }
+}
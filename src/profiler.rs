@@ -0,0 +1,281 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    io::Write,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    cfg::Cfg,
+    inst::INST_TRANSLATE,
+    program::Program,
+    trace::{Step, Tracer},
+    VMError,
+};
+
+const NO_LABEL: &str = "<entry>";
+
+#[derive(Debug)]
+struct Loop {
+    header: usize,
+    // Instruction range covered from the header down to the jump back
+    body: std::ops::Range<usize>,
+}
+
+#[derive(Debug)]
+pub struct Profiler<W: Write + Debug, F: Write + Debug> {
+    hasm: Vec<String>,
+    labels: Vec<String>,
+    block_of: Vec<usize>,
+    loops: Vec<Loop>,
+
+    ip_counts: Vec<u64>,
+    opcode_counts: BTreeMap<&'static str, u64>,
+    block_time: Vec<Duration>,
+    last: Option<Instant>,
+    folded_counts: BTreeMap<String, u64>,
+
+    top: usize,
+    report: W,
+    folded: Option<F>,
+}
+
+impl<W: Write + Debug, F: Write + Debug> Profiler<W, F> {
+    pub fn new(program: &Program, top: usize, report: W, folded: Option<F>) -> Self {
+        let cfg = Cfg::build(program);
+        let size = program.insts.len();
+
+        let mut block_of = vec![0; size];
+        cfg.blocks
+            .iter()
+            .enumerate()
+            .for_each(|(id, block)| block_of[block.ips()].fill(id));
+
+        // A block jumped to from itself or from further down is a loop header
+        let mut loops: Vec<Loop> = cfg
+            .blocks
+            .iter()
+            .flat_map(|latch| {
+                let blocks = &cfg.blocks;
+                latch
+                    .succs
+                    .iter()
+                    .filter(|succ| blocks[**succ].start <= latch.start)
+                    .map(|succ| Loop {
+                        header: blocks[*succ].start,
+                        body: blocks[*succ].start..latch.end,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        loops.sort_by_key(|l| l.header);
+
+        Self {
+            hasm: program.to_hasm(),
+            labels: (0..size)
+                .map(|ip| program.label_at(ip).unwrap_or(NO_LABEL).to_string())
+                .collect(),
+            block_of,
+            loops,
+
+            ip_counts: vec![0; size],
+            opcode_counts: BTreeMap::new(),
+            block_time: vec![Duration::ZERO; cfg.blocks.len()],
+            last: None,
+            folded_counts: BTreeMap::new(),
+
+            top,
+            report,
+            folded,
+        }
+    }
+
+    fn write_report(&mut self) -> std::io::Result<()> {
+        let total: u64 = self.ip_counts.iter().sum();
+        let total_time: Duration = self.block_time.iter().sum();
+        let percent = |count: u64| count as f64 * 100.0 / total.max(1) as f64;
+        let out = &mut self.report;
+
+        writeln!(out, "steps {}, time {:?}", total, total_time)?;
+
+        writeln!(out, "\nhot instructions")?;
+        let mut ips: Vec<usize> = (0..self.ip_counts.len())
+            .filter(|ip| self.ip_counts[*ip] > 0)
+            .collect();
+        ips.sort_by_key(|ip| std::cmp::Reverse(self.ip_counts[*ip]));
+        for ip in ips.into_iter().take(self.top) {
+            let count = self.ip_counts[ip];
+            writeln!(
+                out,
+                "  {:>10} {:>6.2}%  {:>5}: {:<16} {}",
+                count,
+                percent(count),
+                ip,
+                self.hasm[ip],
+                self.labels[ip]
+            )?;
+        }
+
+        writeln!(out, "\nhot opcodes")?;
+        let mut opcodes: Vec<(&str, u64)> =
+            self.opcode_counts.iter().map(|(k, v)| (*k, *v)).collect();
+        opcodes.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        for (opcode, count) in opcodes.into_iter().take(self.top) {
+            writeln!(out, "  {:>10} {:>6.2}%  {}", count, percent(count), opcode)?;
+        }
+
+        writeln!(out, "\nhot labels")?;
+        let mut labels: BTreeMap<&str, (u64, Duration)> = BTreeMap::new();
+        self.ip_counts.iter().enumerate().for_each(|(ip, count)| {
+            labels.entry(&self.labels[ip]).or_default().0 += count;
+        });
+        self.block_time.iter().enumerate().for_each(|(id, time)| {
+            if let Some(ip) = self.block_of.iter().position(|block| *block == id) {
+                labels.entry(&self.labels[ip]).or_default().1 += *time;
+            }
+        });
+        let mut labels: Vec<_> = labels.into_iter().filter(|(_, (c, _))| *c > 0).collect();
+        labels.sort_by_key(|(_, (count, _))| std::cmp::Reverse(*count));
+        for (label, (count, time)) in labels.into_iter().take(self.top) {
+            writeln!(
+                out,
+                "  {:>10} {:>6.2}%  {:>12?}  {}",
+                count,
+                percent(count),
+                time,
+                label
+            )?;
+        }
+
+        writeln!(out, "\nloops")?;
+        for l in &self.loops {
+            let steps: u64 = self.ip_counts[l.body.clone()].iter().sum();
+            let mut blocks: Vec<usize> = self.block_of[l.body.clone()].to_vec();
+            blocks.dedup();
+            let time: Duration = blocks.iter().map(|id| self.block_time[*id]).sum();
+            writeln!(
+                out,
+                "  ip {:>5} {:<16} iterations {:>10}, steps {:>10} ({:.2}%), time {:?}",
+                l.header,
+                self.labels[l.header],
+                self.ip_counts[l.header],
+                steps,
+                percent(steps),
+                time
+            )?;
+        }
+
+        Ok(())
+    }
+
+    // caller;...;label;opcode count, ready for flamegraph.pl or inferno
+    fn write_folded(&mut self) -> std::io::Result<()> {
+        let Some(out) = self.folded.as_mut() else {
+            return Ok(());
+        };
+
+        for (stack, count) in &self.folded_counts {
+            writeln!(out, "{} {}", stack, count)?;
+        }
+        out.flush()
+    }
+
+    // Calls come from the VM's frames, so throws and fiber switches are
+    // already accounted for
+    fn count_folded(&mut self, step: &Step) {
+        let mut stack: Vec<&str> = step
+            .calls
+            .iter()
+            .rev()
+            .filter_map(|call| self.labels.get(*call))
+            .map(String::as_str)
+            .collect();
+        stack.push(&self.labels[step.ip]);
+        stack.push(self.hasm[step.ip].split(' ').next().unwrap_or_default());
+        *self.folded_counts.entry(stack.join(";")).or_default() += 1;
+    }
+}
+
+impl<W: Write + Debug + Send, F: Write + Debug + Send> Tracer for Profiler<W, F> {
+    fn wants_calls(&self) -> bool {
+        self.folded.is_some()
+    }
+
+    fn step(&mut self, step: &Step) -> Result<(), VMError> {
+        let now = Instant::now();
+        let elapsed = self.last.map_or(Duration::ZERO, |last| now - last);
+        self.last = Some(now);
+
+        if let Some(count) = self.ip_counts.get_mut(step.ip) {
+            *count += 1;
+            self.block_time[self.block_of[step.ip]] += elapsed;
+            if self.folded.is_some() {
+                self.count_folded(step);
+            }
+        }
+        *self
+            .opcode_counts
+            .entry(INST_TRANSLATE.extract_val(&step.inst.as_ref()))
+            .or_default() += 1;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), VMError> {
+        self.write_report()?;
+        self.write_folded()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::vm_with;
+    use std::{env, fs, fs::File, process};
+
+    #[test]
+    fn throws_unwind_the_folded_stacks() {
+        let program = Program::from_hasm(
+            "try caught
+            call f
+            endtry
+            halt
+        caught:
+            printi
+            halt
+        f:
+            call g
+            ret
+        g:
+            push 7
+            throw",
+        )
+        .unwrap();
+        let path = env::temp_dir().join(format!("haesuk-{}-folded", process::id()));
+
+        let folded = File::create(&path).unwrap();
+        let profiler = Profiler::new(&program, 10, Vec::new(), Some(folded));
+        let mut vm = vm_with(program, "");
+        vm.set_tracer(Box::new(profiler));
+        vm.run(Some(100)).unwrap();
+        vm.finish_tracer().unwrap();
+
+        let folded = fs::read_to_string(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let mut lines: Vec<&str> = folded.lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "<entry>;call 1",
+                "<entry>;f;call 1",
+                "<entry>;f;g;push 1",
+                "<entry>;f;g;throw 1",
+                "<entry>;try 1",
+                "caught;halt 1",
+                "caught;printi 1",
+            ]
+        );
+    }
+}
@@ -1,17 +1,61 @@
-use std::{collections::HashMap, ops::Deref, process::exit, str::FromStr, u16};
+use std::{collections::HashMap, ops::Deref, str::FromStr};
 
 use crate::{
-    dehasm::hasm_with_operand,
-    inst::{Inst, INST_TRANSLATE, OPERAND_REQUIRED},
+    analysis::{analyze_stack, MaxStack},
+    cfg::Cfg,
+    dehasm::escape_data,
+    inst::{Inst, INST_TRANSLATE},
+    word::Word,
     VMError,
 };
 
 pub const LABLE_TABLE_CAPACITY: u16 = u16::MAX;
 pub const DEFERRED_OPERANDS_CAPACITY: u16 = u16::MAX;
 
-#[derive(Default, Debug)]
+// .ha header, 16 bytes:
+// [0..4] magic, [4..6] version, [6..8] reserved, [8..16] max stack, as
+// the assembler found it for tools to show
+// Sections follow: [0..4] kind, [4..8] payload length, then the payload.
+pub const HA_MAGIC: &[u8; 4] = b"HAES";
+pub const HA_VERSION: u16 = 1;
+pub const HA_HEADER_SIZE: usize = 16;
+const MAX_STACK_UNKNOWN: u64 = u64::MAX;
+const MAX_STACK_UNBOUNDED: u64 = u64::MAX - 1;
+
+// Instructions back to back, as Inst::encode writes them
+const SECTION_CODE: u32 = 1;
+// Debug info, repeated [0..8] ip, [8..12] name length, then the name
+const SECTION_LABELS: u32 = 2;
+// Initial contents of VM memory
+const SECTION_DATA: u32 = 3;
+// Debug info, laid out like SECTION_LABELS with memory offsets for ips
+const SECTION_DATA_LABELS: u32 = 4;
+// String literals for pushs, repeated [0..4] length, then the bytes
+const SECTION_STRINGS: u32 = 5;
+// Numbers for pushk, repeated [0] Word tag, then [1..9] the value
+const SECTION_CONSTANTS: u32 = 6;
+// Initial value of every global slot, laid out like SECTION_CONSTANTS
+const SECTION_GLOBALS: u32 = 7;
+// Debug info, laid out like SECTION_LABELS with global slots for ips
+const SECTION_GLOBAL_NAMES: u32 = 8;
+
+#[derive(Default, Debug, Clone)]
 pub struct Program {
     pub insts: Vec<Inst>,
+    pub max_stack: Option<MaxStack>,
+    // Debug info, (ip, label) sorted by ip
+    pub labels: Vec<(usize, String)>,
+    pub data: Vec<u8>,
+    // Debug info, (offset, name) sorted by offset
+    pub data_labels: Vec<(usize, String)>,
+    // String literals, each one stored once however often it is pushed
+    pub strings: Vec<Vec<u8>>,
+    // Numbers pushed more than once, bit for bit as written
+    pub constants: Vec<Word>,
+    // Initial value of each global, the VM starts from these
+    pub globals: Vec<Word>,
+    // Debug info, (slot, name) sorted by slot
+    pub global_names: Vec<(usize, String)>,
 }
 
 #[derive(Default, Debug)]
@@ -24,45 +68,240 @@ pub struct HMCache<K, V> {
 pub struct TranslationContext {
     pub label_table: HMCache<String, u16>,
     pub deferred_operands: HMCache<u16, String>,
+    pub data_symbols: HashMap<String, u64>,
+    pub globals: HashMap<String, u64>,
+    pub strings: Vec<Vec<u8>>,
+    pub string_indices: HashMap<Vec<u8>, u64>,
+    // Names from the latest run of .local lines, numbered from 0
+    pub locals: HashMap<String, u64>,
+    // Set once an instruction follows them, so the next .local starts afresh
+    pub locals_closed: bool,
+}
+
+impl TranslationContext {
+    // Index of the literal in the string pool, adding it the first time
+    pub fn intern(&mut self, literal: Vec<u8>) -> u64 {
+        if let Some(index) = self.string_indices.get(&literal) {
+            return *index;
+        }
+        let index = self.strings.len() as u64;
+        self.strings.push(literal.clone());
+        self.string_indices.insert(literal, index);
+        index
+    }
+
+    // Gives the local the next index in its frame
+    pub fn declare_local(&mut self, name: &str) -> Option<u64> {
+        if self.locals_closed {
+            self.locals.clear();
+            self.locals_closed = false;
+        }
+        if self.locals.contains_key(name) {
+            return None;
+        }
+        let index = self.locals.len() as u64;
+        self.locals.insert(name.to_string(), index);
+        Some(index)
+    }
 }
 
 impl Program {
-    pub fn to_bytes(&self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, VMError> {
+        let max_stack = match self.max_stack {
+            Some(MaxStack::Bounded(n)) => n as u64,
+            Some(MaxStack::Unbounded) => MAX_STACK_UNBOUNDED,
+            None => MAX_STACK_UNKNOWN,
+        };
+
         let mut bytes = Vec::new();
-        self.insts
+        bytes.extend(HA_MAGIC);
+        bytes.extend(HA_VERSION.to_le_bytes());
+        bytes.extend([0u8; 2]);
+        bytes.extend(max_stack.to_le_bytes());
+
+        let mut code = Vec::new();
+        for inst in &self.insts {
+            inst.encode(&mut code)?;
+        }
+        write_section(&mut bytes, SECTION_CODE, &code);
+
+        if !self.labels.is_empty() {
+            write_section(&mut bytes, SECTION_LABELS, &encode_labels(&self.labels));
+        }
+        if !self.data.is_empty() {
+            write_section(&mut bytes, SECTION_DATA, &self.data);
+        }
+        if !self.data_labels.is_empty() {
+            write_section(
+                &mut bytes,
+                SECTION_DATA_LABELS,
+                &encode_labels(&self.data_labels),
+            );
+        }
+        if !self.strings.is_empty() {
+            write_section(&mut bytes, SECTION_STRINGS, &encode_strings(&self.strings));
+        }
+        if !self.constants.is_empty() {
+            write_section(
+                &mut bytes,
+                SECTION_CONSTANTS,
+                &encode_constants(&self.constants),
+            );
+        }
+        if !self.globals.is_empty() {
+            write_section(
+                &mut bytes,
+                SECTION_GLOBALS,
+                &encode_constants(&self.globals),
+            );
+        }
+        if !self.global_names.is_empty() {
+            write_section(
+                &mut bytes,
+                SECTION_GLOBAL_NAMES,
+                &encode_labels(&self.global_names),
+            );
+        }
+
+        Ok(bytes)
+    }
+
+    pub fn global_name(&self, slot: usize) -> Option<&str> {
+        self.global_names
             .iter()
-            .for_each(|inst| bytes.extend(inst.to_bytes()));
+            .find(|(at, _)| *at == slot)
+            .map(|(_, name)| name.as_str())
+    }
 
-        bytes
+    // Innermost label at or before ip
+    pub fn label_at(&self, ip: usize) -> Option<&str> {
+        self.labels
+            .iter()
+            .rev()
+            .find(|(label_ip, _)| *label_ip <= ip)
+            .map(|(_, label)| label.as_str())
     }
 
-    pub fn from_bytes(bytes: &Vec<u8>) -> Result<Self, VMError> {
-        assert!(bytes.len() % 16 == 0);
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VMError> {
+        if bytes.len() < HA_HEADER_SIZE || !bytes.starts_with(HA_MAGIC) {
+            return Err(VMError::ParseLeBytesFail);
+        }
 
-        let insts = bytes
-            .chunks_exact(16)
-            .map(|chunk| {
-                let mut inst_bytes: [u8; 16] =
-                    chunk.try_into().map_err(|_| VMError::ParseLeBytesFail)?;
-                Ok(Inst::from_bytes(&mut inst_bytes).map_err(|_| VMError::ParseLeBytesFail)?)
-            })
-            .collect::<Result<Vec<Inst>, VMError>>()?;
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != HA_VERSION {
+            return Err(VMError::UnsupportedVersion { version });
+        }
+
+        let mut program = Self::default();
+        let mut rest = &bytes[HA_HEADER_SIZE..];
+        while !rest.is_empty() {
+            if rest.len() < 8 {
+                return Err(VMError::ParseLeBytesFail);
+            }
+            let kind = u32::from_le_bytes(rest[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(rest[4..8].try_into().unwrap()) as usize;
+            let payload = rest.get(8..8 + len).ok_or(VMError::ParseLeBytesFail)?;
 
-        Ok(Self { insts })
+            // Unknown sections are skipped, so older readers cope with newer files
+            match kind {
+                SECTION_CODE => program.insts = decode_insts(payload)?,
+                SECTION_LABELS => program.labels = decode_labels(payload)?,
+                SECTION_DATA => program.data = payload.to_vec(),
+                SECTION_DATA_LABELS => program.data_labels = decode_labels(payload)?,
+                SECTION_STRINGS => program.strings = decode_strings(payload)?,
+                SECTION_CONSTANTS => program.constants = decode_constants(payload)?,
+                SECTION_GLOBALS => program.globals = decode_constants(payload)?,
+                SECTION_GLOBAL_NAMES => program.global_names = decode_labels(payload)?,
+                _ => {}
+            }
+            rest = &rest[8 + len..];
+        }
+
+        // The header only records what the assembler worked out. Edited, it
+        // could size the stack too small for a valid program, so loading
+        // works it out again instead of trusting it.
+        program.max_stack = Some(analyze_stack(&program, &Cfg::build(&program)).max);
+        Ok(program)
     }
 
-    pub fn from_hasm(asm: &String) -> Result<Self, VMError> {
+    pub fn from_hasm(asm: &str) -> Result<Self, VMError> {
         let mut tc = TranslationContext::default();
         let mut program_size_t: u16 = 0;
 
+        // Directives only lay out data and globals, so they can come before
+        // or after the code using them
+        let mut data = Vec::new();
+        let mut data_labels = Vec::new();
+        let mut globals = Vec::new();
+        let mut global_names = Vec::new();
+        asm.split("\n")
+            .map(str::trim)
+            .filter(|line| line.starts_with('.') && parse_local(line).is_none())
+            .try_for_each(|line| {
+                let invalid = || VMError::InvalidAsmInst {
+                    inst: line.to_string(),
+                };
+                if let Some((name, init)) = parse_global(line) {
+                    let init = match init {
+                        Some(init) => Word::parse_literal(init).ok_or_else(invalid)?,
+                        None => Word::i64(0),
+                    };
+                    if tc.globals.contains_key(name) {
+                        return Err(invalid());
+                    }
+                    tc.globals.insert(name.to_string(), globals.len() as u64);
+                    global_names.push((globals.len(), name.to_string()));
+                    globals.push(init);
+                    return Ok(());
+                }
+
+                let (name, bytes) = parse_directive(line).ok_or_else(invalid)?;
+                tc.data_symbols.insert(name.to_string(), data.len() as u64);
+                data_labels.push((data.len(), name.to_string()));
+                data.extend(bytes);
+                Ok::<(), VMError>(())
+            })?;
+
         let asm_insts: Vec<&str> = asm
             .split("\n")
-            .filter(|inst| !inst.trim().is_empty())
+            .filter(|inst| {
+                let inst = inst.trim();
+                !inst.is_empty() && (!inst.starts_with('.') || parse_local(inst).is_some())
+            })
             .collect();
 
         let mut insts = asm_insts
             .into_iter()
             .filter_map(|asm_inst| {
+                // .local lines name the locals of the code after them, in order
+                if let Some(name) = parse_local(asm_inst.trim()) {
+                    return tc.declare_local(name).is_none().then(|| {
+                        Err(VMError::InvalidAsmInst {
+                            inst: asm_inst.trim().to_string(),
+                        })
+                    });
+                }
+
+                // push "a literal", which may hold spaces and #
+                let quote = asm_inst
+                    .find('"')
+                    .filter(|at| asm_inst.find('#').is_none_or(|hash| hash > *at));
+                let (asm_inst, literal) = match quote {
+                    Some(at) => match parse_string(&asm_inst[at..]) {
+                        Some((literal, rest))
+                            if rest.trim().is_empty() || rest.trim().starts_with('#') =>
+                        {
+                            (&asm_inst[..at], Some(literal))
+                        }
+                        _ => {
+                            return Some(Err(VMError::InvalidAsmInst {
+                                inst: asm_inst.trim().to_string(),
+                            }))
+                        }
+                    },
+                    None => (asm_inst, None),
+                };
+
                 // \tpush 3 # why not push 4?
                 // push 3 # why not push 4?
                 // ["push", "3", "", "#", "why", "not", "push", "4", ""]
@@ -77,13 +316,25 @@ impl Program {
 
                 let interpret_hasm =
                     |inst: Vec<&str>, tc: &mut TranslationContext, program_size_t: &mut u16| {
-                        //
-                        let inst_str = *INST_TRANSLATE.extract_key(&inst[0]);
+                        let invalid = || VMError::InvalidAsmInst {
+                            inst: inst.join(" "),
+                        };
+                        if let Some(literal) = literal {
+                            if inst != ["push"] {
+                                return Err(invalid());
+                            }
+                            *program_size_t += 1;
+                            return Ok(Inst::InstPushs(Word::u64(tc.intern(literal))));
+                        }
+                        let inst_str = *INST_TRANSLATE.get_key(&inst[0]).ok_or_else(invalid)?;
                         let maybe_operand = inst.get(1).map(Deref::deref);
-                        let inst: Inst = Inst::from_str(inst_str).unwrap();
-                        let inst = inst.resolve_operand(maybe_operand, tc, program_size_t);
+                        let asm_inst: Inst = Inst::from_str(inst_str).map_err(|_| invalid())?;
+                        let asm_inst = asm_inst
+                            .resolve_operand(maybe_operand, tc, program_size_t)
+                            .ok_or_else(invalid)?;
                         *program_size_t += 1;
-                        Ok(inst)
+                        tc.locals_closed = true;
+                        Ok(asm_inst)
                     };
 
                 if inst.first()?.ends_with(":") {
@@ -108,39 +359,368 @@ impl Program {
             .hash_map
             .into_iter()
             .try_for_each(|(inst_index, label)| {
-                if let Inst::InstJmp(_) = &mut insts[inst_index as usize] {
+                let inst = &mut insts[inst_index as usize];
+                if let Inst::InstJmp(_)
+                | Inst::InstCall(_)
+                | Inst::InstTry(_)
+                | Inst::InstSpawn(_) = inst
+                {
                     let resolved_label = tc
                         .label_table
                         .hash_map
                         .get(&label)
                         .ok_or(VMError::ResolveLabelFail)?;
-                    insts[inst_index as usize] = Inst::InstJmp(((*resolved_label) as u64).into())
+                    let target = ((*resolved_label) as u64).into();
+                    *inst = match inst {
+                        Inst::InstCall(_) => Inst::InstCall(target),
+                        Inst::InstTry(_) => Inst::InstTry(target),
+                        Inst::InstSpawn(_) => Inst::InstSpawn(target),
+                        _ => Inst::InstJmp(target),
+                    };
                 }
 
-                Ok(())
+                Ok::<(), VMError>(())
             })?;
 
-        Ok(Self { insts })
+        let mut labels: Vec<(usize, String)> = tc
+            .label_table
+            .hash_map
+            .into_iter()
+            .map(|(label, ip)| (ip as usize, label))
+            .collect();
+        labels.sort();
+
+        Ok(Self {
+            insts,
+            max_stack: None,
+            labels,
+            data,
+            data_labels,
+            strings: tc.strings,
+            constants: Vec::new(),
+            globals,
+            global_names,
+        })
+    }
+
+    // Moves every number pushed more than once into the constant pool, one
+    // entry each, turning its pushes into pushk
+    pub fn pool_constants(&mut self) {
+        let key = |word: &Word| (word.tag(), word.to_le_bytes());
+        let mut counts: HashMap<(u8, [u8; 8]), usize> = HashMap::new();
+        self.insts.iter().for_each(|inst| {
+            if let Inst::InstPush(word) = inst {
+                *counts.entry(key(word)).or_default() += 1;
+            }
+        });
+
+        let mut indices: HashMap<(u8, [u8; 8]), u64> = self
+            .constants
+            .iter()
+            .enumerate()
+            .map(|(index, word)| (key(word), index as u64))
+            .collect();
+        for inst in self.insts.iter_mut() {
+            let Inst::InstPush(word) = inst else {
+                continue;
+            };
+            if counts[&key(word)] < 2 {
+                continue;
+            }
+            let index = *indices.entry(key(word)).or_insert_with(|| {
+                self.constants.push(*word);
+                self.constants.len() as u64 - 1
+            });
+            *inst = Inst::InstPushk(Word::u64(index));
+        }
     }
 
     pub fn to_hasm(&self) -> Vec<String> {
         self.insts
             .iter()
-            .map(|inst| {
-                let asm_inst = (*INST_TRANSLATE.extract_val(&inst.as_ref())).to_string();
-
-                if *OPERAND_REQUIRED.get(inst.as_ref()).unwrap_or(&false) {
-                    return match inst {
-                        Inst::InstPush(operand)
-                        | Inst::InstDup(operand)
-                        | Inst::InstEq(operand)
-                        | Inst::InstJmp(operand) => hasm_with_operand(asm_inst, *operand),
-                        _ => exit(2),
-                    };
+            .map(|inst| self.inst_to_hasm(inst))
+            .collect::<Vec<String>>()
+    }
+
+    // Like Inst::to_hasm, with string literals written out
+    pub fn inst_to_hasm(&self, inst: &Inst) -> String {
+        match inst {
+            Inst::InstPushs(index) => match self.strings.get(u64::from(*index) as usize) {
+                Some(literal) => format!("push \"{}\"", escape_data(literal)),
+                None => inst.to_hasm(),
+            },
+            Inst::InstPushk(index) => match self.constants.get(u64::from(*index) as usize) {
+                Some(word) => Inst::InstPush(*word).to_hasm(),
+                None => inst.to_hasm(),
+            },
+            Inst::InstGload(slot) | Inst::InstGstore(slot) => {
+                match self.global_name(u64::from(*slot) as usize) {
+                    Some(name) => {
+                        format!("{} {}", INST_TRANSLATE.extract_val(&inst.as_ref()), name)
+                    }
+                    None => inst.to_hasm(),
                 }
+            }
+            _ => inst.to_hasm(),
+        }
+    }
+}
 
-                asm_inst
-            })
-            .collect::<Vec<String>>()
+fn write_section(bytes: &mut Vec<u8>, kind: u32, payload: &[u8]) {
+    bytes.extend(kind.to_le_bytes());
+    bytes.extend((payload.len() as u32).to_le_bytes());
+    bytes.extend(payload);
+}
+
+// .data name "raw bytes", .string name "text" (u32 length first), .space name size
+fn parse_directive(line: &str) -> Option<(&str, Vec<u8>)> {
+    let (directive, rest) = line.split_once(char::is_whitespace)?;
+    let (name, rest) = rest.trim_start().split_once(char::is_whitespace)?;
+    let rest = rest.trim_start();
+
+    let (bytes, rest) = match directive {
+        ".data" => parse_string(rest)?,
+        ".string" => {
+            let (text, rest) = parse_string(rest)?;
+            let mut bytes = (u32::try_from(text.len()).ok()?).to_le_bytes().to_vec();
+            bytes.extend(text);
+            (bytes, rest)
+        }
+        ".space" => {
+            let (size, rest) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            (vec![0u8; size.parse().ok()?], rest)
+        }
+        _ => return None,
+    };
+
+    let rest = rest.trim();
+    (rest.is_empty() || rest.starts_with('#')).then_some((name, bytes))
+}
+
+// .global name, or .global name = init, None for any other line
+fn parse_global(line: &str) -> Option<(&str, Option<&str>)> {
+    let line = line.split_once('#').map_or(line, |(code, _)| code);
+    let rest = line.strip_prefix(".global")?;
+    if !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let (name, init) = match rest.split_once('=') {
+        Some((name, init)) => (name.trim(), Some(init.trim())),
+        None => (rest.trim(), None),
+    };
+    let single = |word: &str| !word.is_empty() && !word.contains(char::is_whitespace);
+    (single(name) && init.is_none_or(single)).then_some((name, init))
+}
+
+// .local name, None for any other line
+fn parse_local(line: &str) -> Option<&str> {
+    let (directive, rest) = line.split_once(char::is_whitespace)?;
+    let (name, rest) = rest
+        .trim_start()
+        .split_once(char::is_whitespace)
+        .unwrap_or((rest.trim_start(), ""));
+    let rest = rest.trim();
+    (directive == ".local" && !name.is_empty() && (rest.is_empty() || rest.starts_with('#')))
+        .then_some(name)
+}
+
+// Quoted literal with \n \r \t \0 \\ \" and \xNN escapes, plus whatever follows it
+fn parse_string(s: &str) -> Option<(Vec<u8>, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut bytes = Vec::new();
+
+    while let Some((at, c)) = chars.next() {
+        match c {
+            '"' => return Some((bytes, &s[at + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => bytes.push(b'\n'),
+                'r' => bytes.push(b'\r'),
+                't' => bytes.push(b'\t'),
+                '0' => bytes.push(0),
+                '\\' => bytes.push(b'\\'),
+                '"' => bytes.push(b'"'),
+                'x' => {
+                    let hex: String = [chars.next()?.1, chars.next()?.1].iter().collect();
+                    bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+                }
+                _ => return None,
+            },
+            c => bytes.extend(c.to_string().as_bytes()),
+        }
+    }
+
+    None
+}
+
+fn encode_labels(labels: &[(usize, String)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    labels.iter().for_each(|(at, label)| {
+        bytes.extend((*at as u64).to_le_bytes());
+        bytes.extend((label.len() as u32).to_le_bytes());
+        bytes.extend(label.as_bytes());
+    });
+    bytes
+}
+
+fn encode_strings(strings: &[Vec<u8>]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    strings.iter().for_each(|string| {
+        bytes.extend((string.len() as u32).to_le_bytes());
+        bytes.extend(string);
+    });
+    bytes
+}
+
+fn decode_strings(mut bytes: &[u8]) -> Result<Vec<Vec<u8>>, VMError> {
+    let mut strings = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 4 {
+            return Err(VMError::ParseLeBytesFail);
+        }
+        let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+        let string = bytes.get(4..4 + len).ok_or(VMError::ParseLeBytesFail)?;
+        strings.push(string.to_vec());
+        bytes = &bytes[4 + len..];
+    }
+
+    Ok(strings)
+}
+
+fn encode_constants(constants: &[Word]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    constants.iter().for_each(|word| {
+        bytes.push(word.tag());
+        bytes.extend(word.to_le_bytes());
+    });
+    bytes
+}
+
+fn decode_constants(bytes: &[u8]) -> Result<Vec<Word>, VMError> {
+    if !bytes.len().is_multiple_of(9) {
+        return Err(VMError::ParseLeBytesFail);
+    }
+
+    bytes
+        .chunks_exact(9)
+        .map(|chunk| {
+            Word::from_tagged(chunk[0], chunk[1..9].try_into().unwrap())
+                .ok_or(VMError::ParseLeBytesFail)
+        })
+        .collect()
+}
+
+fn decode_insts(mut bytes: &[u8]) -> Result<Vec<Inst>, VMError> {
+    let mut insts = Vec::new();
+    while !bytes.is_empty() {
+        insts.push(Inst::decode(&mut bytes).map_err(|_| VMError::ParseLeBytesFail)?);
+    }
+    Ok(insts)
+}
+
+fn decode_labels(mut bytes: &[u8]) -> Result<Vec<(usize, String)>, VMError> {
+    let mut labels = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 12 {
+            return Err(VMError::ParseLeBytesFail);
+        }
+        let ip = u64::from_le_bytes(bytes[0..8].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+        let name = bytes.get(12..12 + len).ok_or(VMError::ParseLeBytesFail)?;
+
+        labels.push((
+            ip,
+            String::from_utf8(name.to_vec()).map_err(|_| VMError::ParseLeBytesFail)?,
+        ));
+        bytes = &bytes[12 + len..];
+    }
+
+    Ok(labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{finish, vm_with};
+
+    #[test]
+    fn an_edited_max_stack_does_not_size_the_stack() {
+        let mut program = Program::from_hasm("push 1\npush 2\npush 3\naddi\naddi\nhalt").unwrap();
+        program.max_stack = Some(MaxStack::Bounded(1));
+        let bytes = program.to_bytes().unwrap();
+
+        let loaded = Program::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.max_stack, Some(MaxStack::Bounded(3)));
+        assert_eq!(finish(vm_with(loaded, "")).unwrap().stack, [Word::i64(6)]);
+    }
+
+    #[test]
+    fn pooled_constants_run_the_same_from_a_smaller_file() {
+        let hasm =
+            "push 1.25\npush 1.25\naddf\npush 1.25\naddf\npush 70000\npush 70000\naddi\nhalt";
+        let plain = Program::from_hasm(hasm).unwrap();
+        let mut pooled = plain.clone();
+        pooled.pool_constants();
+        assert_eq!(pooled.constants.len(), 2);
+
+        let plain = plain.to_bytes().unwrap();
+        let pooled = pooled.to_bytes().unwrap();
+        assert!(
+            pooled.len() < plain.len(),
+            "{} vs {}",
+            pooled.len(),
+            plain.len()
+        );
+
+        let run = |bytes: &[u8]| finish(vm_with(Program::from_bytes(bytes).unwrap(), "")).unwrap();
+        assert_eq!(run(&pooled), run(&plain));
+        assert_eq!(run(&pooled).stack, [Word::f64(3.75), Word::i64(140000)]);
+    }
+
+    #[test]
+    fn globals_keep_their_slots_initial_values_and_names() {
+        let hasm = "gload counter
+            push 2
+            addi
+            gstore counter
+            halt
+            .global counter = 40
+            .global rate = 1.5 # after the code is fine too
+            .global unset";
+        let program = Program::from_hasm(hasm).unwrap();
+        let program = Program::from_bytes(&program.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            program.globals,
+            [Word::i64(40), Word::f64(1.5), Word::i64(0)]
+        );
+        assert_eq!(program.global_name(1), Some("rate"));
+        assert_eq!(program.global_name(3), None);
+        assert_eq!(program.inst_to_hasm(&program.insts[3]), "gstore counter");
+
+        let mut vm = vm_with(program, "");
+        vm.run(Some(16)).unwrap();
+        assert_eq!(vm.globals()[0], Word::i64(42));
+    }
+
+    #[test]
+    fn bad_globals_are_rejected() {
+        let bad = [
+            ".global a\n.global a",
+            ".global a b",
+            ".global a =",
+            ".global a = one",
+            ".globala",
+            "gload missing",
+        ];
+        for hasm in bad {
+            assert!(
+                matches!(
+                    Program::from_hasm(&format!("{}\nhalt", hasm)),
+                    Err(VMError::InvalidAsmInst { .. } | VMError::ResolveLabelFail)
+                ),
+                "{}",
+                hasm
+            );
+        }
     }
 }
@@ -0,0 +1,253 @@
+use std::{fs, io::Write};
+
+use crate::{
+    console::{Console, StdConsole},
+    program::Program,
+    snapshot::Snapshot,
+    VMError, VM,
+};
+
+// Keeps a runaway `jmp` from hanging the session
+const STEPS_PER_LINE: usize = 10_000;
+
+const HELP: &str = "hasm lines are assembled and run as they are typed
+  name:          define a label at the next instruction
+  :label name    same as name:
+  :undo          go back to before the last instruction
+  :insts         show the assembled program
+  :stack         show the stack
+  :save path     write the session as a .hasm file
+  :reset         start over
+  :help          this message
+  :quit          leave";
+
+#[derive(Debug, Default)]
+pub struct Repl {
+    source: Vec<String>,
+    vm: VM,
+    // The session as it was before each line that ran, newest last
+    undo: Vec<(Vec<String>, Snapshot)>,
+}
+
+fn is_label(line: &str) -> bool {
+    line.split_whitespace()
+        .next()
+        .is_some_and(|first| first.ends_with(':'))
+        && line.split_whitespace().count() == 1
+}
+
+impl Repl {
+    // Runs `line`, leaving the session as it was if that fails
+    fn exec(&mut self, line: &str) -> Result<usize, VMError> {
+        let before = (self.source.clone(), self.vm.snapshot());
+        match self.exec_line(line) {
+            Ok(steps) => {
+                self.undo.push(before);
+                Ok(steps)
+            }
+            Err(err) => {
+                self.rewind(before)?;
+                Err(err)
+            }
+        }
+    }
+
+    // Assembles the session plus `line` and runs whatever became reachable
+    fn exec_line(&mut self, line: &str) -> Result<usize, VMError> {
+        let mut source = self.source.clone();
+        source.push(line.to_string());
+        let program = Program::from_hasm(&source.join("\n"))?;
+
+        self.source = source;
+        self.vm.replace_program(program);
+
+        let mut steps = 0;
+        while !self.vm.halted() && self.vm.ip() < self.vm.program().insts.len() {
+            if steps == STEPS_PER_LINE {
+                break;
+            }
+            self.vm.step()?;
+            steps += 1;
+        }
+
+        Ok(steps)
+    }
+
+    // Starts over, still reading from the session's console
+    fn reset(&mut self) {
+        let console = self.vm.set_console(Box::new(StdConsole));
+        *self = Self::default();
+        self.vm.set_console(console);
+    }
+
+    // Puts the session back without running anything again, so output and
+    // reads are not repeated
+    fn rewind(&mut self, (source, snapshot): (Vec<String>, Snapshot)) -> Result<(), VMError> {
+        self.source = source;
+        self.vm.restore(snapshot)
+    }
+
+    fn undo(&mut self) -> Result<(), VMError> {
+        match self.undo.pop() {
+            Some(before) => self.rewind(before),
+            None => {
+                self.reset();
+                Ok(())
+            }
+        }
+    }
+
+    fn show_stack(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let stack: Vec<String> = self.vm.stack().iter().map(|w| format!("{:?}", w)).collect();
+        writeln!(out, "[{}]", stack.join(", "))
+    }
+
+    fn command(
+        &mut self,
+        cmd: &str,
+        arg: Option<&str>,
+        out: &mut impl Write,
+    ) -> Result<bool, VMError> {
+        match (cmd, arg) {
+            (":quit" | ":q", _) => return Ok(false),
+            (":help" | ":h", _) => writeln!(out, "{}", HELP)?,
+            (":stack", _) => self.show_stack(out)?,
+            (":label", Some(name)) => {
+                self.source.push(format!("{}:", name));
+                writeln!(out, "{} = {}", name, self.vm.program().insts.len())?;
+            }
+            (":undo", _) => {
+                self.undo()?;
+                self.show_stack(out)?;
+            }
+            (":insts", _) => {
+                let program = self.vm.program();
+                for (ip, inst) in program.insts.iter().enumerate() {
+                    let marker = if ip == self.vm.ip() { ">" } else { " " };
+                    writeln!(
+                        out,
+                        "{} {:>4}: {:<16} {:?}",
+                        marker,
+                        ip,
+                        program.inst_to_hasm(inst),
+                        inst
+                    )?;
+                }
+            }
+            (":save", Some(path)) => {
+                fs::write(path, self.source.join("\n") + "\n")?;
+                writeln!(out, "saved {} lines to {}", self.source.len(), path)?;
+            }
+            (":reset", _) => self.reset(),
+            _ => writeln!(out, "unknown command {}, try :help", cmd)?,
+        }
+
+        Ok(true)
+    }
+
+    // Typed lines and whatever the program reads both come from input
+    pub fn run(
+        &mut self,
+        mut input: impl Console + Clone + 'static,
+        mut out: impl Write,
+    ) -> Result<(), VMError> {
+        self.vm.set_console(Box::new(input.clone()));
+        writeln!(out, "haesuk repl, :help for commands")?;
+        write!(out, "> ")?;
+        out.flush()?;
+
+        while let Some(line) = input.read_line()? {
+            let line = line.trim();
+
+            if line.starts_with(':') {
+                let mut words = line.split_whitespace();
+                let (cmd, arg) = (words.next().unwrap_or_default(), words.next());
+                if !self.command(cmd, arg, &mut out)? {
+                    return Ok(());
+                }
+            } else if is_label(line) {
+                self.source.push(line.to_string());
+                writeln!(
+                    out,
+                    "{} = {}",
+                    line.trim_end_matches(':'),
+                    self.vm.program().insts.len()
+                )?;
+            } else if !line.is_empty() && self.vm.halted() {
+                writeln!(out, "halted, :undo to go back")?;
+            } else if !line.is_empty() {
+                match self.exec(line) {
+                    Ok(STEPS_PER_LINE) => {
+                        writeln!(out, "stopped after {} steps", STEPS_PER_LINE)?;
+                        self.show_stack(&mut out)?;
+                    }
+                    Ok(_) => {
+                        self.show_stack(&mut out)?;
+                        if self.vm.halted() {
+                            writeln!(out, "halted, :undo to go back")?;
+                        }
+                    }
+                    Err(err) => {
+                        writeln!(out, "ERROR: {}", err)?;
+                    }
+                }
+            }
+
+            write!(out, "> ")?;
+            out.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        console::{BufferConsole, SharedConsole},
+        word::Word,
+    };
+
+    fn captured(repl: &Repl) -> &[u8] {
+        repl.vm.console().captured().unwrap()
+    }
+
+    #[test]
+    fn undo_does_not_run_earlier_lines_again() {
+        let mut out = Vec::new();
+        let mut repl = Repl::default();
+        repl.vm.set_console(Box::new(BufferConsole::new("5")));
+        let input = "push 65\nprintc\nreadi\npush 1\n:undo\n:undo\n:quit\n";
+        input
+            .lines()
+            .try_for_each(|line| {
+                if line.starts_with(':') {
+                    repl.command(line, None, &mut out).map(|_| ())
+                } else {
+                    repl.exec(line).map(|_| ())
+                }
+            })
+            .unwrap();
+
+        assert_eq!(captured(&repl), b"A");
+        assert_eq!(repl.vm.stack(), [] as [Word; 0]);
+        assert_eq!(repl.source, ["push 65", "printc"]);
+    }
+
+    #[test]
+    fn programs_read_from_the_session_input() {
+        // Used to deadlock, the session and readi each locking stdin
+        let input = SharedConsole::new(&b"readi\n42\npush 1\naddi\n:quit\n"[..]);
+        let mut out = Vec::new();
+        Repl::default().run(input, &mut out).unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("> [i64(42)]\n"), "{}", out);
+        assert!(
+            out.contains("> [i64(42), i64(1)]\n> [i64(43)]\n"),
+            "{}",
+            out
+        );
+    }
+}
@@ -0,0 +1,499 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::{
+    fiber::{Fiber, Frame, Handler, Scheduler},
+    gc::GcStats,
+    program::Program,
+    word::Word,
+    VMError,
+};
+
+// Snapshot file: [0..4] magic, [4..6] version, [6..8] reserved, then front
+// to back, every number a u64 and every word a tag + 8 bytes:
+// ip, fuel, halt, whether there is an exit status and the status as an i64,
+// the gc stats (collections, freed objects, freed bytes, live objects, live
+// bytes), the stack limit, then the stack, memory, frames (return ip, frame pointer,
+// local count), locals, globals and active tries (handler ip, stack size,
+// frame count) of the running fiber, each as a count and entries.
+// Then the running fiber's id, the next fiber id, the parked fiber count and
+// each parked fiber: id, ip, blocked, then its stack, frames, locals and
+// handlers the same way. Then the channel count and each channel as a word
+// count and words, the result count and each result as a fiber id and a word,
+// and last the program's length and .ha bytes.
+//
+// Files the program has open live in the host, so they cannot be written out
+// and to_bytes refuses while any are. Capabilities are left out on purpose: a
+// restored VM keeps its own grants, so resuming never allows a program more
+// than whoever resumes it does.
+pub const SNAPSHOT_MAGIC: &[u8; 4] = b"HSNP";
+pub const SNAPSHOT_VERSION: u16 = 2;
+const SNAPSHOT_HEADER_SIZE: usize = 8;
+const WORD_SIZE: usize = 9;
+const FRAME_SIZE: usize = 24;
+const HANDLER_SIZE: usize = 24;
+// id, ip, blocked and four empty counts
+const FIBER_MIN_SIZE: usize = 56;
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub ip: usize,
+    pub fuel: u64,
+    pub halt: bool,
+    pub exit_status: Option<i64>,
+    pub gc_stats: GcStats,
+    // Files open in the host when this was taken, never saved
+    pub open_files: usize,
+    pub stack_limit: usize,
+    pub stack: Vec<Word>,
+    pub memory: Vec<u8>,
+    // Root frame first
+    pub frames: Vec<Frame>,
+    pub locals: Vec<Word>,
+    pub globals: Vec<Word>,
+    // Innermost try last
+    pub handlers: Vec<Handler>,
+    // The fields above belong to the running fiber, the rest are parked here
+    pub fiber_id: u64,
+    pub scheduler: Scheduler,
+    pub program: Arc<Program>,
+}
+
+fn encode_word(bytes: &mut Vec<u8>, word: &Word) {
+    bytes.push(word.tag());
+    bytes.extend(word.to_le_bytes());
+}
+
+fn encode_words(bytes: &mut Vec<u8>, words: &[Word]) {
+    bytes.extend((words.len() as u64).to_le_bytes());
+    words.iter().for_each(|word| encode_word(bytes, word));
+}
+
+fn encode_frames(bytes: &mut Vec<u8>, frames: &[Frame]) {
+    bytes.extend((frames.len() as u64).to_le_bytes());
+    frames.iter().for_each(|frame| {
+        bytes.extend((frame.return_ip as u64).to_le_bytes());
+        bytes.extend((frame.fp as u64).to_le_bytes());
+        bytes.extend((frame.locals as u64).to_le_bytes());
+    });
+}
+
+fn encode_handlers(bytes: &mut Vec<u8>, handlers: &[Handler]) {
+    bytes.extend((handlers.len() as u64).to_le_bytes());
+    handlers.iter().for_each(|handler| {
+        bytes.extend((handler.ip as u64).to_le_bytes());
+        bytes.extend((handler.stack_size as u64).to_le_bytes());
+        bytes.extend((handler.frame_count as u64).to_le_bytes());
+    });
+}
+
+// Frames must start at the root and cover their locals, tries must belong to
+// one of the frames
+fn valid_calls(frames: &[Frame], locals: usize, handlers: &[Handler]) -> bool {
+    !frames.is_empty()
+        && frames.iter().all(|frame| {
+            frame
+                .fp
+                .checked_add(frame.locals)
+                .is_some_and(|end| end <= locals)
+        })
+        && handlers
+            .iter()
+            .all(|handler| handler.frame_count > 0 && handler.frame_count <= frames.len())
+}
+
+// Reads a snapshot front to back, counts first
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u64(&mut self) -> Result<u64, VMError> {
+        let le: [u8; 8] = self
+            .bytes
+            .get(self.at..self.at + 8)
+            .ok_or(VMError::ParseLeBytesFail)?
+            .try_into()
+            .unwrap();
+        self.at += 8;
+        Ok(u64::from_le_bytes(le))
+    }
+
+    // A count, checked against what is left so a bad one cannot allocate much
+    fn count(&mut self, size: usize) -> Result<usize, VMError> {
+        let count = self.u64()? as usize;
+        count
+            .checked_mul(size)
+            .filter(|len| *len <= self.bytes.len().saturating_sub(self.at))
+            .ok_or(VMError::ParseLeBytesFail)?;
+        Ok(count)
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], VMError> {
+        let len = self.count(1)?;
+        let bytes = &self.bytes[self.at..self.at + len];
+        self.at += len;
+        Ok(bytes)
+    }
+
+    fn word(&mut self) -> Result<Word, VMError> {
+        let word = self
+            .bytes
+            .get(self.at..self.at + WORD_SIZE)
+            .ok_or(VMError::ParseLeBytesFail)?;
+        self.at += WORD_SIZE;
+        Word::from_tagged(word[0], word[1..].try_into().unwrap()).ok_or(VMError::ParseLeBytesFail)
+    }
+
+    fn words(&mut self) -> Result<Vec<Word>, VMError> {
+        let count = self.count(WORD_SIZE)?;
+        (0..count).map(|_| self.word()).collect()
+    }
+
+    fn frames(&mut self) -> Result<Vec<Frame>, VMError> {
+        let count = self.count(FRAME_SIZE)?;
+        (0..count)
+            .map(|_| {
+                Ok(Frame {
+                    return_ip: self.u64()? as usize,
+                    fp: self.u64()? as usize,
+                    locals: self.u64()? as usize,
+                })
+            })
+            .collect()
+    }
+
+    fn handlers(&mut self) -> Result<Vec<Handler>, VMError> {
+        let count = self.count(HANDLER_SIZE)?;
+        (0..count)
+            .map(|_| {
+                Ok(Handler {
+                    ip: self.u64()? as usize,
+                    stack_size: self.u64()? as usize,
+                    frame_count: self.u64()? as usize,
+                })
+            })
+            .collect()
+    }
+
+    fn fiber(&mut self, stack_limit: usize) -> Result<Fiber, VMError> {
+        let id = self.u64()?;
+        let ip = self.u64()? as usize;
+        let blocked = self.u64()? != 0;
+        let stack = self.words()?;
+        let frames = self.frames()?;
+        let locals = self.words()?;
+        let handlers = self.handlers()?;
+        if stack.len() > stack_limit || !valid_calls(&frames, locals.len(), &handlers) {
+            return Err(VMError::ParseLeBytesFail);
+        }
+
+        let mut fiber = Fiber::new(id, ip, stack_limit);
+        fiber.stack_size = stack.len();
+        fiber.stack = stack;
+        fiber.frame_count = frames.len();
+        fiber.frames = frames;
+        fiber.locals_size = locals.len();
+        fiber.locals = locals;
+        fiber.handler_count = handlers.len();
+        fiber.handlers = handlers;
+        fiber.blocked = blocked;
+        Ok(fiber)
+    }
+
+    fn scheduler(&mut self, stack_limit: usize) -> Result<Scheduler, VMError> {
+        let next_id = self.u64()?;
+        let fiber_count = self.count(FIBER_MIN_SIZE)?;
+        let fibers = (0..fiber_count)
+            .map(|_| self.fiber(stack_limit))
+            .collect::<Result<VecDeque<Fiber>, VMError>>()?;
+        let channel_count = self.count(8)?;
+        let channels = (0..channel_count)
+            .map(|_| Ok(self.words()?.into()))
+            .collect::<Result<Vec<VecDeque<Word>>, VMError>>()?;
+        let result_count = self.count(8 + WORD_SIZE)?;
+        let results = (0..result_count)
+            .map(|_| Ok((self.u64()?, self.word()?)))
+            .collect::<Result<_, VMError>>()?;
+
+        Ok(Scheduler {
+            fibers,
+            channels,
+            results,
+            next_id,
+        })
+    }
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, VMError> {
+        if self.open_files > 0 {
+            return Err(VMError::FilesOpen {
+                count: self.open_files,
+            });
+        }
+
+        let mut bytes = Vec::new();
+        bytes.extend(SNAPSHOT_MAGIC);
+        bytes.extend(SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend([0u8; 2]);
+        bytes.extend((self.ip as u64).to_le_bytes());
+        bytes.extend(self.fuel.to_le_bytes());
+        bytes.extend((self.halt as u64).to_le_bytes());
+        bytes.extend((self.exit_status.is_some() as u64).to_le_bytes());
+        bytes.extend(self.exit_status.unwrap_or_default().to_le_bytes());
+        let stats = &self.gc_stats;
+        [
+            stats.collections,
+            stats.freed_objects,
+            stats.freed_bytes,
+            stats.live_objects,
+            stats.live_bytes,
+        ]
+        .iter()
+        .for_each(|n| bytes.extend(n.to_le_bytes()));
+        bytes.extend((self.stack_limit as u64).to_le_bytes());
+
+        encode_words(&mut bytes, &self.stack);
+        bytes.extend((self.memory.len() as u64).to_le_bytes());
+        bytes.extend(&self.memory);
+        encode_frames(&mut bytes, &self.frames);
+        encode_words(&mut bytes, &self.locals);
+        encode_words(&mut bytes, &self.globals);
+        encode_handlers(&mut bytes, &self.handlers);
+
+        bytes.extend(self.fiber_id.to_le_bytes());
+        bytes.extend(self.scheduler.next_id.to_le_bytes());
+        bytes.extend((self.scheduler.fibers.len() as u64).to_le_bytes());
+        self.scheduler.fibers.iter().for_each(|fiber| {
+            bytes.extend(fiber.id.to_le_bytes());
+            bytes.extend((fiber.ip as u64).to_le_bytes());
+            bytes.extend((fiber.blocked as u64).to_le_bytes());
+            encode_words(&mut bytes, &fiber.stack[..fiber.stack_size]);
+            encode_frames(&mut bytes, &fiber.frames[..fiber.frame_count]);
+            encode_words(&mut bytes, &fiber.locals[..fiber.locals_size]);
+            encode_handlers(&mut bytes, &fiber.handlers[..fiber.handler_count]);
+        });
+        bytes.extend((self.scheduler.channels.len() as u64).to_le_bytes());
+        self.scheduler.channels.iter().for_each(|channel| {
+            bytes.extend((channel.len() as u64).to_le_bytes());
+            channel
+                .iter()
+                .for_each(|word| encode_word(&mut bytes, word));
+        });
+        bytes.extend((self.scheduler.results.len() as u64).to_le_bytes());
+        self.scheduler.results.iter().for_each(|(id, word)| {
+            bytes.extend(id.to_le_bytes());
+            encode_word(&mut bytes, word);
+        });
+
+        let program = self.program.to_bytes()?;
+        bytes.extend((program.len() as u64).to_le_bytes());
+        bytes.extend(program);
+
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VMError> {
+        if !bytes.starts_with(SNAPSHOT_MAGIC) || bytes.len() < SNAPSHOT_HEADER_SIZE {
+            return Err(VMError::ParseLeBytesFail);
+        }
+
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != SNAPSHOT_VERSION {
+            return Err(VMError::UnsupportedVersion { version });
+        }
+
+        let mut reader = Reader {
+            bytes,
+            at: SNAPSHOT_HEADER_SIZE,
+        };
+        let ip = reader.u64()? as usize;
+        let fuel = reader.u64()?;
+        let halt = reader.u64()? != 0;
+        let has_exit_status = reader.u64()? != 0;
+        let exit_status = reader.u64()? as i64;
+        let gc_stats = GcStats {
+            collections: reader.u64()?,
+            freed_objects: reader.u64()?,
+            freed_bytes: reader.u64()?,
+            live_objects: reader.u64()?,
+            live_bytes: reader.u64()?,
+        };
+        let stack_limit = reader.u64()? as usize;
+
+        let stack = reader.words()?;
+        let memory = reader.bytes()?.to_vec();
+        let frames = reader.frames()?;
+        let locals = reader.words()?;
+        let globals = reader.words()?;
+        let handlers = reader.handlers()?;
+        if stack.len() > stack_limit || !valid_calls(&frames, locals.len(), &handlers) {
+            return Err(VMError::ParseLeBytesFail);
+        }
+
+        let fiber_id = reader.u64()?;
+        let scheduler = reader.scheduler(stack_limit)?;
+
+        let program = reader.bytes()?;
+        if reader.at != bytes.len() {
+            return Err(VMError::ParseLeBytesFail);
+        }
+        let program = Arc::new(Program::from_bytes(program)?);
+
+        Ok(Self {
+            ip,
+            fuel,
+            halt,
+            exit_status: has_exit_status.then_some(exit_status),
+            gc_stats,
+            open_files: 0,
+            stack_limit,
+            stack,
+            memory,
+            frames,
+            locals,
+            globals,
+            handlers,
+            fiber_id,
+            scheduler,
+            program,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        host::{Capabilities, MemoryHost},
+        testing::{finish, vm},
+    };
+    use std::env;
+
+    const HASM: &str = ".global ch
+        .data text \"hi\"
+        chan
+        gstore ch
+        push 5
+        spawn producer
+        push 20
+        call twice
+        gload ch
+        recv
+        addi
+        printi
+        join
+        halt 3
+    twice:
+        .local n
+        enter 1
+        lstore n
+        try caught
+        lload n
+        lload n
+        addi
+        yield
+        endtry
+        leave
+        ret
+    caught:
+        halt 9
+    producer:
+        gload ch
+        push 1
+        send
+        yield
+        halt";
+
+    #[test]
+    fn resumes_where_it_was_taken() {
+        let mut vm = vm(HASM);
+        (0..12).for_each(|_| vm.step().unwrap());
+        // Inside the try in twice, with the producer parked
+        let snapshot = vm.snapshot();
+        assert!(snapshot.frames.len() > 1 && !snapshot.handlers.is_empty());
+        assert!(!snapshot.scheduler.fibers.is_empty());
+
+        // Loading works out the program's max stack again, so the bytes
+        // settle after the first reload
+        let snapshot = Snapshot::from_bytes(&snapshot.to_bytes().unwrap()).unwrap();
+        let bytes = snapshot.to_bytes().unwrap();
+        let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(snapshot.to_bytes().unwrap(), bytes);
+
+        let mut resumed = self::vm(HASM);
+        resumed.restore(snapshot).unwrap();
+        assert_eq!(finish(resumed).unwrap(), finish(vm).unwrap());
+    }
+
+    #[test]
+    fn truncated_snapshots_are_rejected() {
+        let bytes = vm(HASM).snapshot().to_bytes().unwrap();
+        (0..bytes.len()).for_each(|len| assert!(Snapshot::from_bytes(&bytes[..len]).is_err()));
+
+        let mut newer = bytes.clone();
+        newer[4] += 1;
+        assert!(matches!(
+            Snapshot::from_bytes(&newer),
+            Err(VMError::UnsupportedVersion { version: 3 })
+        ));
+    }
+
+    #[test]
+    fn gc_stats_come_back() {
+        let mut vm = vm("push 4\nnew\ngc\nhalt");
+        (0..3).for_each(|_| vm.step().unwrap());
+        let bytes = vm.snapshot().to_bytes().unwrap();
+
+        let mut resumed = self::vm("halt");
+        resumed
+            .restore(Snapshot::from_bytes(&bytes).unwrap())
+            .unwrap();
+        assert_eq!(resumed.gc_stats().collections, 1);
+        assert_eq!(resumed.gc_stats(), vm.gc_stats());
+    }
+
+    #[test]
+    fn open_files_cannot_be_written_out() {
+        let mut vm = vm(".data path \"f.txt\"
+            push path
+            push 5
+            push 1
+            syscall 1
+            syscall 4
+            halt");
+        vm.grant(Capabilities {
+            fs_root: Some(env::temp_dir()),
+            ..Default::default()
+        });
+        vm.set_host(Box::new(MemoryHost::default()));
+        (0..4).for_each(|_| vm.step().unwrap());
+        assert!(matches!(
+            vm.snapshot().to_bytes(),
+            Err(VMError::FilesOpen { count: 1 })
+        ));
+
+        vm.step().unwrap();
+        assert!(vm.snapshot().to_bytes().is_ok());
+    }
+
+    #[test]
+    fn resuming_grants_nothing_new() {
+        let mut vm = vm("syscall 5\nhalt");
+        vm.grant(Capabilities {
+            clock: true,
+            ..Default::default()
+        });
+        let bytes = vm.snapshot().to_bytes().unwrap();
+
+        let mut resumed = self::vm("halt");
+        resumed
+            .restore(Snapshot::from_bytes(&bytes).unwrap())
+            .unwrap();
+        assert!(matches!(
+            finish(resumed),
+            Err(VMError::PermissionDenied { .. })
+        ));
+    }
+}
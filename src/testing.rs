@@ -0,0 +1,48 @@
+// Fixtures shared by the test modules
+use crate::{console::BufferConsole, program::Program, word::Word, VMError, VM};
+
+// Enough for every test program, and still quick to run out in a runaway loop
+pub const FUEL: u64 = 10_000;
+
+// Loaded with program, reading its console input from input
+pub fn vm_with(program: Program, input: &str) -> VM {
+    let mut vm = VM::new();
+    vm.set_console(Box::new(BufferConsole::new(input)));
+    vm.load_ha_from_memory(program).unwrap();
+    vm
+}
+
+pub fn vm(hasm: &str) -> VM {
+    vm_with(Program::from_hasm(hasm).unwrap(), "")
+}
+
+// The fault itself, without where it happened
+pub fn inner(err: VMError) -> VMError {
+    match err {
+        VMError::Runtime { error, .. } => *error,
+        err => err,
+    }
+}
+
+// What a run left behind
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+    pub stack: Vec<Word>,
+    pub output: Vec<u8>,
+    pub halted: bool,
+    pub exit_status: Option<i64>,
+}
+
+pub fn finish(mut vm: VM) -> Result<Run, VMError> {
+    vm.run(Some(FUEL)).map_err(inner)?;
+    Ok(Run {
+        stack: vm.stack().to_vec(),
+        output: vm.console().captured().unwrap_or_default().to_vec(),
+        halted: vm.halted(),
+        exit_status: vm.exit_status(),
+    })
+}
+
+pub fn run_with(hasm: &str, input: &str) -> Result<Run, VMError> {
+    finish(vm_with(Program::from_hasm(hasm)?, input))
+}
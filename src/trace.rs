@@ -0,0 +1,259 @@
+use std::{
+    fmt::Debug,
+    fs,
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+use clap::ValueEnum;
+
+use crate::{cfg::escape, inst::Inst, word::Word, VMError};
+
+// Binary trace: magic, then fixed size records
+pub const TRACE_MAGIC: &[u8; 4] = b"HTRC";
+const RECORD_SIZE: usize = 49;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum TraceFormat {
+    jsonl,
+    binary,
+}
+
+// One executed instruction, as seen right after it ran
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub ip: usize,
+    pub inst: Inst,
+    pub depth: usize,
+    pub delta: isize,
+    pub top: Option<Word>,
+    pub fuel: u64,
+    // Call sites of the calls not yet returned from when it ran, innermost
+    // first. Only filled in for tracers that want it, traces leave it out.
+    pub calls: Vec<usize>,
+}
+
+impl Step {
+    pub fn to_json(&self, n: usize) -> String {
+        let top = self
+            .top
+            .map_or("null".to_string(), |top| format!("\"{:?}\"", top));
+        format!(
+            "{{\"step\":{},\"ip\":{},\"inst\":\"{}\",\"depth\":{},\"delta\":{},\"top\":{},\"fuel\":{}}}",
+            n,
+            self.ip,
+            escape(&self.inst.to_hasm()),
+            self.depth,
+            self.delta,
+            top,
+            self.fuel
+        )
+    }
+
+    pub fn to_bytes(&self) -> Result<[u8; RECORD_SIZE], VMError> {
+        let (tag, top) = self
+            .top
+            .map_or((0u8, [0u8; 8]), |top| (top.tag(), top.to_le_bytes()));
+
+        let mut bytes = [0u8; RECORD_SIZE];
+        bytes[0..8].copy_from_slice(&(self.ip as u64).to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.fuel.to_le_bytes());
+        bytes[16..20].copy_from_slice(&(self.depth as u32).to_le_bytes());
+        bytes[20..24].copy_from_slice(&(self.delta as i32).to_le_bytes());
+        bytes[24..40].copy_from_slice(&self.inst.to_bytes()?);
+        bytes[40] = tag;
+        bytes[41..49].copy_from_slice(&top);
+
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VMError> {
+        let bytes: &[u8; RECORD_SIZE] = bytes.try_into().map_err(|_| VMError::ParseLeBytesFail)?;
+        let le8 = |at: usize| -> [u8; 8] { bytes[at..at + 8].try_into().unwrap() };
+        let le4 = |at: usize| -> [u8; 4] { bytes[at..at + 4].try_into().unwrap() };
+
+        let mut inst_bytes: [u8; 16] = bytes[24..40].try_into().unwrap();
+        let top = match bytes[40] {
+            0 => None,
+            tag => Some(Word::from_tagged(tag, le8(41)).ok_or(VMError::ParseLeBytesFail)?),
+        };
+
+        Ok(Self {
+            ip: u64::from_le_bytes(le8(0)) as usize,
+            fuel: u64::from_le_bytes(le8(8)),
+            depth: u32::from_le_bytes(le4(16)) as usize,
+            delta: i32::from_le_bytes(le4(20)) as isize,
+            inst: Inst::from_bytes(&mut inst_bytes)?,
+            top,
+            calls: Vec::new(),
+        })
+    }
+}
+
+pub trait Tracer: Debug + Send {
+    fn step(&mut self, step: &Step) -> Result<(), VMError>;
+
+    // Whether steps should come with their calls, which costs a walk of the frames
+    fn wants_calls(&self) -> bool {
+        false
+    }
+
+    fn finish(&mut self) -> Result<(), VMError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct JsonlTracer<W: Write + Debug> {
+    out: W,
+    steps: usize,
+}
+
+impl<W: Write + Debug> JsonlTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self { out, steps: 0 }
+    }
+}
+
+impl<W: Write + Debug + Send> Tracer for JsonlTracer<W> {
+    fn step(&mut self, step: &Step) -> Result<(), VMError> {
+        writeln!(self.out, "{}", step.to_json(self.steps))?;
+        self.steps += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), VMError> {
+        Ok(self.out.flush()?)
+    }
+}
+
+#[derive(Debug)]
+pub struct BinaryTracer<W: Write + Debug> {
+    out: W,
+    started: bool,
+}
+
+impl<W: Write + Debug> BinaryTracer<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            started: false,
+        }
+    }
+}
+
+impl<W: Write + Debug + Send> Tracer for BinaryTracer<W> {
+    fn step(&mut self, step: &Step) -> Result<(), VMError> {
+        if !self.started {
+            self.out.write_all(TRACE_MAGIC)?;
+            self.started = true;
+        }
+        self.out.write_all(&step.to_bytes()?)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), VMError> {
+        Ok(self.out.flush()?)
+    }
+}
+
+// Trace of either format, as JSON lines so the two can be compared
+pub fn read_trace(path: &Path) -> Result<Vec<String>, VMError> {
+    let bytes = fs::read(path)?;
+
+    if let Some(records) = bytes.strip_prefix(TRACE_MAGIC) {
+        if records.len() % RECORD_SIZE != 0 {
+            return Err(VMError::ParseLeBytesFail);
+        }
+
+        return records
+            .chunks_exact(RECORD_SIZE)
+            .enumerate()
+            .map(|(n, record)| Ok(Step::from_bytes(record)?.to_json(n)))
+            .collect();
+    }
+
+    BufReader::new(bytes.as_slice())
+        .lines()
+        .map(|line| Ok(line?))
+        .collect()
+}
+
+// Fuel depends on the limit a run was given rather than on what the program did
+fn without_fuel(line: &str) -> &str {
+    line.rsplit_once(",\"fuel\":")
+        .map_or(line, |(step, _)| step)
+}
+
+// Index of the first step where the two traces disagree, if they ever do
+pub fn diff_traces(a: &[String], b: &[String]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(a, b)| without_fuel(a) != without_fuel(b))
+        .or((a.len() != b.len()).then(|| a.len().min(b.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::vm;
+    use std::{env, fs::File, process};
+
+    // Trace of hasm run to the end, written in format to a temp file
+    fn trace(hasm: &str, format: TraceFormat) -> Vec<String> {
+        let path = env::temp_dir().join(format!("haesuk-{}-{:?}.trace", process::id(), format));
+        let out = File::create(&path).unwrap();
+        let mut vm = vm(hasm);
+        vm.set_tracer(match format {
+            TraceFormat::jsonl => Box::new(JsonlTracer::new(out)),
+            TraceFormat::binary => Box::new(BinaryTracer::new(out)),
+        });
+        vm.run(Some(64)).unwrap();
+        vm.finish_tracer().unwrap();
+
+        let lines = read_trace(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        lines
+    }
+
+    #[test]
+    fn both_formats_read_back_the_same() {
+        let hasm = "push 2\npush 3\nmuli\nhalt";
+        let lines = trace(hasm, TraceFormat::jsonl);
+        assert_eq!(lines, trace(hasm, TraceFormat::binary));
+        assert_eq!(lines.len(), 4);
+        assert_eq!(
+            lines[2],
+            "{\"step\":2,\"ip\":2,\"inst\":\"muli\",\"depth\":1,\"delta\":-1,\"top\":\"i64(6)\",\"fuel\":61}"
+        );
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let step = Step {
+            ip: 7,
+            inst: Inst::InstPush(Word::f64(-1.5)),
+            depth: 3,
+            delta: -2,
+            top: None,
+            fuel: 99,
+            calls: Vec::new(),
+        };
+        assert_eq!(Step::from_bytes(&step.to_bytes().unwrap()).unwrap(), step);
+        assert!(Step::from_bytes(&[0; RECORD_SIZE - 1]).is_err());
+    }
+
+    #[test]
+    fn diffs_skip_fuel_and_find_the_first_divergence() {
+        let lines = |fuel: u64, last: &str| {
+            vec![
+                format!("{{\"ip\":0,\"fuel\":{}}}", fuel),
+                format!("{{\"ip\":{},\"fuel\":{}}}", last, fuel - 1),
+            ]
+        };
+        assert_eq!(diff_traces(&lines(10, "1"), &lines(500, "1")), None);
+        assert_eq!(diff_traces(&lines(10, "1"), &lines(10, "2")), Some(1));
+        assert_eq!(diff_traces(&lines(10, "1"), &lines(10, "1")[..1]), Some(1));
+    }
+}
@@ -0,0 +1,425 @@
+use thiserror::Error;
+
+use crate::{
+    analysis::{block_exits, call_effects},
+    cfg::Cfg,
+    host::syscall_arity,
+    inst::Inst,
+    program::Program,
+    vm::STACK_SIZE_LIMIT,
+    word::Word,
+};
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum VerifyErrorKind {
+    #[error("jump target {target} out of range")]
+    JumpOutOfRange { target: u64 },
+
+    #[error("execution falls off the end of the program")]
+    FallsOffEnd,
+
+    #[error("no path from here reaches a halt, ret, throw or exit")]
+    NeverHalts,
+
+    #[error("stack underflow, depth {depth} but {required} required")]
+    StackUnderflow { depth: usize, required: usize },
+
+    #[error("stack overflow, depth {depth}")]
+    StackOverflow { depth: usize },
+
+    #[error("inconsistent stack depth at merge point, {expected} vs {found}")]
+    InconsistentDepth { expected: usize, found: usize },
+
+    #[error("unsupported operand {operand}")]
+    UnsupportedOperand { operand: Word },
+
+    #[error("constant {index} out of range")]
+    ConstantOutOfRange { index: u64 },
+
+    #[error("global {index} out of range")]
+    GlobalOutOfRange { index: u64 },
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("ip {ip}: {kind}")]
+pub struct VerifyError {
+    pub ip: usize,
+    pub kind: VerifyErrorKind,
+}
+
+impl VerifyError {
+    fn new(ip: usize, kind: VerifyErrorKind) -> Self {
+        Self { ip, kind }
+    }
+}
+
+pub fn verify(program: &Program) -> Result<(), Vec<VerifyError>> {
+    let insts = &program.insts;
+    let mut errors = Vec::new();
+
+    // Operand checks hold for every instruction, reachable or not
+    insts.iter().enumerate().for_each(|(ip, inst)| match inst {
+        Inst::InstJmp(operand)
+        | Inst::InstCall(operand)
+        | Inst::InstTry(operand)
+        | Inst::InstSpawn(operand) => {
+            let target = u64::from(*operand);
+            if target >= insts.len() as u64 {
+                errors.push(VerifyError::new(
+                    ip,
+                    VerifyErrorKind::JumpOutOfRange { target },
+                ));
+            }
+        }
+        Inst::InstPush(operand @ Word::ptr(_)) => errors.push(VerifyError::new(
+            ip,
+            VerifyErrorKind::UnsupportedOperand { operand: *operand },
+        )),
+        Inst::InstSyscall(operand) if syscall_arity(u64::from(*operand)).is_none() => {
+            errors.push(VerifyError::new(
+                ip,
+                VerifyErrorKind::UnsupportedOperand { operand: *operand },
+            ))
+        }
+        Inst::InstPushk(index) => match program.constants.get(u64::from(*index) as usize) {
+            Some(operand @ Word::ptr(_)) => errors.push(VerifyError::new(
+                ip,
+                VerifyErrorKind::UnsupportedOperand { operand: *operand },
+            )),
+            Some(_) => {}
+            None => errors.push(VerifyError::new(
+                ip,
+                VerifyErrorKind::ConstantOutOfRange {
+                    index: u64::from(*index),
+                },
+            )),
+        },
+        Inst::InstPushs(index) if u64::from(*index) >= program.strings.len() as u64 => {
+            errors.push(VerifyError::new(
+                ip,
+                VerifyErrorKind::ConstantOutOfRange {
+                    index: u64::from(*index),
+                },
+            ))
+        }
+        Inst::InstGload(slot) | Inst::InstGstore(slot)
+            if u64::from(*slot) >= program.globals.len() as u64 =>
+        {
+            errors.push(VerifyError::new(
+                ip,
+                VerifyErrorKind::GlobalOutOfRange {
+                    index: u64::from(*slot),
+                },
+            ))
+        }
+        _ => {}
+    });
+
+    if insts.is_empty() {
+        errors.push(VerifyError::new(0, VerifyErrorKind::FallsOffEnd));
+        return Err(errors);
+    }
+
+    // Walk every path from the entry block, and from each function entry
+    // given the words it takes, recording the stack depth on arrival. Calls
+    // are checked against what their callee takes and stepped over.
+    let cfg = Cfg::build(program);
+    let effects = call_effects(program, &cfg);
+    // A callee taking more than any stack holds is reported where it is called
+    let mut depths: Vec<Option<usize>> = vec![None; cfg.blocks.len()];
+    let mut worklist: Vec<(usize, usize, Option<usize>)> = effects
+        .iter()
+        .filter_map(|(entry, effect)| {
+            Some((
+                cfg.block_at(*entry)?,
+                effect.required.min(STACK_SIZE_LIMIT),
+                Some(*entry),
+            ))
+        })
+        .collect();
+    worklist.push((0, 0, None));
+
+    'blocks: while let Some((id, depth, function)) = worklist.pop() {
+        let block = &cfg.blocks[id];
+        match depths[id] {
+            Some(expected) if expected != depth => {
+                errors.push(VerifyError::new(
+                    block.start,
+                    VerifyErrorKind::InconsistentDepth {
+                        expected,
+                        found: depth,
+                    },
+                ));
+                continue;
+            }
+            Some(_) => continue,
+            None => depths[id] = Some(depth),
+        }
+
+        let mut depth = depth;
+        for ip in block.ips() {
+            let (required, delta) = match &insts[ip] {
+                Inst::InstCall(callee) => effects
+                    .get(&(u64::from(*callee) as usize))
+                    .map_or((0, 0), |effect| (effect.required, 0)),
+                inst => inst.stack_effect(),
+            };
+            if depth < required {
+                errors.push(VerifyError::new(
+                    ip,
+                    VerifyErrorKind::StackUnderflow { depth, required },
+                ));
+                continue 'blocks;
+            }
+
+            depth = (depth as isize + delta) as usize;
+            if depth > STACK_SIZE_LIMIT {
+                errors.push(VerifyError::new(
+                    ip,
+                    VerifyErrorKind::StackOverflow { depth },
+                ));
+                continue 'blocks;
+            }
+        }
+
+        let last = block.end - 1;
+        if block.end == insts.len() && insts[last].successors(last).contains(&insts.len()) {
+            errors.push(VerifyError::new(last, VerifyErrorKind::FallsOffEnd));
+        }
+        // Every ret of a function has to leave what its first one did
+        if let (Inst::InstRet, Some(effect)) =
+            (&insts[last], function.and_then(|f| effects.get(&f)))
+        {
+            let expected = effect
+                .net
+                .map(|net| (effect.required as i64 + net) as usize);
+            if let Some(expected) = expected.filter(|expected| *expected != depth) {
+                errors.push(VerifyError::new(
+                    last,
+                    VerifyErrorKind::InconsistentDepth {
+                        expected,
+                        found: depth,
+                    },
+                ));
+            }
+        }
+        worklist.extend(
+            block_exits(insts, &cfg, &effects, id, depth as i64)
+                .into_iter()
+                .map(|(succ, depth)| {
+                    let spawned = matches!(insts[last], Inst::InstSpawn(_))
+                        && cfg.blocks[succ].start != last + 1;
+                    (succ, depth as usize, function.filter(|_| !spawned))
+                }),
+        );
+    }
+
+    // Every path has to get out somewhere, so mark what can reach a block
+    // without successors and report where the rest is first entered
+    let blocks = &cfg.blocks;
+    let mut preds = vec![Vec::new(); blocks.len()];
+    blocks.iter().enumerate().for_each(|(id, block)| {
+        block.succs.iter().for_each(|succ| preds[*succ].push(id));
+    });
+    let reachable = cfg.reachable();
+    let mut exits = vec![false; blocks.len()];
+    let mut worklist: Vec<usize> = (0..blocks.len())
+        .filter(|id| blocks[*id].succs.is_empty())
+        .collect();
+    while let Some(id) = worklist.pop() {
+        if !exits[id] {
+            exits[id] = true;
+            worklist.extend(&preds[id]);
+        }
+    }
+    (0..blocks.len())
+        .filter(|id| reachable[*id] && !exits[*id])
+        .filter(|id| *id == 0 || preds[*id].iter().any(|pred| exits[*pred]))
+        .for_each(|id| {
+            errors.push(VerifyError::new(
+                blocks[id].start,
+                VerifyErrorKind::NeverHalts,
+            ))
+        });
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        errors.sort_by_key(|err| err.ip);
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(hasm: &str) -> Vec<(usize, VerifyErrorKind)> {
+        let program = Program::from_hasm(hasm).unwrap();
+        match verify(&program) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.into_iter().map(|err| (err.ip, err.kind)).collect(),
+        }
+    }
+
+    #[test]
+    fn accepts_balanced_programs() {
+        let hasm = "push 1
+            try handler
+            push 2
+            divi
+            endtry
+            printi
+            halt
+        handler:
+            printi
+            printi
+            halt 1";
+        assert_eq!(problems(hasm), []);
+    }
+
+    #[test]
+    fn jumps_out_of_range() {
+        let mut program = Program::from_hasm("jmp end\nend:\nhalt").unwrap();
+        program.insts[0] = Inst::InstJmp(Word::u64(7));
+        let errors = verify(&program).unwrap_err();
+        assert_eq!(
+            errors[0],
+            VerifyError::new(0, VerifyErrorKind::JumpOutOfRange { target: 7 })
+        );
+    }
+
+    #[test]
+    fn falls_off_the_end() {
+        assert_eq!(
+            problems("push 1\nprinti"),
+            [(1, VerifyErrorKind::FallsOffEnd)]
+        );
+        assert_eq!(problems(""), [(0, VerifyErrorKind::FallsOffEnd)]);
+    }
+
+    #[test]
+    fn loops_that_never_halt() {
+        assert_eq!(
+            problems("push 1\nloop:\nprinti\npush 2\njmp loop"),
+            [(0, VerifyErrorKind::NeverHalts)]
+        );
+        // Reported where the loop is entered from code that could still halt
+        assert_eq!(
+            problems("try out\nloop:\njmp loop\nout:\nhalt"),
+            [(1, VerifyErrorKind::NeverHalts)]
+        );
+        // Looping until a throw gets out is fine
+        assert_eq!(problems("loop:\npush 1\nthrow\njmp loop"), []);
+    }
+
+    #[test]
+    fn underflows_and_inconsistent_merges() {
+        assert_eq!(
+            problems("push 1\naddi\nhalt"),
+            [(
+                1,
+                VerifyErrorKind::StackUnderflow {
+                    depth: 1,
+                    required: 2
+                }
+            )]
+        );
+        // The handler is entered with the error code, the jmp with two words
+        let found = problems("try handler\npush 1\npush 2\nendtry\njmp handler\nhandler:\nhalt");
+        assert!(
+            matches!(found[..], [(5, VerifyErrorKind::InconsistentDepth { .. })]),
+            "{:?}",
+            found
+        );
+    }
+
+    #[test]
+    fn operands_out_of_range() {
+        let mut program = Program::from_hasm("push 1\npushk 0\nhalt").unwrap();
+        program.insts[0] = Inst::InstPush(Word::ptr(0x1000));
+        program.insts[1] = Inst::InstPushk(Word::u64(3));
+        program.insts.push(Inst::InstGload(Word::u64(0)));
+        program.insts.push(Inst::InstSyscall(Word::u64(99)));
+        let kinds: Vec<VerifyErrorKind> = verify(&program)
+            .unwrap_err()
+            .into_iter()
+            .map(|err| err.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                VerifyErrorKind::UnsupportedOperand {
+                    operand: Word::ptr(0x1000)
+                },
+                VerifyErrorKind::ConstantOutOfRange { index: 3 },
+                VerifyErrorKind::GlobalOutOfRange { index: 0 },
+                VerifyErrorKind::UnsupportedOperand {
+                    operand: Word::u64(99)
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn calls_take_what_their_callee_needs() {
+        let add = "\nhalt\nadd:\naddi\nret";
+        assert_eq!(
+            problems(&format!("push 2\npush 3\ncall add\nprinti{}", add)),
+            []
+        );
+        assert_eq!(
+            problems(&format!("push 2\ncall add{}", add)),
+            [(
+                1,
+                VerifyErrorKind::StackUnderflow {
+                    depth: 1,
+                    required: 2
+                }
+            )]
+        );
+        // Checking goes on past the call with what the callee left
+        assert_eq!(
+            problems(&format!("push 2\npush 3\ncall add\naddi{}", add)),
+            [(
+                3,
+                VerifyErrorKind::StackUnderflow {
+                    depth: 1,
+                    required: 2
+                }
+            )]
+        );
+
+        let square = "push 5
+            call square
+            printi
+            halt
+        square:
+            enter 1
+            lstore 0
+            lload 0
+            lload 0
+            muli
+            leave
+            ret";
+        assert_eq!(problems(square), []);
+    }
+
+    #[test]
+    fn every_ret_leaves_the_same_depth() {
+        let hasm = "call f
+            halt
+        f:
+            try other
+            push 1
+            endtry
+            ret
+        other:
+            printi
+            ret";
+        assert!(matches!(
+            problems(hasm)[..],
+            [(5 | 7, VerifyErrorKind::InconsistentDepth { .. })]
+        ));
+    }
+}
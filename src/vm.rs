@@ -1,31 +1,85 @@
-use std::{fs::File, io::Read};
+use std::{fs::File, io::Read, mem, path::Path, sync::Arc};
 
-use crate::{inst::Inst, program::Program, word::Word, VMError};
+use crate::{
+    analysis::MaxStack,
+    console::{BufferConsole, Console, StdConsole},
+    decode::{decode, Op},
+    fiber::{Fiber, Frame, Handler, Scheduler, CHANNEL_LIMIT, FIBER_LIMIT, MAIN_FIBER},
+    gc::{self, GcStats, ObjectKind},
+    heap::{Heap, HeapFault, HeapMemory, HeapOptions, HeapStats},
+    history::{Change, History, Input},
+    host::{
+        syscall_arity, syscall_name, Capabilities, Host, OpenMode, OsHost, FIRST_FILE_FD,
+        SYS_CLOCK, SYS_CLOSE, SYS_EXIT, SYS_GETENV, SYS_OPEN, SYS_READ, SYS_WRITE,
+    },
+    inst::Inst,
+    program::Program,
+    snapshot::Snapshot,
+    trace::{Step, Tracer},
+    verifier::verify,
+    word::Word,
+    ErrorContext, VMError, ERROR_STACK_DEPTH,
+};
 
-const STACK_SIZE_LIMIT: usize = 1024;
+pub const STACK_SIZE_LIMIT: usize = 1024;
+pub const DEFAULT_FUEL: u64 = 64;
+pub const CALL_DEPTH_LIMIT: usize = 1024;
+pub const LOCALS_LIMIT: usize = 64 * 1024;
 
 #[derive(Debug)]
 pub struct VM {
-    stack: [Word; STACK_SIZE_LIMIT],
-    stack_size: usize,
+    // The running fiber, the others wait in the scheduler
+    fiber: Fiber,
+    scheduler: Scheduler,
 
-    program: Program,
+    // Never changed while running, so VMs running it can share it
+    program: Arc<Program>,
     program_size: usize,
-    ip: usize,
+    // The program decoded for run's fast loop
+    code: Vec<Op>,
+
+    globals: Vec<Word>,
+
+    memory: Vec<u8>,
+    heap: Heap,
+    heap_options: HeapOptions,
+    gc_stats: GcStats,
 
     halt: bool,
+    exit_status: Option<i64>,
+    fuel: u64,
+    tracer: Option<Box<dyn Tracer>>,
+    history: Option<History>,
+    console: Box<dyn Console>,
+    host: Box<dyn Host>,
+    capabilities: Capabilities,
 }
 
 impl VM {
     pub fn new() -> Self {
         Self {
-            stack: [Word::i64(0); 1024],
-            stack_size: 0,
+            fiber: Fiber::new(MAIN_FIBER, 0, STACK_SIZE_LIMIT),
+            scheduler: Scheduler::new(),
 
-            program: Program::default(),
+            program: Arc::default(),
             program_size: 0,
-            ip: 0,
+            code: Vec::new(),
+
+            globals: Vec::new(),
+
+            memory: Vec::new(),
+            heap: Heap::default(),
+            heap_options: HeapOptions::default(),
+            gc_stats: GcStats::default(),
+
             halt: false,
+            exit_status: None,
+            fuel: DEFAULT_FUEL,
+            tracer: None,
+            history: None,
+            console: Box::new(StdConsole),
+            host: Box::new(OsHost::default()),
+            capabilities: Capabilities::default(),
         }
     }
 
@@ -44,20 +98,58 @@ impl VM {
 
         let program = Program::from_hasm(&buffer)?;
 
+        self.load(Arc::new(program))
+    }
+
+    fn load(&mut self, program: Arc<Program>) -> Result<(), VMError> {
+        let stack_size_limit = match program.max_stack {
+            Some(MaxStack::Bounded(max_stack)) if max_stack > STACK_SIZE_LIMIT => {
+                return Err(VMError::StackLimitExceeded { max_stack });
+            }
+            Some(MaxStack::Bounded(max_stack)) => max_stack,
+            _ => STACK_SIZE_LIMIT,
+        };
+
+        self.fiber = Fiber::new(MAIN_FIBER, 0, stack_size_limit);
+        self.scheduler = Scheduler::new();
+        self.globals = program.globals.clone();
+        self.memory = program.data.clone();
+        self.heap = Heap::new(program.data.len(), self.heap_options);
+        self.heap.init(&mut self.memory)?;
         self.program_size = program.insts.len();
+        self.code = decode(&program);
         self.program = program;
 
         Ok(())
     }
 
     pub fn load_ha_from_memory(&mut self, program: Program) -> Result<(), VMError> {
-        self.program_size = program.insts.len();
-        self.program = program;
+        self.load(Arc::new(program))
+    }
 
-        Ok(())
+    // Loads a program other VMs may be running too
+    pub fn load_shared(&mut self, program: Arc<Program>) -> Result<(), VMError> {
+        self.load(program)
+    }
+
+    pub fn load_ha_from_file(&mut self, path: &Path) -> Result<(), VMError> {
+        let mut file = File::open(path).map_err(|err| VMError::IoFail {
+            err: err.to_string(),
+        })?;
+
+        let mut buffer = Vec::new();
+
+        file.read_to_end(&mut buffer)
+            .map_err(|err| VMError::IoFail {
+                err: err.to_string(),
+            })?;
+
+        let program = Program::from_bytes(&buffer)?;
+
+        self.load(Arc::new(program))
     }
 
-    pub fn load_ha_from_file(&mut self, path: &str) -> Result<(), VMError> {
+    pub fn load_verified_ha_from_file(&mut self, path: &Path) -> Result<(), VMError> {
         let mut file = File::open(path).map_err(|err| VMError::IoFail {
             err: err.to_string(),
         })?;
@@ -70,181 +162,1394 @@ impl VM {
             })?;
 
         let program = Program::from_bytes(&buffer)?;
+        verify(&program).map_err(|errors| VMError::VerifyFail { errors })?;
 
+        self.load(Arc::new(program))
+    }
+
+    // Swaps in a new program without touching the stack, memory or ip. The
+    // heap sits right after the data, so new data means starting over with
+    // an empty heap. Globals keep their values, new ones start from their
+    // initial value.
+    pub fn replace_program(&mut self, program: Program) {
+        self.globals.truncate(program.globals.len());
+        self.globals
+            .extend_from_slice(&program.globals[self.globals.len()..]);
+        if program.data.len() != self.program.data.len() || self.memory.is_empty() {
+            self.memory = program.data.clone();
+            self.heap = Heap::new(program.data.len(), self.heap_options);
+            // Growing memory first makes room for the header, this cannot fail
+            let _ = self.heap.init(&mut self.memory);
+        }
         self.program_size = program.insts.len();
-        self.program = program;
+        self.code = decode(&program);
+        self.program = Arc::new(program);
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn stack(&self) -> &[Word] {
+        &self.fiber.stack[..self.fiber.stack_size]
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    // Innermost last
+    pub fn frames(&self) -> &[Frame] {
+        &self.fiber.frames[..self.fiber.frame_count]
+    }
+
+    pub fn locals(&self) -> &[Word] {
+        &self.fiber.locals[..self.fiber.locals_size]
+    }
+
+    pub fn globals(&self) -> &[Word] {
+        &self.globals
+    }
+
+    // Id of the running fiber, the main one is MAIN_FIBER
+    pub fn fiber_id(&self) -> u64 {
+        self.fiber.id
+    }
+
+    // The fibers waiting their turn, next to run first
+    pub fn parked_fibers(&self) -> impl Iterator<Item = &Fiber> {
+        self.scheduler.fibers.iter()
+    }
+
+    pub fn handlers(&self) -> &[Handler] {
+        &self.fiber.handlers[..self.fiber.handler_count]
+    }
+
+    // ip of the call each frame is waiting on, innermost first
+    pub fn call_trace(&self) -> Vec<usize> {
+        self.frames()[1..]
+            .iter()
+            .rev()
+            .map(|frame| frame.return_ip - 1)
+            .collect()
+    }
+
+    // What went wrong where, for an error about to leave step
+    fn error_context(&self, error: VMError, ip: usize, inst: &Inst) -> VMError {
+        let label = |ip: usize| self.program.label_at(ip).map(str::to_string);
+        let from = self.fiber.stack_size.saturating_sub(ERROR_STACK_DEPTH);
+        VMError::Runtime {
+            error: Box::new(error),
+            context: Box::new(ErrorContext {
+                ip,
+                inst: self.program.inst_to_hasm(inst),
+                label: label(ip),
+                stack: self.fiber.stack[from..self.fiber.stack_size].to_vec(),
+                stack_size: self.fiber.stack_size,
+                calls: self
+                    .call_trace()
+                    .into_iter()
+                    .map(|ip| (ip, label(ip)))
+                    .collect(),
+            }),
+        }
+    }
+
+    // Takes effect from the next program loaded
+    pub fn configure_heap(&mut self, options: HeapOptions) {
+        self.heap_options = options;
+    }
+
+    pub fn heap_stats(&self) -> Result<HeapStats, VMError> {
+        self.heap.stats(&self.memory)
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc_stats
+    }
+
+    // Collects garbage now, returning what this collection freed
+    pub fn collect_garbage(&mut self) -> Result<GcStats, VMError> {
+        self.collect(&[])
+    }
+
+    // Everything the program can still reach objects from
+    fn roots(&self) -> Vec<Word> {
+        let mut roots = self.stack().to_vec();
+        roots.extend(self.locals());
+        roots.extend(self.globals());
+        self.scheduler.fibers.iter().for_each(|fiber| {
+            roots.extend(&fiber.stack[..fiber.stack_size]);
+            roots.extend(&fiber.locals[..fiber.locals_size]);
+        });
+        self.scheduler
+            .channels
+            .iter()
+            .for_each(|channel| roots.extend(channel));
+        roots.extend(self.scheduler.results.values());
+        roots
+    }
+
+    fn collect(&mut self, extra_roots: &[Word]) -> Result<GcStats, VMError> {
+        let mut roots = self.roots();
+        roots.extend(extra_roots);
+
+        let heap = self.heap;
+        let stats = gc::collect(&heap, &mut self.memory_view(), &roots)?;
+        self.gc_stats.collections += 1;
+        self.gc_stats.freed_objects += stats.freed_objects;
+        self.gc_stats.freed_bytes += stats.freed_bytes;
+        self.gc_stats.live_objects = stats.live_objects;
+        self.gc_stats.live_bytes = stats.live_bytes;
+        Ok(stats)
+    }
+
+    // Collects when the heap runs out, and each time it grows past another
+    // multiple of the gc threshold
+    fn alloc_object(&mut self, kind: ObjectKind, len: u64) -> Result<Word, VMError> {
+        let heap = self.heap;
+        let before = heap.used(&self.memory)?;
+        let object = match gc::alloc(&heap, &mut self.memory_view(), kind, len) {
+            Err(VMError::OutOfMemory { .. }) => {
+                self.collect(&[])?;
+                gc::alloc(&heap, &mut self.memory_view(), kind, len)?
+            }
+            object => object?,
+        };
+
+        let threshold = self.heap_options.gc_threshold.max(1);
+        if heap.used(&self.memory)? / threshold > before / threshold {
+            self.collect(&[object])?;
+        }
+        Ok(object)
+    }
+
+    fn new_string(&mut self, text: &[u8]) -> Result<Word, VMError> {
+        let string = self.alloc_object(ObjectKind::String, text.len() as u64)?;
+        self.memory_view().write(u64::from(string), text)?;
+        Ok(string)
+    }
+
+    fn string(&self, word: Word) -> Result<Vec<u8>, VMError> {
+        gc::string(&self.heap, &self.memory, word)
+    }
+
+    // Address of a block from alloc, references belong to the collector
+    fn manual_block(&self, word: Word) -> Result<u64, VMError> {
+        match word {
+            Word::ptr(ptr) => Err(VMError::HeapFault {
+                addr: ptr,
+                fault: HeapFault::InvalidFree,
+            }),
+            word => Ok(u64::from(word)),
+        }
+    }
+
+    fn memory_view(&mut self) -> MemoryView<'_> {
+        MemoryView {
+            memory: &mut self.memory,
+            history: self.history.as_mut(),
+        }
+    }
+
+    pub fn ip(&self) -> usize {
+        self.fiber.ip
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halt
+    }
+
+    // Status the program halted with, through halt or the exit syscall
+    pub fn exit_status(&self) -> Option<i64> {
+        self.exit_status
+    }
+
+    pub fn fuel(&self) -> u64 {
+        self.fuel
+    }
+
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = fuel;
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            ip: self.fiber.ip,
+            fuel: self.fuel,
+            halt: self.halt,
+            exit_status: self.exit_status,
+            gc_stats: self.gc_stats,
+            open_files: self.host.open_files(),
+            stack_limit: self.fiber.stack_limit,
+            stack: self.stack().to_vec(),
+            memory: self.memory.clone(),
+            frames: self.frames().to_vec(),
+            locals: self.locals().to_vec(),
+            globals: self.globals.clone(),
+            handlers: self.handlers().to_vec(),
+            fiber_id: self.fiber.id,
+            scheduler: self.scheduler.clone(),
+            program: self.program.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<(), VMError> {
+        if snapshot.stack_limit > STACK_SIZE_LIMIT {
+            return Err(VMError::StackLimitExceeded {
+                max_stack: snapshot.stack_limit,
+            });
+        }
+
+        self.fiber = Fiber::new(snapshot.fiber_id, snapshot.ip, snapshot.stack_limit);
+        self.fiber.stack_size = snapshot.stack.len();
+        self.fiber.stack = snapshot.stack;
+        self.memory = snapshot.memory;
+        self.fuel = snapshot.fuel;
+        self.halt = snapshot.halt;
+        self.exit_status = snapshot.exit_status;
+        self.gc_stats = snapshot.gc_stats;
+        self.fiber.frame_count = snapshot.frames.len();
+        self.fiber.frames = snapshot.frames;
+        self.fiber.locals_size = snapshot.locals.len();
+        self.fiber.locals = snapshot.locals;
+        self.globals = snapshot.globals;
+        self.fiber.handler_count = snapshot.handlers.len();
+        self.fiber.handlers = snapshot.handlers;
+        self.scheduler = snapshot.scheduler;
+
+        // Snapshots of a VM with nothing loaded yet have no heap header
+        self.heap = Heap::new(snapshot.program.data.len(), self.heap_options);
+        if (self.memory.len() as u64) < self.heap.first_block() {
+            self.heap.init(&mut self.memory)?;
+        }
+        self.program_size = snapshot.program.insts.len();
+        self.code = decode(&snapshot.program);
+        self.program = snapshot.program;
 
         Ok(())
     }
 
-    pub fn run(&mut self, limit: Option<u16>) -> Result<(), VMError> {
-        let limit = match limit {
-            Some(l) => l,
-            None => 64,
+    // Starts logging every step so it can be undone with step_back
+    pub fn record_history(&mut self, capacity: usize, interval: u64) {
+        self.history = Some(History::new(capacity, interval));
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // Undoes the last step, false when there is no further history to go back to
+    pub fn step_back(&mut self) -> Result<bool, VMError> {
+        let Some(history) = self.history.as_mut() else {
+            return Ok(false);
         };
 
-        let mut loop_count: u16 = 0;
-        while !self.halt && loop_count < limit {
-            if self.ip >= self.program_size {
-                return Err(VMError::SegmentFault);
+        if let Some(change) = history.pop() {
+            change
+                .globals
+                .iter()
+                .rev()
+                .for_each(|(slot, old)| self.globals[*slot] = *old);
+            change
+                .memory
+                .iter()
+                .rev()
+                .for_each(|(at, old)| self.memory[*at..*at + old.len()].copy_from_slice(old));
+            self.memory.truncate(change.memory_size);
+            self.fuel = change.fuel;
+            self.halt = change.halt;
+            self.exit_status = change.exit_status;
+
+            // A step that may have switched fibers kept all of them whole
+            if let Some(fibers) = change.fibers {
+                (self.fiber, self.scheduler) = *fibers;
+                return Ok(true);
             }
-            let inst = &self.program.insts[self.ip];
-            self.dump();
+            change
+                .stack
+                .iter()
+                .rev()
+                .for_each(|(slot, old)| self.fiber.stack[*slot] = *old);
+            change
+                .frames
+                .iter()
+                .rev()
+                .for_each(|(slot, old)| self.fiber.frames[*slot] = *old);
+            change
+                .locals
+                .iter()
+                .rev()
+                .for_each(|(slot, old)| self.fiber.locals[*slot] = *old);
+            change
+                .handlers
+                .iter()
+                .rev()
+                .for_each(|(slot, old)| self.fiber.handlers[*slot] = *old);
+            self.fiber.ip = change.ip;
+            self.fiber.stack_size = change.stack_size;
+            self.fiber.frame_count = change.frame_count;
+            self.fiber.locals_size = change.locals_size;
+            self.fiber.handler_count = change.handler_count;
+            return Ok(true);
+        }
+
+        let Some(target) = history.steps().checked_sub(1) else {
+            return Ok(false);
+        };
+        let Some((at, snapshot)) = history.rewind_to(target) else {
+            return Ok(false);
+        };
+
+        // The tracer and the console already saw these steps, and reads come
+        // back from the history
+        let tracer = self.tracer.take();
+        let console = std::mem::replace(&mut self.console, Box::new(BufferConsole::default()));
+        self.restore(snapshot)?;
+        let result = (at..target).try_for_each(|_| self.step());
+        self.tracer = tracer;
+        self.console = console;
+        result.map(|_| true)
+    }
+
+    // Hands back the console it replaces
+    pub fn set_console(&mut self, console: Box<dyn Console>) -> Box<dyn Console> {
+        mem::replace(&mut self.console, console)
+    }
+
+    pub fn console(&self) -> &dyn Console {
+        self.console.as_ref()
+    }
+
+    pub fn set_host(&mut self, host: Box<dyn Host>) {
+        self.host = host;
+    }
+
+    pub fn grant(&mut self, capabilities: Capabilities) {
+        self.capabilities = capabilities;
+    }
+
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn finish_tracer(&mut self) -> Result<(), VMError> {
+        match self.tracer.take() {
+            Some(mut tracer) => tracer.finish(),
+            None => Ok(()),
+        }
+    }
 
-            match inst {
-                Inst::InstPush(operand) => {
-                    if self.stack_size >= STACK_SIZE_LIMIT {
-                        return Err(VMError::StackOverflow { inst: inst.clone() });
+    // Without a limit, runs on whatever fuel is left. Gives the exit status
+    // once the program halts, None when the fuel runs out first.
+    pub fn run(&mut self, limit: Option<u64>) -> Result<Option<i64>, VMError> {
+        if let Some(limit) = limit {
+            self.fuel = limit;
+        }
+
+        // Nothing is watching single steps, so most can skip the bookkeeping
+        // and run from the code decoded at load
+        if self.history.is_none() && self.tracer.is_none() {
+            let program = Arc::clone(&self.program);
+            while !self.halt && self.fuel > 0 {
+                let ip = self.fiber.ip;
+                match self.code.get(ip) {
+                    Some(&op) if op != Op::Step => {
+                        let inst = &program.insts[ip];
+                        if let Err(err) = self.exec_op(op, inst) {
+                            self.settle(Err(err), ip, inst)?;
+                        }
+                        self.fuel -= 1;
                     }
+                    _ => self.step()?,
+                }
+            }
+            return Ok(self.exit_status);
+        }
+
+        while !self.halt && self.fuel > 0 {
+            self.step()?;
+        }
+
+        Ok(self.exit_status)
+    }
 
-                    self.stack[self.stack_size] = *operand;
-                    self.stack_size += 1;
-                    self.ip += 1;
+    pub fn step(&mut self) -> Result<(), VMError> {
+        if self.fiber.ip >= self.program_size {
+            return Err(VMError::SegmentFault);
+        }
+        let program = Arc::clone(&self.program);
+        let inst = &program.insts[self.fiber.ip];
+        let (ip, depth) = (self.fiber.ip, self.fiber.stack_size);
+        let calls = match &self.tracer {
+            Some(tracer) if tracer.wants_calls() => self.call_trace(),
+            _ => Vec::new(),
+        };
+
+        if self.history.as_ref().is_some_and(History::wants_checkpoint) {
+            let snapshot = self.snapshot();
+            if let Some(history) = self.history.as_mut() {
+                history.checkpoint(snapshot);
+            }
+        }
+        if let Some(history) = self.history.as_mut() {
+            history.begin(Change {
+                ip: self.fiber.ip,
+                fuel: self.fuel,
+                halt: self.halt,
+                exit_status: self.exit_status,
+                memory_size: self.memory.len(),
+                stack_size: self.fiber.stack_size,
+                frame_count: self.fiber.frame_count,
+                locals_size: self.fiber.locals_size,
+                handler_count: self.fiber.handler_count,
+                fibers: inst
+                    .schedules()
+                    .then(|| Box::new((self.fiber.clone(), self.scheduler.clone()))),
+                ..Default::default()
+            });
+        }
+
+        let result = self.exec(inst);
+        let result = self.settle(result, ip, inst);
+        if let Some(history) = self.history.as_mut() {
+            match result {
+                Ok(()) => history.commit(),
+                Err(_) => history.abort(),
+            }
+        }
+        result?;
+
+        self.fuel = self.fuel.saturating_sub(1);
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.step(&Step {
+                ip,
+                inst: inst.clone(),
+                depth: self.fiber.stack_size,
+                delta: self.fiber.stack_size as isize - depth as isize,
+                top: self
+                    .fiber
+                    .stack_size
+                    .checked_sub(1)
+                    .map(|top| self.fiber.stack[top]),
+                fuel: self.fuel,
+                calls,
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // A fault inside a try goes to its handler, like a throw of its exit code
+    fn settle(
+        &mut self,
+        result: Result<(), VMError>,
+        ip: usize,
+        inst: &Inst,
+    ) -> Result<(), VMError> {
+        match result {
+            Err(err) if self.fiber.handler_count > 0 => self.raise(inst, err.exit_code() as i64),
+            result => result,
+        }
+        .map_err(|err| self.error_context(err, ip, inst))
+    }
+
+    fn set(&mut self, slot: usize, word: Word) {
+        if let Some(history) = self.history.as_mut() {
+            history.record_stack(slot, self.fiber.stack[slot]);
+        }
+        self.fiber.stack[slot] = word;
+    }
+
+    // Slots past the end grow the vec, there is nothing there to log
+    fn set_frame(&mut self, slot: usize, frame: Frame) {
+        match self.fiber.frames.get_mut(slot) {
+            Some(old) => {
+                if let Some(history) = self.history.as_mut() {
+                    history.record_frame(slot, *old);
                 }
-                Inst::InstAddi => {
-                    if self.stack_size < 2 {
-                        return Err(VMError::StackUnderflow { inst: inst.clone() });
-                    }
-                    self.stack[self.stack_size - 2] = Word::i64(
-                        i64::from(self.stack[self.stack_size - 2])
-                            + i64::from(self.stack[self.stack_size - 1]),
-                    );
-                    self.stack_size -= 1;
-                    self.ip += 1;
-                }
-                Inst::InstSubi => {
-                    if self.stack_size < 2 {
-                        return Err(VMError::StackUnderflow { inst: inst.clone() });
-                    }
+                *old = frame;
+            }
+            None => self.fiber.frames.push(frame),
+        }
+    }
 
-                    self.stack[self.stack_size - 2] = Word::i64(
-                        i64::from(self.stack[self.stack_size - 2])
-                            - i64::from(self.stack[self.stack_size - 1]),
-                    );
-                    self.stack_size -= 1;
-                    self.ip += 1;
+    fn set_handler(&mut self, slot: usize, handler: Handler) {
+        match self.fiber.handlers.get_mut(slot) {
+            Some(old) => {
+                if let Some(history) = self.history.as_mut() {
+                    history.record_handler(slot, *old);
                 }
-                Inst::InstMuli => {
-                    if self.stack_size < 2 {
-                        return Err(VMError::StackUnderflow { inst: inst.clone() });
-                    }
+                *old = handler;
+            }
+            None => self.fiber.handlers.push(handler),
+        }
+    }
+
+    // Unwinds to the innermost try and enters its handler with the code pushed
+    fn raise(&mut self, inst: &Inst, code: i64) -> Result<(), VMError> {
+        if self.fiber.handler_count == 0 {
+            return Err(VMError::Uncaught { code });
+        }
+
+        self.fiber.handler_count -= 1;
+        let handler = self.fiber.handlers[self.fiber.handler_count];
+        self.fiber.stack_size = self.fiber.stack_size.min(handler.stack_size);
+        self.fiber.frame_count = handler.frame_count;
+        let frame = self.frame();
+        self.fiber.locals_size = frame.fp + frame.locals;
+        self.push(inst, Word::i64(code))?;
+        self.fiber.ip = handler.ip;
+        Ok(())
+    }
 
-                    self.stack[self.stack_size - 2] = Word::i64(
-                        i64::from(self.stack[self.stack_size - 2])
-                            * i64::from(self.stack[self.stack_size - 1]),
-                    );
-                    self.stack_size -= 1;
-                    self.ip += 1;
+    // Runs the next fiber that is not blocked, parking the running one at
+    // the back unless it finished. With nothing else ready a yield carries on,
+    // anything else is stuck for good.
+    fn switch_fiber(&mut self, park: bool) -> Result<(), VMError> {
+        let Some(next) = self
+            .scheduler
+            .fibers
+            .iter()
+            .position(|fiber| !fiber.blocked)
+        else {
+            if park && !self.fiber.blocked {
+                return Ok(());
+            }
+            self.fiber.blocked = false;
+            return Err(VMError::Deadlock);
+        };
+
+        self.scheduler.fibers.rotate_left(next);
+        let next = self.scheduler.fibers.pop_front().unwrap();
+        let fiber = mem::replace(&mut self.fiber, next);
+        if park {
+            self.scheduler.fibers.push_back(fiber);
+        }
+        Ok(())
+    }
+
+    fn channel(&self, id: Word) -> Result<usize, VMError> {
+        let id = u64::from(id);
+        if id >= self.scheduler.channels.len() as u64 {
+            return Err(VMError::InvalidHandle {
+                kind: "channel",
+                id,
+            });
+        }
+        Ok(id as usize)
+    }
+
+    fn set_local(&mut self, slot: usize, word: Word) {
+        match self.fiber.locals.get_mut(slot) {
+            Some(old) => {
+                if let Some(history) = self.history.as_mut() {
+                    history.record_local(slot, *old);
                 }
-                Inst::InstDivi => {
-                    if self.stack_size < 2 {
-                        return Err(VMError::StackUnderflow { inst: inst.clone() });
-                    }
+                *old = word;
+            }
+            None => self.fiber.locals.push(word),
+        }
+    }
 
-                    // Todo: 0 for all types?
-                    if self.stack[self.stack_size - 2] == Word::u64(0) {
-                        return Err(VMError::DivisionByZero);
-                    }
+    fn global_slot(&self, slot: usize) -> Result<usize, VMError> {
+        if slot >= self.globals.len() {
+            return Err(VMError::GlobalOutOfRange { index: slot as u64 });
+        }
+        Ok(slot)
+    }
+
+    fn frame(&self) -> Frame {
+        self.fiber.frames[self.fiber.frame_count - 1]
+    }
+
+    // Slot of local index in the current frame
+    fn local_slot(&self, index: usize) -> Result<usize, VMError> {
+        let frame = self.frame();
+        if index >= frame.locals {
+            return Err(VMError::LocalOutOfRange {
+                index: index as u64,
+                locals: frame.locals,
+            });
+        }
+        Ok(frame.fp + index)
+    }
+
+    fn load_memory(&self, at: u64, len: usize) -> Result<&[u8], VMError> {
+        let at = usize::try_from(at).map_err(|_| VMError::SegmentFault)?;
+        self.memory
+            .get(at..at.checked_add(len).ok_or(VMError::SegmentFault)?)
+            .ok_or(VMError::SegmentFault)
+    }
 
-                    self.stack[self.stack_size - 2] = Word::i64(
-                        i64::from(self.stack[self.stack_size - 2])
-                            / i64::from(self.stack[self.stack_size - 1]),
-                    );
-                    self.stack_size -= 1;
-                    self.ip += 1;
+    fn store_memory(&mut self, at: u64, bytes: &[u8]) -> Result<(), VMError> {
+        self.load_memory(at, bytes.len())?;
+        let at = at as usize;
+        let old = &mut self.memory[at..at + bytes.len()];
+        if let Some(history) = self.history.as_mut() {
+            history.record_memory(at, old);
+        }
+        old.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn read_line(&mut self) -> Result<Option<String>, VMError> {
+        let Some(history) = self.history.as_mut() else {
+            return self.console.read_line();
+        };
+
+        let line = match history.take_unread() {
+            Some(Input::Line(line)) => line,
+            _ => self.console.read_line()?,
+        };
+        history.record_read(Input::Line(line.clone()));
+        Ok(line)
+    }
+
+    fn load_string(&self, at: Word, len: Word) -> Result<String, VMError> {
+        let bytes = self.load_memory(u64::from(at), u64::from(len) as usize)?;
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    // Result and whatever the call read into its buffer, see host.rs for the ABI
+    fn call_host(&mut self, n: u64, args: &[Word]) -> Result<(Word, Vec<u8>), VMError> {
+        let failed = (Word::i64(-1), Vec::new());
+        let denied = || VMError::PermissionDenied {
+            syscall: syscall_name(n),
+        };
+
+        let called = match n {
+            SYS_OPEN => {
+                let path = self
+                    .capabilities
+                    .resolve(&self.load_string(args[0], args[1])?)?;
+                let Some(mode) = OpenMode::from_word(u64::from(args[2])) else {
+                    return Ok(failed);
+                };
+                self.host
+                    .open(&path, mode)
+                    .map(|fd| (Word::i64(fd as i64), Vec::new()))
+            }
+            SYS_READ => {
+                let (fd, len) = (u64::from(args[0]), u64::from(args[2]) as usize);
+                self.load_memory(u64::from(args[1]), len)?;
+
+                let mut buf = vec![0u8; len];
+                let read = match fd {
+                    0 => self.console.read_line()?.map_or(Ok(0), |line| {
+                        let line = line + "\n";
+                        let n = line.len().min(len);
+                        buf[..n].copy_from_slice(&line.as_bytes()[..n]);
+                        Ok(n)
+                    }),
+                    FIRST_FILE_FD.. if self.capabilities.fs_root.is_none() => return Err(denied()),
+                    FIRST_FILE_FD.. => self.host.read(fd, &mut buf),
+                    _ => return Ok(failed),
+                };
+                read.map(|n| {
+                    buf.truncate(n);
+                    (Word::i64(n as i64), buf)
+                })
+            }
+            SYS_WRITE => {
+                let fd = u64::from(args[0]);
+                let buf = self
+                    .load_memory(u64::from(args[1]), u64::from(args[2]) as usize)?
+                    .to_vec();
+                let written = match fd {
+                    1 | 2 => self.console.write(&buf).map(|_| buf.len())?,
+                    FIRST_FILE_FD.. if self.capabilities.fs_root.is_none() => return Err(denied()),
+                    FIRST_FILE_FD.. => match self.host.write(fd, &buf) {
+                        Ok(n) => n,
+                        Err(_) => return Ok(failed),
+                    },
+                    _ => return Ok(failed),
+                };
+                Ok((Word::i64(written as i64), Vec::new()))
+            }
+            SYS_CLOSE if self.capabilities.fs_root.is_none() => return Err(denied()),
+            SYS_CLOSE => self
+                .host
+                .close(u64::from(args[0]))
+                .map(|_| (Word::i64(0), Vec::new())),
+            SYS_CLOCK if self.capabilities.clock => {
+                let millis = self.host.now().as_millis() as i64;
+                Ok((Word::i64(millis), Vec::new()))
+            }
+            SYS_GETENV if self.capabilities.env => {
+                let name = self.load_string(args[0], args[1])?;
+                let len = u64::from(args[3]) as usize;
+                self.load_memory(u64::from(args[2]), len)?;
+
+                return Ok(match self.host.env(&name) {
+                    Some(value) => (
+                        Word::i64(value.len() as i64),
+                        value.as_bytes()[..value.len().min(len)].to_vec(),
+                    ),
+                    None => failed,
+                });
+            }
+            SYS_CLOCK | SYS_GETENV => return Err(denied()),
+            _ => return Err(VMError::InvalidOperand),
+        };
+
+        Ok(called.unwrap_or(failed))
+    }
+
+    fn pop(&mut self, inst: &Inst) -> Result<Word, VMError> {
+        if self.fiber.stack_size < 1 {
+            return Err(VMError::StackUnderflow { inst: inst.clone() });
+        }
+        self.fiber.stack_size -= 1;
+        Ok(self.fiber.stack[self.fiber.stack_size])
+    }
+
+    fn push(&mut self, inst: &Inst, word: Word) -> Result<(), VMError> {
+        if !self.fiber.reserve() {
+            return Err(VMError::StackOverflow { inst: inst.clone() });
+        }
+        self.set(self.fiber.stack_size, word);
+        self.fiber.stack_size += 1;
+        Ok(())
+    }
+
+    // Pops the top two words and pushes op applied to them
+    fn binary(
+        &mut self,
+        inst: &Inst,
+        op: impl Fn(Word, Word) -> Result<Word, VMError>,
+    ) -> Result<(), VMError> {
+        let size = self.fiber.stack_size;
+        if size < 2 {
+            return Err(VMError::StackUnderflow { inst: inst.clone() });
+        }
+        let word = op(self.fiber.stack[size - 2], self.fiber.stack[size - 1])?;
+        self.set(size - 2, word);
+        self.fiber.stack_size -= 1;
+        Ok(())
+    }
+
+    // The instructions decode turns into an Op, for step and the fast loop
+    // in run alike. Faults leave the VM as it was, so either can hand them
+    // to a try. Left to itself the compiler calls it out of the fast loop,
+    // which halves what `haesuk bench` measures.
+    #[inline(always)]
+    fn exec_op(&mut self, op: Op, inst: &Inst) -> Result<(), VMError> {
+        let size = self.fiber.stack_size;
+        match op {
+            Op::Push(word) => self.push(inst, word)?,
+            Op::Addi => self.binary(inst, |a, b| {
+                Ok(Word::i64(i64::from(a).wrapping_add(i64::from(b))))
+            })?,
+            Op::Subi => self.binary(inst, |a, b| {
+                Ok(Word::i64(i64::from(a).wrapping_sub(i64::from(b))))
+            })?,
+            Op::Muli => self.binary(inst, |a, b| {
+                Ok(Word::i64(i64::from(a).wrapping_mul(i64::from(b))))
+            })?,
+            Op::Divi => self.binary(inst, |a, b| match i64::from(b) {
+                0 => Err(VMError::DivisionByZero),
+                b => Ok(Word::i64(i64::from(a).wrapping_div(b))),
+            })?,
+            Op::Addf => self.binary(inst, |a, b| Ok(Word::f64(f64::from(a) + f64::from(b))))?,
+            Op::Subf => self.binary(inst, |a, b| Ok(Word::f64(f64::from(a) - f64::from(b))))?,
+            Op::Mulf => self.binary(inst, |a, b| Ok(Word::f64(f64::from(a) * f64::from(b))))?,
+            Op::Divf => self.binary(inst, |a, b| match f64::from(b) {
+                0.0 => Err(VMError::DivisionByZero),
+                b => Ok(Word::f64(f64::from(a) / b)),
+            })?,
+            Op::Jmp(target) => {
+                self.fiber.ip = target;
+                return Ok(());
+            }
+            Op::Eq(word) => {
+                if size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let top = self.fiber.stack[size - 1];
+                self.push(inst, Word::u64((top == word) as u64))?;
+            }
+            Op::Dup(n) => {
+                if size <= n {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
                 }
-                Inst::InstAddf => {
-                    if self.stack_size < 2 {
-                        return Err(VMError::StackUnderflow { inst: inst.clone() });
+                self.push(inst, self.fiber.stack[size - 1 - n])?;
+            }
+            Op::Nop => {}
+            Op::Call(target) => {
+                if self.fiber.frame_count >= CALL_DEPTH_LIMIT {
+                    return Err(VMError::CallStackOverflow {
+                        depth: self.fiber.frame_count,
+                    });
+                }
+                self.set_frame(
+                    self.fiber.frame_count,
+                    Frame {
+                        return_ip: self.fiber.ip + 1,
+                        fp: self.fiber.locals_size,
+                        locals: 0,
+                    },
+                );
+                self.fiber.frame_count += 1;
+                self.fiber.ip = target;
+                return Ok(());
+            }
+            Op::Ret => {
+                // Whatever locals the callee still holds go with its frame
+                if self.fiber.frame_count <= 1 {
+                    return Err(VMError::ReturnWithoutCall);
+                }
+                let frame = self.frame();
+                self.fiber.frame_count -= 1;
+                self.fiber.locals_size = frame.fp;
+                self.fiber.ip = frame.return_ip;
+                // So do the tries it never ended
+                while self.fiber.handler_count > 0
+                    && self.fiber.handlers[self.fiber.handler_count - 1].frame_count
+                        > self.fiber.frame_count
+                {
+                    self.fiber.handler_count -= 1;
+                }
+                return Ok(());
+            }
+            Op::Lload(index) => {
+                let slot = self.local_slot(index)?;
+                self.push(inst, self.fiber.locals[slot])?;
+            }
+            Op::Lstore(index) => {
+                let slot = self.local_slot(index)?;
+                let word = self.pop(inst)?;
+                self.set_local(slot, word);
+            }
+            Op::Gload(slot) => {
+                let slot = self.global_slot(slot)?;
+                self.push(inst, self.globals[slot])?;
+            }
+            Op::Gstore(slot) => {
+                let slot = self.global_slot(slot)?;
+                let word = self.pop(inst)?;
+                if let Some(history) = self.history.as_mut() {
+                    history.record_global(slot, self.globals[slot]);
+                }
+                self.globals[slot] = word;
+            }
+            Op::Step => unreachable!("{:?} has no decoded form", inst),
+        }
+        self.fiber.ip += 1;
+        Ok(())
+    }
+
+    fn exec(&mut self, inst: &Inst) -> Result<(), VMError> {
+        match Op::decode(&self.program, inst) {
+            Op::Step => {}
+            op => return self.exec_op(op, inst),
+        }
+
+        match inst {
+            Inst::InstPush(_)
+            | Inst::InstAddi
+            | Inst::InstSubi
+            | Inst::InstMuli
+            | Inst::InstDivi
+            | Inst::InstAddf
+            | Inst::InstSubf
+            | Inst::InstMulf
+            | Inst::InstDivf
+            | Inst::InstJmp(_)
+            | Inst::InstEq(_)
+            | Inst::InstDup(_)
+            | Inst::InstNop
+            | Inst::InstCall(_)
+            | Inst::InstRet
+            | Inst::InstLload(_)
+            | Inst::InstLstore(_)
+            | Inst::InstGload(_)
+            | Inst::InstGstore(_) => unreachable!("{:?} runs as an Op", inst),
+            // Only the main fiber halts the VM, any other finishes with the
+            // top of its stack as its result, or the operand on an empty stack
+            Inst::InstHalt(operand) if self.fiber.id != MAIN_FIBER => {
+                let result = match self.fiber.stack_size {
+                    0 => *operand,
+                    size => self.fiber.stack[size - 1],
+                };
+                self.scheduler.results.insert(self.fiber.id, result);
+                self.scheduler.wake_all();
+                self.switch_fiber(false)?;
+            }
+            Inst::InstHalt(operand) => {
+                self.halt = true;
+                self.exit_status = Some(i64::from(*operand));
+            }
+            Inst::InstPrinti
+            | Inst::InstPrintu
+            | Inst::InstPrintf
+            | Inst::InstPrintc
+            | Inst::InstPrints => {
+                let word = self.pop(inst)?;
+                let text = match inst {
+                    Inst::InstPrinti => i64::from(word).to_string(),
+                    Inst::InstPrintu => u64::from(word).to_string(),
+                    Inst::InstPrintf => f64::from(word).to_string(),
+                    Inst::InstPrintc => u32::try_from(u64::from(word))
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or(VMError::InvalidOperand)?
+                        .to_string(),
+                    _ if matches!(word, Word::ptr(_)) => {
+                        String::from_utf8_lossy(&self.string(word)?).into_owned()
                     }
-                    self.stack[self.stack_size - 2] = Word::f64(
-                        f64::from(self.stack[self.stack_size - 2])
-                            + f64::from(self.stack[self.stack_size - 1]),
-                    );
-                    self.stack_size -= 1;
-                    self.ip += 1;
-                }
-                Inst::InstSubf => {
-                    if self.stack_size < 2 {
-                        return Err(VMError::StackUnderflow { inst: inst.clone() });
+                    _ => {
+                        // [u32 length][bytes], as laid out by .string
+                        let at = u64::from(word);
+                        let len = self.load_memory(at, 4)?.try_into().unwrap();
+                        let len = u32::from_le_bytes(len) as usize;
+                        String::from_utf8_lossy(self.load_memory(at + 4, len)?).into_owned()
                     }
+                };
+                self.console.write(text.as_bytes())?;
+                self.fiber.ip += 1;
+            }
+            Inst::InstReadi => {
+                if !self.fiber.reserve() {
+                    return Err(VMError::StackOverflow { inst: inst.clone() });
+                }
 
-                    self.stack[self.stack_size - 2] = Word::f64(
-                        f64::from(self.stack[self.stack_size - 2])
-                            - f64::from(self.stack[self.stack_size - 1]),
-                    );
-                    self.stack_size -= 1;
-                    self.ip += 1;
+                let line = self.read_line()?.ok_or(VMError::InvalidInput {
+                    input: String::new(),
+                })?;
+                let n = line
+                    .trim()
+                    .parse::<i64>()
+                    .map_err(|_| VMError::InvalidInput { input: line })?;
+                self.push(inst, Word::i64(n))?;
+                self.fiber.ip += 1;
+            }
+            Inst::InstReadln => {
+                // Pops capacity then address, pushes the length stored or -1 at end of input
+                if self.fiber.stack_size < 2 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
                 }
-                Inst::InstMulf => {
-                    if self.stack_size < 2 {
-                        return Err(VMError::StackUnderflow { inst: inst.clone() });
+                let capacity = u64::from(self.fiber.stack[self.fiber.stack_size - 1]) as usize;
+                let at = u64::from(self.fiber.stack[self.fiber.stack_size - 2]);
+                self.load_memory(at, capacity.saturating_add(4))?;
+
+                let stored = match self.read_line()? {
+                    Some(line) => {
+                        let bytes = &line.as_bytes()[..line.len().min(capacity)];
+                        let mut record = (bytes.len() as u32).to_le_bytes().to_vec();
+                        record.extend(bytes);
+                        self.store_memory(at, &record)?;
+                        Word::u64(bytes.len() as u64)
                     }
+                    None => Word::i64(-1),
+                };
+                // Only now that the read went through, so a failed one can be retried
+                self.fiber.stack_size -= 2;
+                self.push(inst, stored)?;
+                self.fiber.ip += 1;
+            }
+            Inst::InstSyscall(operand) => {
+                let n = u64::from(*operand);
+                let (arity, _) = syscall_arity(n).ok_or(VMError::InvalidOperand)?;
+                if self.fiber.stack_size < arity {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let args =
+                    self.fiber.stack[self.fiber.stack_size - arity..self.fiber.stack_size].to_vec();
 
-                    self.stack[self.stack_size - 2] = Word::f64(
-                        f64::from(self.stack[self.stack_size - 2])
-                            * f64::from(self.stack[self.stack_size - 1]),
-                    );
-                    self.stack_size -= 1;
-                    self.ip += 1;
+                if n == SYS_EXIT {
+                    self.fiber.stack_size -= 1;
+                    self.exit_status = Some(i64::from(args[0]));
+                    self.halt = true;
+                    return Ok(());
                 }
-                Inst::InstDivf => {
-                    if self.stack_size < 2 {
-                        return Err(VMError::StackUnderflow { inst: inst.clone() });
-                    }
 
-                    // Todo: 0 for all types?
-                    if self.stack[self.stack_size - 2] == Word::u64(0) {
-                        return Err(VMError::DivisionByZero);
-                    }
+                // A redone step gets the recorded result instead of calling out again
+                let (result, data) = match self.history.as_mut().and_then(History::take_unread) {
+                    Some(Input::Syscall { result, data }) => (result, data),
+                    _ => self.call_host(n, &args)?,
+                };
+                if let Some(history) = self.history.as_mut() {
+                    history.record_read(Input::Syscall {
+                        result,
+                        data: data.clone(),
+                    });
+                }
 
-                    self.stack[self.stack_size - 2] = Word::f64(
-                        f64::from(self.stack[self.stack_size - 2])
-                            / f64::from(self.stack[self.stack_size - 1]),
-                    );
-                    self.stack_size -= 1;
-                    self.ip += 1;
+                let buf = match n {
+                    SYS_READ => Some(args[1]),
+                    SYS_GETENV => Some(args[2]),
+                    _ => None,
+                };
+                if let Some(buf) = buf.filter(|_| !data.is_empty()) {
+                    self.store_memory(u64::from(buf), &data)?;
                 }
-                Inst::InstHalt => {
-                    self.halt = true;
+
+                self.fiber.stack_size -= arity;
+                self.push(inst, result)?;
+                self.fiber.ip += 1;
+            }
+            Inst::InstAlloc => {
+                if self.fiber.stack_size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let size = u64::from(self.fiber.stack[self.fiber.stack_size - 1]);
+                let heap = self.heap;
+                let addr = heap.alloc(&mut self.memory_view(), size)?;
+                self.set(self.fiber.stack_size - 1, Word::u64(addr));
+                self.fiber.ip += 1;
+            }
+            Inst::InstFree => {
+                if self.fiber.stack_size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
                 }
-                Inst::InstJmp(operand) => {
-                    let n: u64 = (*operand).into();
-                    self.ip = n as usize;
+                let addr = self.manual_block(self.fiber.stack[self.fiber.stack_size - 1])?;
+                let heap = self.heap;
+                heap.free(&mut self.memory_view(), addr)?;
+                self.fiber.stack_size -= 1;
+                self.fiber.ip += 1;
+            }
+            Inst::InstRealloc => {
+                // Pops size then address, pushes the address the block ended up at
+                if self.fiber.stack_size < 2 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let size = u64::from(self.fiber.stack[self.fiber.stack_size - 1]);
+                let addr = self.manual_block(self.fiber.stack[self.fiber.stack_size - 2])?;
+                let heap = self.heap;
+                let addr = heap.realloc(&mut self.memory_view(), addr, size)?;
+                self.set(self.fiber.stack_size - 2, Word::u64(addr));
+                self.fiber.stack_size -= 1;
+                self.fiber.ip += 1;
+            }
+            Inst::InstLoad | Inst::InstLoadf | Inst::InstLoadb => {
+                if self.fiber.stack_size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
                 }
-                Inst::InstEq(operand) => {
-                    if self.stack_size >= STACK_SIZE_LIMIT {
-                        return Err(VMError::StackOverflow { inst: inst.clone() });
+                let at = u64::from(self.fiber.stack[self.fiber.stack_size - 1]);
+                self.heap.check_access(&self.memory, at)?;
+
+                let word = match inst {
+                    Inst::InstLoadb => Word::i64(self.load_memory(at, 1)?[0] as i64),
+                    _ => {
+                        let bytes = self.load_memory(at, 8)?.try_into().expect("8 bytes");
+                        match inst {
+                            Inst::InstLoadf => Word::f64(f64::from_le_bytes(bytes)),
+                            _ => Word::i64(i64::from_le_bytes(bytes)),
+                        }
                     }
+                };
+                self.set(self.fiber.stack_size - 1, word);
+                self.fiber.ip += 1;
+            }
+            Inst::InstStore | Inst::InstStoreb => {
+                // Pops the value then the address
+                if self.fiber.stack_size < 2 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let value = self.fiber.stack[self.fiber.stack_size - 1];
+                let at = u64::from(self.fiber.stack[self.fiber.stack_size - 2]);
+                self.heap.check_access(&self.memory, at)?;
+
+                let bytes = value.to_le_bytes();
+                match inst {
+                    Inst::InstStoreb => self.store_memory(at, &bytes[..1])?,
+                    _ => self.store_memory(at, &bytes)?,
+                }
+                self.fiber.stack_size -= 2;
+                self.fiber.ip += 1;
+            }
+            Inst::InstNew | Inst::InstNewbytes => {
+                if self.fiber.stack_size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let len = u64::from(self.fiber.stack[self.fiber.stack_size - 1]);
+                let kind = match inst {
+                    Inst::InstNew => ObjectKind::Record,
+                    _ => ObjectKind::Bytes,
+                };
+                let object = self.alloc_object(kind, len)?;
+                self.set(self.fiber.stack_size - 1, object);
+                self.fiber.ip += 1;
+            }
+            Inst::InstGetfield => {
+                // Pops the index then the object
+                if self.fiber.stack_size < 2 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let index = u64::from(self.fiber.stack[self.fiber.stack_size - 1]);
+                let object = self.fiber.stack[self.fiber.stack_size - 2];
+                let word = gc::get_field(&self.heap, &self.memory, object, index)?;
+                self.set(self.fiber.stack_size - 2, word);
+                self.fiber.stack_size -= 1;
+                self.fiber.ip += 1;
+            }
+            Inst::InstSetfield => {
+                // Pops the value, the index, then the object
+                if self.fiber.stack_size < 3 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let value = self.fiber.stack[self.fiber.stack_size - 1];
+                let index = u64::from(self.fiber.stack[self.fiber.stack_size - 2]);
+                let object = self.fiber.stack[self.fiber.stack_size - 3];
+                let heap = self.heap;
+                gc::set_field(&heap, &mut self.memory_view(), object, index, value)?;
+                self.fiber.stack_size -= 3;
+                self.fiber.ip += 1;
+            }
+            Inst::InstGc => {
+                self.collect(&[])?;
+                self.fiber.ip += 1;
+            }
+            Inst::InstPushs(index) => {
+                if !self.fiber.reserve() {
+                    return Err(VMError::StackOverflow { inst: inst.clone() });
+                }
+                let literal = self
+                    .program
+                    .strings
+                    .get(u64::from(*index) as usize)
+                    .ok_or(VMError::InvalidOperand)?
+                    .clone();
+                let string = self.new_string(&literal)?;
+                self.push(inst, string)?;
+                self.fiber.ip += 1;
+            }
+            // One in range decodes to the push of its constant
+            Inst::InstPushk(_) => return Err(VMError::InvalidOperand),
+            Inst::InstEnter(n) => {
+                let n = u64::from(*n) as usize;
+                let size = self
+                    .fiber
+                    .locals_size
+                    .checked_add(n)
+                    .filter(|size| *size <= LOCALS_LIMIT)
+                    .ok_or_else(|| VMError::StackOverflow { inst: inst.clone() })?;
+                (self.fiber.locals_size..size).for_each(|slot| self.set_local(slot, Word::i64(0)));
+                self.fiber.locals_size = size;
+
+                let mut frame = self.frame();
+                frame.locals += n;
+                self.set_frame(self.fiber.frame_count - 1, frame);
+                self.fiber.ip += 1;
+            }
+            Inst::InstLeave => {
+                let mut frame = self.frame();
+                self.fiber.locals_size = frame.fp;
+                frame.locals = 0;
+                self.set_frame(self.fiber.frame_count - 1, frame);
+                self.fiber.ip += 1;
+            }
+            Inst::InstTry(handler) => {
+                if self.fiber.handler_count >= CALL_DEPTH_LIMIT {
+                    return Err(VMError::StackOverflow { inst: inst.clone() });
+                }
+                self.set_handler(
+                    self.fiber.handler_count,
+                    Handler {
+                        ip: u64::from(*handler) as usize,
+                        stack_size: self.fiber.stack_size,
+                        frame_count: self.fiber.frame_count,
+                    },
+                );
+                self.fiber.handler_count += 1;
+                self.fiber.ip += 1;
+            }
+            Inst::InstEndtry => {
+                // Only a try from the same frame can be ended
+                if self.fiber.handler_count == 0
+                    || self.fiber.handlers[self.fiber.handler_count - 1].frame_count
+                        != self.fiber.frame_count
+                {
+                    return Err(VMError::EndtryWithoutTry);
+                }
+                self.fiber.handler_count -= 1;
+                self.fiber.ip += 1;
+            }
+            Inst::InstThrow => {
+                let code = self.pop(inst)?;
+                self.raise(inst, i64::from(code))?;
+            }
+            Inst::InstSpawn(entry) => {
+                if self.fiber.stack_size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                if self.scheduler.fibers.len() + 1 >= FIBER_LIMIT {
+                    return Err(VMError::HandleLimit {
+                        kind: "fiber",
+                        limit: FIBER_LIMIT,
+                    });
+                }
+
+                let id = self.scheduler.next_id;
+                let mut fiber = Fiber::new(id, u64::from(*entry) as usize, self.fiber.stack_limit);
+                fiber.stack[0] = self.fiber.stack[self.fiber.stack_size - 1];
+                fiber.stack_size = 1;
+                self.scheduler.fibers.push_back(fiber);
+                self.scheduler.next_id += 1;
+
+                self.set(self.fiber.stack_size - 1, Word::u64(id));
+                self.fiber.ip += 1;
+            }
+            Inst::InstYield => {
+                self.fiber.ip += 1;
+                self.switch_fiber(true)?;
+            }
+            Inst::InstJoin => {
+                if self.fiber.stack_size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let id = u64::from(self.fiber.stack[self.fiber.stack_size - 1]);
+                if id >= self.scheduler.next_id {
+                    return Err(VMError::InvalidHandle { kind: "fiber", id });
+                }
 
-                    self.stack[self.stack_size] = if self.stack[self.stack_size - 1] == *operand {
-                        Word::u64(1)
-                    } else {
-                        Word::u64(0)
-                    };
-                    self.stack_size += 1;
-                    self.ip += 1;
-                }
-                Inst::InstDup(operand) => {
-                    let operand_u64 = u64::from(*operand);
-                    if self.stack_size as u64 - operand_u64 <= 0 {
-                        return Err(VMError::StackUnderflow { inst: inst.clone() });
+                match self.scheduler.results.get(&id) {
+                    Some(result) => {
+                        self.set(self.fiber.stack_size - 1, *result);
+                        self.fiber.ip += 1;
                     }
+                    // Tries again once some fiber finishes
+                    None => {
+                        self.fiber.blocked = true;
+                        self.switch_fiber(true)?;
+                    }
+                }
+            }
+            Inst::InstChan => {
+                if self.scheduler.channels.len() >= CHANNEL_LIMIT {
+                    return Err(VMError::HandleLimit {
+                        kind: "channel",
+                        limit: CHANNEL_LIMIT,
+                    });
+                }
+                self.push(inst, Word::u64(self.scheduler.channels.len() as u64))?;
+                self.scheduler.channels.push(Default::default());
+                self.fiber.ip += 1;
+            }
+            Inst::InstSend => {
+                // Pops the word then the channel, channels never fill up
+                if self.fiber.stack_size < 2 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let channel = self.channel(self.fiber.stack[self.fiber.stack_size - 2])?;
+                let word = self.fiber.stack[self.fiber.stack_size - 1];
+                self.scheduler.channels[channel].push_back(word);
+                self.scheduler.wake_all();
+                self.fiber.stack_size -= 2;
+                self.fiber.ip += 1;
+            }
+            Inst::InstRecv => {
+                if self.fiber.stack_size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let channel = self.channel(self.fiber.stack[self.fiber.stack_size - 1])?;
 
-                    if self.stack_size >= STACK_SIZE_LIMIT {
-                        return Err(VMError::StackOverflow { inst: inst.clone() });
+                match self.scheduler.channels[channel].pop_front() {
+                    Some(word) => {
+                        self.set(self.fiber.stack_size - 1, word);
+                        self.fiber.ip += 1;
+                    }
+                    // Tries again once something is sent
+                    None => {
+                        self.fiber.blocked = true;
+                        self.switch_fiber(true)?;
                     }
+                }
+            }
+            Inst::InstConcat => {
+                if self.fiber.stack_size < 2 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let mut text = self.string(self.fiber.stack[self.fiber.stack_size - 2])?;
+                text.extend(self.string(self.fiber.stack[self.fiber.stack_size - 1])?);
+                let string = self.new_string(&text)?;
+                self.set(self.fiber.stack_size - 2, string);
+                self.fiber.stack_size -= 1;
+                self.fiber.ip += 1;
+            }
+            Inst::InstStrlen => {
+                if self.fiber.stack_size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let text = self.string(self.fiber.stack[self.fiber.stack_size - 1])?;
+                self.set(self.fiber.stack_size - 1, Word::i64(text.len() as i64));
+                self.fiber.ip += 1;
+            }
+            Inst::InstSubstr => {
+                // Pops the length, the start, then the string
+                if self.fiber.stack_size < 3 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let len = u64::from(self.fiber.stack[self.fiber.stack_size - 1]);
+                let start = u64::from(self.fiber.stack[self.fiber.stack_size - 2]);
+                let text = self.string(self.fiber.stack[self.fiber.stack_size - 3])?;
+                let end = start
+                    .checked_add(len)
+                    .filter(|end| *end <= text.len() as u64)
+                    .ok_or(VMError::SegmentFault)?;
 
-                    self.stack[self.stack_size] =
-                        self.stack[self.stack_size - 1 - operand_u64 as usize];
-                    self.stack_size += 1;
-                    self.ip += 1;
+                let string = self.new_string(&text[start as usize..end as usize])?;
+                self.set(self.fiber.stack_size - 3, string);
+                self.fiber.stack_size -= 2;
+                self.fiber.ip += 1;
+            }
+            Inst::InstStrcmp => {
+                // Pushes -1, 0 or 1 as the first string sorts before, equal or after the second
+                if self.fiber.stack_size < 2 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let a = self.string(self.fiber.stack[self.fiber.stack_size - 2])?;
+                let b = self.string(self.fiber.stack[self.fiber.stack_size - 1])?;
+                self.set(self.fiber.stack_size - 2, Word::i64(a.cmp(&b) as i64));
+                self.fiber.stack_size -= 1;
+                self.fiber.ip += 1;
+            }
+            Inst::InstCharat => {
+                // Pops the index then the string, pushes the byte there
+                if self.fiber.stack_size < 2 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
+                }
+                let index = u64::from(self.fiber.stack[self.fiber.stack_size - 1]);
+                let text = self.string(self.fiber.stack[self.fiber.stack_size - 2])?;
+                let byte = usize::try_from(index)
+                    .ok()
+                    .and_then(|index| text.get(index))
+                    .ok_or(VMError::SegmentFault)?;
+                self.set(self.fiber.stack_size - 2, Word::i64(*byte as i64));
+                self.fiber.stack_size -= 1;
+                self.fiber.ip += 1;
+            }
+            Inst::InstItos | Inst::InstFtos => {
+                if self.fiber.stack_size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
                 }
-                Inst::InstNop => {
-                    self.ip += 1;
+                let word = self.fiber.stack[self.fiber.stack_size - 1];
+                let text = match inst {
+                    Inst::InstItos => i64::from(word).to_string(),
+                    _ => f64::from(word).to_string(),
+                };
+                let string = self.new_string(text.as_bytes())?;
+                self.set(self.fiber.stack_size - 1, string);
+                self.fiber.ip += 1;
+            }
+            Inst::InstStoi | Inst::InstStof => {
+                if self.fiber.stack_size < 1 {
+                    return Err(VMError::StackUnderflow { inst: inst.clone() });
                 }
+                let text = self.string(self.fiber.stack[self.fiber.stack_size - 1])?;
+                let text = String::from_utf8_lossy(&text);
+                let invalid = || VMError::InvalidInput {
+                    input: text.to_string(),
+                };
+                let word = match inst {
+                    Inst::InstStoi => Word::i64(text.trim().parse().map_err(|_| invalid())?),
+                    _ => Word::f64(text.trim().parse().map_err(|_| invalid())?),
+                };
+                self.set(self.fiber.stack_size - 1, word);
+                self.fiber.ip += 1;
             }
-            loop_count += 1
         }
 
         Ok(())
@@ -252,16 +1557,390 @@ impl VM {
 
     pub fn dump(&self) {
         println!("Stack: ");
-        (0..self.stack_size).for_each(|n| {
-            let n = &self.stack[n];
+        (0..self.fiber.stack_size).for_each(|n| {
+            let n = &self.fiber.stack[n];
             let n_u64: u64 = (*n).into();
             let n_i64: i64 = (*n).into();
             let n_f64: f64 = (*n).into();
-            let n_ptr: *mut Word = (*n).into();
             println!(
-                "\tu64: {}, i64: {}, f64: {}, ptr: {:?}",
-                n_u64, n_i64, n_f64, n_ptr
+                "\tu64: {}, i64: {}, f64: {}, ptr: {:#x}",
+                n_u64, n_i64, n_f64, n_u64
             );
-        })
+        });
+
+        if self.fiber.frame_count > 1 || self.fiber.locals_size > 0 {
+            println!("Frames: ");
+            self.frames().iter().for_each(|frame| {
+                let locals: Vec<String> = self.fiber.locals[frame.fp..frame.fp + frame.locals]
+                    .iter()
+                    .map(|word| i64::from(*word).to_string())
+                    .collect();
+                println!(
+                    "\treturn ip: {}, locals: [{}]",
+                    frame.return_ip,
+                    locals.join(", ")
+                );
+            });
+        }
+
+        if !self.scheduler.fibers.is_empty() {
+            println!("Fibers: ");
+            println!("\tid: {}, ip: {} (running)", self.fiber.id, self.fiber.ip);
+            self.scheduler.fibers.iter().for_each(|fiber| {
+                println!(
+                    "\tid: {}, ip: {}, stack size: {}{}",
+                    fiber.id,
+                    fiber.ip,
+                    fiber.stack_size,
+                    if fiber.blocked { " (blocked)" } else { "" }
+                );
+            });
+        }
+
+        if !self.globals.is_empty() {
+            println!("Globals: ");
+            self.globals.iter().enumerate().for_each(|(slot, word)| {
+                match self.program.global_name(slot) {
+                    Some(name) => println!("\t{} = {}", name, word),
+                    None => println!("\t{} = {}", slot, word),
+                }
+            });
+        }
+
+        match self.heap_stats() {
+            Ok(stats) if stats.used > 0 => println!(
+                "Heap: {} of {} bytes used, {} allocated in {} block(s), {} free in {} block(s)",
+                stats.used,
+                stats.limit,
+                stats.allocated,
+                stats.blocks - stats.free_blocks,
+                stats.free,
+                stats.free_blocks
+            ),
+            Ok(_) => {}
+            Err(err) => println!("Heap: {}", err),
+        }
+        if self.gc_stats.collections > 0 {
+            println!(
+                "GC: {} collection(s), {} object(s) freed, {} live in {} bytes",
+                self.gc_stats.collections,
+                self.gc_stats.freed_objects,
+                self.gc_stats.live_objects,
+                self.gc_stats.live_bytes
+            );
+        }
+    }
+}
+
+// VM memory for the heap, logging what it overwrites so steps can be undone
+struct MemoryView<'a> {
+    memory: &'a mut Vec<u8>,
+    history: Option<&'a mut History>,
+}
+
+impl MemoryView<'_> {
+    // Out of range writes fail afterwards, so there is nothing to log for them
+    fn record(&mut self, at: u64, len: u64) {
+        let Some(history) = self.history.as_mut() else {
+            return;
+        };
+        let end = at.saturating_add(len) as usize;
+        if let Some(old) = self.memory.get(at as usize..end) {
+            history.record_memory(at as usize, old);
+        }
+    }
+}
+
+impl HeapMemory for MemoryView<'_> {
+    fn read_u64(&self, at: u64) -> Result<u64, VMError> {
+        self.memory.read_u64(at)
+    }
+
+    fn write_u64(&mut self, at: u64, value: u64) -> Result<(), VMError> {
+        self.record(at, 8);
+        self.memory.write_u64(at, value)
+    }
+
+    fn read(&self, at: u64, len: u64) -> Result<Vec<u8>, VMError> {
+        self.memory.read(at, len)
+    }
+
+    fn write(&mut self, at: u64, bytes: &[u8]) -> Result<(), VMError> {
+        self.record(at, bytes.len() as u64);
+        self.memory.write(at, bytes)
+    }
+
+    fn fill(&mut self, at: u64, len: u64, byte: u8) -> Result<(), VMError> {
+        self.record(at, len);
+        self.memory.fill(at, len, byte)
+    }
+
+    fn copy(&mut self, from: u64, to: u64, len: u64) -> Result<(), VMError> {
+        self.record(to, len);
+        self.memory.copy(from, to, len)
+    }
+
+    fn grow(&mut self, len: u64) {
+        self.memory.grow(len)
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{finish, inner, run_with, vm, FUEL};
+
+    #[test]
+    fn eq_on_an_empty_stack_underflows() {
+        // run takes the decoded loop, step the full one
+        let err = vm("eq 0\nhalt").run(Some(8)).unwrap_err();
+        assert!(matches!(inner(err), VMError::StackUnderflow { .. }));
+
+        let err = vm("eq 0\nhalt").step().unwrap_err();
+        assert!(matches!(inner(err), VMError::StackUnderflow { .. }));
+    }
+
+    #[test]
+    fn stepping_back_over_alloc_shrinks_memory_again() {
+        let mut vm = vm("push 4096\nalloc\nhalt");
+        vm.record_history(16, 1_000);
+        vm.step().unwrap();
+        let before = vm.memory().to_vec();
+
+        vm.step().unwrap();
+        assert!(vm.memory().len() > before.len());
+        assert!(vm.step_back().unwrap());
+        assert_eq!(vm.memory(), &before[..]);
+    }
+
+    #[test]
+    fn storing_over_the_heap_header_faults() {
+        let err = vm("push 8\npush 0\nstore\npush 2\nnew\nhalt 0")
+            .run(Some(8))
+            .unwrap_err();
+        assert!(matches!(
+            inner(err),
+            VMError::HeapFault {
+                fault: HeapFault::Corrupted,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn spawned_fibers_grow_their_stack_as_they_push() {
+        let pushes = "push 1\n".repeat(40);
+        let hasm = format!("push 0\nspawn f\njoin\nhalt\nf:\n{}halt", pushes);
+        let mut fibers = vm(&hasm);
+        fibers.step().unwrap();
+        fibers.step().unwrap();
+        let parked = fibers.parked_fibers().next().unwrap();
+        assert!(parked.stack.len() < parked.stack_limit);
+
+        fibers.run(Some(FUEL)).unwrap();
+        assert_eq!(fibers.stack(), [Word::i64(1)]);
+
+        // Unbounded pushes still stop at the limit
+        let err = vm("push 0\nspawn f\njoin\nhalt\nf:\npush 1\njmp f")
+            .run(Some(FUEL))
+            .unwrap_err();
+        assert!(matches!(inner(err), VMError::StackOverflow { .. }));
+    }
+
+    #[test]
+    fn string_instructions_build_and_take_apart_text() {
+        let run = run_with(
+            r#"push "ab"
+            push "c# d"
+            concat
+            dup 0
+            strlen
+            printi
+            dup 0
+            push 1
+            push 2
+            substr
+            prints
+            dup 0
+            push 3
+            charat
+            printc
+            push "ab"
+            strcmp
+            printi
+            push -42
+            itos
+            push "\"!"
+            concat
+            prints
+            push " 7 "
+            stoi
+            push 2.5
+            ftos
+            dup 0
+            prints
+            stof
+            halt"#,
+            "",
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(run.output).unwrap(), "6bc#1-42\"!2.5");
+        assert_eq!(run.stack, [Word::i64(7), Word::f64(2.5)]);
+    }
+
+    #[test]
+    fn bad_string_operands_fault() {
+        let cases = [
+            ("push \"ab\"\npush 1\npush 2\nsubstr", "SegmentFault"),
+            ("push \"ab\"\npush 2\ncharat", "SegmentFault"),
+            ("push \"x1\"\nstoi", "InvalidInput"),
+            ("push 8\nstrlen", "HeapFault"),
+            ("push \"ab\"\npush 8\nconcat", "HeapFault"),
+        ];
+        for (hasm, fault) in cases {
+            let err = run_with(&format!("{}\nhalt", hasm), "").unwrap_err();
+            assert!(
+                format!("{:?}", err).starts_with(fault),
+                "{}: {:?}",
+                hasm,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn throws_unwind_to_the_innermost_try() {
+        let run = run_with(
+            "push 100
+            try outer
+            push 2
+            call thrower
+            halt 1
+        thrower:
+            enter 1
+            push 9
+            lstore 0
+            try inner
+            push 3
+            push 4
+            throw
+        inner:
+            printi
+            lload 0
+            throw
+        outer:
+            printi
+            halt 3",
+            "",
+        )
+        .unwrap();
+        // The inner handler sees the stack as it was at its try, the outer
+        // one is back in the caller's frame
+        assert_eq!(run.output, b"49");
+        assert_eq!(run.stack, [Word::i64(100)]);
+        assert_eq!(run.exit_status, Some(3));
+    }
+
+    #[test]
+    fn faults_are_caught_with_their_exit_code() {
+        let run = run_with("try caught\npush 1\npush 0\ndivi\ncaught:\nhalt", "").unwrap();
+        assert_eq!(
+            run.stack,
+            [Word::i64(VMError::DivisionByZero.exit_code() as i64)]
+        );
+        // A callee cannot end its caller's try, and faults into it for trying
+        let run = run_with("try h\ncall f\nh:\nhalt\nf:\nendtry\nret", "").unwrap();
+        assert_eq!(
+            run.stack,
+            [Word::i64(VMError::EndtryWithoutTry.exit_code() as i64)]
+        );
+
+        let uncaught = [
+            ("push 5\nthrow", VMError::Uncaught { code: 5 }),
+            (
+                "try h\nendtry\npush 6\nthrow\nh:",
+                VMError::Uncaught { code: 6 },
+            ),
+            ("endtry", VMError::EndtryWithoutTry),
+        ];
+        for (hasm, expected) in uncaught {
+            let err = run_with(&format!("{}\nhalt", hasm), "").unwrap_err();
+            assert_eq!(format!("{:?}", err), format!("{:?}", expected), "{}", hasm);
+        }
+    }
+
+    // run takes the fast loop unless history or a tracer watches each step
+    fn both_ways(hasm: &str) -> (String, String) {
+        let stepped = {
+            let mut vm = vm(hasm);
+            vm.record_history(16, 1_000);
+            finish(vm)
+        };
+        (format!("{:?}", finish(vm(hasm))), format!("{:?}", stepped))
+    }
+
+    #[test]
+    fn the_fast_loop_runs_like_step() {
+        let programs = [
+            ".global total = 10
+            push 6
+            push 7
+            call mul
+            dup 0
+            eq 42
+            gload total
+            subi
+            gstore total
+            nop
+            halt 3
+        mul:
+            enter 1
+            muli
+            lstore 0
+            lload 0
+            leave
+            ret",
+            // Faults inside a try land in its handler either way
+            "try caught
+            push 1
+            push 0
+            divi
+            halt
+        caught:
+            printi
+            halt 1",
+            "push 1.5\npush 0.0\ndivf\nhalt",
+            // ret drops the tries its callee left open
+            "try outer
+            call leaky
+            push 1
+            push 0
+            divi
+            halt
+        leaky:
+            try inner
+            ret
+        inner:
+            halt 5
+        outer:
+            halt 6",
+            "lload 0\nhalt",
+            "gload 3\nhalt",
+            "push 1\ndup 1\nhalt",
+            "ret",
+        ];
+        for hasm in programs {
+            let (fast, stepped) = both_ways(hasm);
+            assert_eq!(fast, stepped, "{}", hasm);
+        }
+        assert_eq!(finish(vm(programs[0])).unwrap().exit_status, Some(3));
+        assert_eq!(finish(vm(programs[1])).unwrap().output, b"73");
+        assert_eq!(finish(vm(programs[3])).unwrap().exit_status, Some(6));
     }
 }
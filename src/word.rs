@@ -6,22 +6,90 @@ pub enum Word {
     i64(i64),
     u64(u64),
     f64(f64),
-    ptr(*mut Word),
+    // Address of a collected object in VM memory
+    ptr(u64),
 }
 
 impl Word {
-    pub fn to_le_bytes(&self) -> [u8; 8] {
+    pub fn to_le_bytes(self) -> [u8; 8] {
         match self {
             Self::i64(n) => n.to_le_bytes(),
             Self::u64(n) => n.to_le_bytes(),
             Self::f64(n) => n.to_le_bytes(),
-            Self::ptr(n) => (*n as u64).to_le_bytes(),
+            Self::ptr(n) => n.to_le_bytes(),
         }
     }
 
     pub fn from_le_bytes<T: FromLeBytes + Into<Word>>(bytes: [u8; 8]) -> Word {
         (T::from_le_bytes(bytes)).into()
     }
+
+    // Type tag for formats that store words outside an instruction
+    pub fn tag(&self) -> u8 {
+        match self {
+            Self::i64(_) => 1,
+            Self::u64(_) => 2,
+            Self::f64(_) => 3,
+            Self::ptr(_) => 4,
+        }
+    }
+
+    // hasm spelling that parses back to the same type and bits: plain
+    // integers are i64, u64:N marks small u64s, floats always have a . or an
+    // exponent, nan and inf are spelled out, and NaNs with a payload are
+    // written as f64:0x followed by their bits
+    pub fn to_literal(self) -> String {
+        match self {
+            Word::i64(n) => n.to_string(),
+            Word::u64(n) if n > i64::MAX as u64 => n.to_string(),
+            Word::u64(n) => format!("u64:{}", n),
+            Word::f64(n) if n.to_bits() == f64::NAN.to_bits() => "nan".to_string(),
+            Word::f64(n) if n.is_nan() => format!("f64:0x{:016x}", n.to_bits()),
+            Word::f64(n) if n.is_infinite() && n > 0.0 => "inf".to_string(),
+            Word::f64(n) if n.is_infinite() => "-inf".to_string(),
+            Word::f64(n) => format!("{:?}", n),
+            Word::ptr(p) => format!("{:#x}", p),
+        }
+    }
+
+    // Reads what to_literal writes, plus i64:N and f64:N for any number
+    pub fn parse_literal(s: &str) -> Option<Word> {
+        if let Some((kind, value)) = s.split_once(':') {
+            return match kind {
+                "i64" => value.parse().ok().map(Word::i64),
+                "u64" => value.parse().ok().map(Word::u64),
+                "f64" => match value.strip_prefix("0x") {
+                    Some(bits) => u64::from_str_radix(bits, 16)
+                        .ok()
+                        .map(|bits| Word::f64(f64::from_bits(bits))),
+                    None => Word::parse_literal(value).map(|word| Word::f64(f64::from(word))),
+                },
+                _ => None,
+            };
+        }
+
+        match s {
+            "nan" => Some(Word::f64(f64::NAN)),
+            "inf" => Some(Word::f64(f64::INFINITY)),
+            "-inf" => Some(Word::f64(f64::NEG_INFINITY)),
+            _ if s.contains(['.', 'e', 'E']) => s.parse().ok().map(Word::f64),
+            _ => s
+                .parse()
+                .map(Word::i64)
+                .or_else(|_| s.parse().map(Word::u64))
+                .ok(),
+        }
+    }
+
+    pub fn from_tagged(tag: u8, bytes: [u8; 8]) -> Option<Word> {
+        match tag {
+            1 => Some(Word::from_le_bytes::<i64>(bytes)),
+            2 => Some(Word::from_le_bytes::<u64>(bytes)),
+            3 => Some(Word::from_le_bytes::<f64>(bytes)),
+            4 => Some(Word::ptr(u64::from_le_bytes(bytes))),
+            _ => None,
+        }
+    }
 }
 
 impl Display for Word {
@@ -30,7 +98,7 @@ impl Display for Word {
             Word::i64(n) => write!(f, "{}", n),
             Word::u64(n) => write!(f, "{}", n),
             Word::f64(n) => write!(f, "{}", n),
-            Word::ptr(p) => write!(f, "{:?}", *p),
+            Word::ptr(p) => write!(f, "{:#x}", p),
         }
     }
 }
@@ -63,13 +131,6 @@ impl FromLeBytes for f64 {
     }
 }
 
-impl FromLeBytes for *mut Word {
-    fn from_le_bytes(bytes: [u8; 8]) -> Self {
-        let ptr = usize::from_le_bytes(bytes);
-        ptr as *mut Word
-    }
-}
-
 impl From<i64> for Word {
     fn from(n: i64) -> Self {
         Self::i64(n)
@@ -82,7 +143,7 @@ impl From<Word> for i64 {
             Word::i64(n) => n,
             Word::u64(n) => n as Self,
             Word::f64(n) => n as Self,
-            Word::ptr(n) => n as *const () as Self,
+            Word::ptr(n) => n as Self,
         }
     }
 }
@@ -99,7 +160,7 @@ impl From<Word> for u64 {
             Word::i64(n) => n as Self,
             Word::u64(n) => n,
             Word::f64(n) => n as Self,
-            Word::ptr(n) => n as *const () as Self,
+            Word::ptr(n) => n,
         }
     }
 }
@@ -116,24 +177,61 @@ impl From<Word> for f64 {
             Word::i64(n) => n as Self,
             Word::u64(n) => n as Self,
             Word::f64(n) => n,
-            Word::ptr(n) => n as *const () as u64 as Self,
+            Word::ptr(n) => n as Self,
         }
     }
 }
 
-impl From<*mut Word> for Word {
-    fn from(ptr: *mut Word) -> Self {
-        Self::ptr(ptr)
-    }
-}
-
-impl From<Word> for *mut Word {
-    fn from(word: Word) -> Self {
-        match word {
-            Word::i64(n) => n as Self,
-            Word::u64(n) => n as Self,
-            Word::f64(n) => n as u64 as Self,
-            Word::ptr(n) => n,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_round_trips(word: Word) {
+        let literal = word.to_literal();
+        let parsed = Word::parse_literal(&literal)
+            .unwrap_or_else(|| panic!("{} does not parse back", literal));
+        assert_eq!(parsed.tag(), word.tag(), "tag of {}", literal);
+        assert_eq!(
+            parsed.to_le_bytes(),
+            word.to_le_bytes(),
+            "bits of {}",
+            literal
+        );
+    }
+
+    #[test]
+    fn floats_round_trip_bit_for_bit() {
+        [
+            0.0,
+            -0.0,
+            3.0,
+            1e300,
+            -1e-300,
+            f64::MIN_POSITIVE / 2.0,
+            f64::from_bits(1),
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::NAN,
+            f64::from_bits(0x7FF8_0000_0000_0001),
+            f64::from_bits(0xFFF0_0000_0000_0002),
+        ]
+        .into_iter()
+        .for_each(|n| assert_round_trips(Word::f64(n)));
+    }
+
+    #[test]
+    fn integers_keep_their_type() {
+        [
+            Word::u64(u64::MAX),
+            Word::u64(i64::MAX as u64 + 1),
+            Word::u64(0),
+            Word::u64(7),
+            Word::i64(i64::MIN),
+            Word::i64(i64::MAX),
+            Word::i64(-1),
+            Word::i64(0),
+        ]
+        .into_iter()
+        .for_each(assert_round_trips);
     }
 }